@@ -0,0 +1,115 @@
+//! Cooperative cancellation of running programs.
+//!
+//! A `CancelToken` is shared with whoever may want to stop a program, usually
+//! another thread. Running code polls it at loop back-edges, but only every
+//! `POLL_INTERVAL`th time, so the check costs a counter decrement on the hot
+//! path. Once it sees the token set it stops with a `Cancelled` error.
+
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Number of taken loop back-edges between two looks at the token.
+pub const POLL_INTERVAL: u32 = 4096;
+
+/// A flag asking running code to stop. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask code polling this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// The flag itself, for generated code to read.
+    pub(crate) fn as_ptr(&self) -> *const AtomicBool {
+        Arc::as_ptr(&self.0)
+    }
+}
+
+/// A program was stopped through its `CancelToken`.
+///
+/// `pointer` is the cell index the tape pointer was at. Output the program
+/// produced before it was stopped has been flushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled {
+    pub pointer: usize,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled at cell {}", self.pointer)
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Back-edge counter of an interpreter, checking `token` every
+/// `POLL_INTERVAL` ticks.
+pub struct Poller<'a> {
+    token: &'a CancelToken,
+    countdown: u32,
+}
+
+impl<'a> Poller<'a> {
+    pub fn new(token: &'a CancelToken) -> Poller<'a> {
+        Poller {
+            token,
+            countdown: POLL_INTERVAL,
+        }
+    }
+
+    /// Count a taken back-edge with the pointer at cell `pointer`.
+    ///
+    /// Fails once the token is cancelled, after flushing stdout, which is
+    /// where the interpreters write their output.
+    #[inline]
+    pub fn tick(&mut self, pointer: usize) -> Result<(), Cancelled> {
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return Ok(());
+        }
+        self.countdown = POLL_INTERVAL;
+        if self.token.is_cancelled() {
+            let _ = io::stdout().flush();
+            return Err(Cancelled { pointer });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_the_flag() {
+        let token = CancelToken::new();
+        let other = token.clone();
+        assert!(!token.is_cancelled());
+        other.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn poller_only_looks_every_interval() {
+        let token = CancelToken::new();
+        token.cancel();
+        let mut poller = Poller::new(&token);
+        for _ in 1..POLL_INTERVAL {
+            assert!(poller.tick(7).is_ok());
+        }
+        assert_eq!(poller.tick(7), Err(Cancelled { pointer: 7 }));
+    }
+}
@@ -1,5 +1,6 @@
 use std::error;
 
+use crate::cancel::CancelToken;
 use crate::interpreter::*;
 
 pub enum OptimizationLevel {
@@ -24,3 +25,55 @@ pub fn run(
         OptimizationLevel::High => interp_5::run(prog),
     }
 }
+
+/// Like `run`, but stops with a `Cancelled` error once `cancel` is set.
+pub fn run_cancellable(
+    prog: &[u8],
+    optimization_level: Option<OptimizationLevel>,
+    cancel: &CancelToken,
+) -> Result<(), Box<dyn error::Error>> {
+    let ol = optimization_level.unwrap_or(OptimizationLevel::High);
+
+    match ol {
+        OptimizationLevel::Raw => interp_1::run_cancellable(prog, cancel),
+        OptimizationLevel::None => interp_2::run_cancellable(prog, cancel),
+        OptimizationLevel::Low => interp_3::run_cancellable(prog, cancel),
+        OptimizationLevel::Medium => interp_4::run_cancellable(prog, cancel),
+        OptimizationLevel::High => interp_5::run_cancellable(prog, cancel),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::cancel::Cancelled;
+
+    use super::*;
+
+    #[test]
+    fn every_level_can_be_cancelled() {
+        let levels = [
+            OptimizationLevel::Raw,
+            OptimizationLevel::None,
+            OptimizationLevel::Low,
+            OptimizationLevel::Medium,
+            OptimizationLevel::High,
+        ];
+        for level in levels {
+            let token = CancelToken::new();
+            let canceller = token.clone();
+            let thread = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                canceller.cancel();
+            });
+            let err = run_cancellable(b">+[]", Some(level), &token).unwrap_err();
+            thread.join().unwrap();
+            assert_eq!(
+                err.downcast_ref::<Cancelled>(),
+                Some(&Cancelled { pointer: 1 })
+            );
+        }
+    }
+}
@@ -5,6 +5,7 @@ use std::{
 };
 
 use crate::bf_types;
+use crate::cancel::{CancelToken, Poller};
 
 /// Run a bf program
 ///
@@ -17,6 +18,12 @@ use crate::bf_types;
 ///
 /// This is a naive implementation, which we will optimize further in other implementations.
 pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_cancellable(prog, &CancelToken::new())
+}
+
+/// Run a bf program until it finishes or `cancel` is set.
+pub fn run_cancellable(prog: &[u8], cancel: &CancelToken) -> Result<(), Box<dyn error::Error>> {
+    let mut poller = Poller::new(cancel);
     let mut pc = 0; /* Program counter tracks location in the code */
     let mut cells = vec![0u8; bf_types::BF_MEMORY_SIZE]; /* memory */
     let mut cc = 0; /* Cell counter (data pointer) points to active location in memory*/
@@ -63,6 +70,7 @@ pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
                         _ => (),
                     }
                 }
+                poller.tick(cc)?;
             }
             '.' => io::stdout().write_all(&cells[cc..cc + 1])?,
             ',' => io::stdin().read_exact(&mut cells[cc..cc + 1])?,
@@ -4,6 +4,7 @@ use std::{
 };
 
 use crate::bf_types;
+use crate::cancel::{CancelToken, Poller};
 
 /// Operations for BF
 #[derive(Debug)]
@@ -40,6 +41,12 @@ fn parse(prog: &[u8]) -> Result<OpSequence, &'static str> {
 }
 
 pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_cancellable(prog, &CancelToken::new())
+}
+
+/// Run a bf program until it finishes or `cancel` is set.
+pub fn run_cancellable(prog: &[u8], cancel: &CancelToken) -> Result<(), Box<dyn error::Error>> {
+    let mut poller = Poller::new(cancel);
     /* Notice: prog is now a vec of OpCodes, not a string */
     let prog_ops = parse(prog)?;
 
@@ -90,6 +97,7 @@ pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
                         _ => (),
                     }
                 }
+                poller.tick(cc)?;
             }
             Ops::Output => io::stdout().write_all(&cells[cc..cc + 1])?,
             Ops::Input => io::stdin().read_exact(&mut cells[cc..cc + 1])?,
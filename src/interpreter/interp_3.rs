@@ -4,6 +4,7 @@ use std::{
 };
 
 use crate::bf_types;
+use crate::cancel::{CancelToken, Poller};
 
 #[derive(Debug)]
 enum Ops {
@@ -50,6 +51,12 @@ fn parse(prog: &[u8]) -> Result<OpSequence, &'static str> {
 }
 
 pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_cancellable(prog, &CancelToken::new())
+}
+
+/// Run a bf program until it finishes or `cancel` is set.
+pub fn run_cancellable(prog: &[u8], cancel: &CancelToken) -> Result<(), Box<dyn error::Error>> {
+    let mut poller = Poller::new(cancel);
     let prog_ops = parse(prog).unwrap();
     let mut cells = vec![0u8; bf_types::BF_MEMORY_SIZE];
     let mut cp = 0;
@@ -61,7 +68,10 @@ pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
             Ops::Add => cells[cp] = cells[cp].wrapping_add(1),
             Ops::Sub => cells[cp] = cells[cp].wrapping_sub(1),
             Ops::LBrack(jump) if cells[cp] == 0 => pc = jump,
-            Ops::RBrack(jump) if cells[cp] != 0 => pc = jump,
+            Ops::RBrack(jump) if cells[cp] != 0 => {
+                pc = jump;
+                poller.tick(cp)?;
+            }
             Ops::Output => io::stdout().write_all(&cells[cp..cp + 1])?,
             Ops::Input => io::stdin().read_exact(&mut cells[cp..cp + 1])?,
             _ => (),
@@ -4,6 +4,7 @@ use std::{
 };
 
 use crate::bf_types;
+use crate::cancel::{CancelToken, Poller};
 
 #[derive(Debug)]
 enum Ops {
@@ -86,6 +87,12 @@ fn parse(prog: &[u8]) -> Result<OpSequence, &'static str> {
 }
 
 pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_cancellable(prog, &CancelToken::new())
+}
+
+/// Run a bf program until it finishes or `cancel` is set.
+pub fn run_cancellable(prog: &[u8], cancel: &CancelToken) -> Result<(), Box<dyn error::Error>> {
+    let mut poller = Poller::new(cancel);
     let prog_ops = parse(prog).unwrap();
 
     let mut cells = vec![0u8; bf_types::BF_MEMORY_SIZE];
@@ -98,7 +105,10 @@ pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
             Ops::Add(v) => cells[cc] = cells[cc].wrapping_add(v),
             Ops::Sub(v) => cells[cc] = cells[cc].wrapping_sub(v),
             Ops::LBrack(jump) if cells[cc] == 0 => pc = jump,
-            Ops::RBrack(jump) if cells[cc] != 0 => pc = jump,
+            Ops::RBrack(jump) if cells[cc] != 0 => {
+                pc = jump;
+                poller.tick(cc)?;
+            }
             Ops::Output => io::stdout().write_all(&cells[cc..cc + 1])?,
             Ops::Input => io::stdin().read_exact(&mut cells[cc..cc + 1])?,
             _ => (),
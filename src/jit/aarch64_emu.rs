@@ -0,0 +1,407 @@
+//! A small aarch64 emulator, so code from `aarch64_jit` can be run on any
+//! host.
+//!
+//! Only the instructions the backend emits are supported; anything else stops
+//! emulation with an error. The tape, the stack and the code live at fixed
+//! addresses, and calls to the helper addresses are carried out in Rust.
+
+use std::error;
+use std::io::{self, BufReader, BufWriter, Read, Write, stdin, stdout};
+
+use crate::bf_types::BF_MEMORY_SIZE;
+use crate::jit::aarch64_jit::{self, Helpers};
+use crate::jit::ir;
+
+const CODE_BASE: u64 = 0x1_0000;
+const TAPE_BASE: u64 = 0x10_0000;
+const STACK_BASE: u64 = 0x20_0000;
+const STACK_SIZE: u64 = 0x1000;
+const GETCHAR: u64 = 0xf000_0000;
+const PUTCHAR: u64 = 0xf000_0010;
+/// Return address of the entry call; returning to it ends emulation.
+const HOST_RETURN: u64 = 0xf000_0020;
+/// Passed as the state pointer, which the code only hands to the helpers.
+const STATE: u64 = 0xf000_1000;
+/// What the helpers leave in the registers they may clobber.
+const CLOBBERED: u64 = 0xdead_beef_dead_beef;
+
+struct Machine<'a> {
+    x: [u64; 31],
+    sp: u64,
+    pc: u64,
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    code: &'a [u8],
+    tape: Vec<u8>,
+    stack: Vec<u8>,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value as i64) << (64 - bits)) >> (64 - bits)
+}
+
+impl Machine<'_> {
+    /// Register `r`, where 31 is the zero register.
+    fn reg(&self, r: u32) -> u64 {
+        if r == 31 { 0 } else { self.x[r as usize] }
+    }
+
+    fn set_reg(&mut self, r: u32, value: u64) {
+        if r != 31 {
+            self.x[r as usize] = value;
+        }
+    }
+
+    /// Register `r`, where 31 is the stack pointer.
+    fn reg_sp(&self, r: u32) -> u64 {
+        if r == 31 { self.sp } else { self.x[r as usize] }
+    }
+
+    fn set_reg_sp(&mut self, r: u32, value: u64) {
+        if r == 31 {
+            self.sp = value;
+        } else {
+            self.x[r as usize] = value;
+        }
+    }
+
+    fn memory(&mut self, addr: u64, len: u64) -> Result<&mut [u8], String> {
+        let (base, region) = if (TAPE_BASE..TAPE_BASE + BF_MEMORY_SIZE as u64).contains(&addr) {
+            (TAPE_BASE, &mut self.tape)
+        } else if (STACK_BASE..STACK_BASE + STACK_SIZE).contains(&addr) {
+            (STACK_BASE, &mut self.stack)
+        } else {
+            return Err(format!("Access to unmapped address {addr:#x}"));
+        };
+        let start = (addr - base) as usize;
+        region
+            .get_mut(start..start + len as usize)
+            .ok_or_else(|| format!("Access to unmapped address {addr:#x}"))
+    }
+
+    fn load(&mut self, addr: u64, len: u64) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        if (CODE_BASE..CODE_BASE + self.code.len() as u64).contains(&addr) {
+            let start = (addr - CODE_BASE) as usize;
+            let code = self
+                .code
+                .get(start..start + len as usize)
+                .ok_or_else(|| format!("Access to unmapped address {addr:#x}"))?;
+            bytes[..len as usize].copy_from_slice(code);
+        } else {
+            bytes[..len as usize].copy_from_slice(self.memory(addr, len)?);
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn store(&mut self, addr: u64, len: u64, value: u64) -> Result<(), String> {
+        self.memory(addr, len)?
+            .copy_from_slice(&value.to_le_bytes()[..len as usize]);
+        Ok(())
+    }
+
+    fn condition(&self, cond: u32) -> bool {
+        let holds = match cond >> 1 {
+            0 => self.z,
+            1 => self.c,
+            2 => self.n,
+            3 => self.v,
+            4 => self.c && !self.z,
+            5 => self.n == self.v,
+            6 => !self.z && self.n == self.v,
+            _ => true,
+        };
+        if cond & 1 == 1 && cond != 0b1111 {
+            !holds
+        } else {
+            holds
+        }
+    }
+
+    /// Carry out a call to a helper, which returns to `x30`.
+    fn call_helper(&mut self) -> Result<(), String> {
+        let cell = self.x[1];
+        let failed = if self.pc == GETCHAR {
+            let mut byte = [0];
+            let failed = self.output.flush().is_err() | self.input.read_exact(&mut byte).is_err();
+            if !failed {
+                self.store(cell, 1, byte[0] as u64)?;
+            }
+            failed
+        } else {
+            let byte = self.load(cell, 1)? as u8;
+            self.output.write_all(&[byte]).is_err()
+        };
+        // The helpers are free to clobber every caller saved register.
+        for r in 1..=18 {
+            self.x[r] = CLOBBERED;
+        }
+        self.x[0] = failed as u64;
+        self.pc = self.x[30];
+        Ok(())
+    }
+
+    /// Execute the instruction at `pc`.
+    fn step(&mut self) -> Result<(), String> {
+        let offset = self.pc.wrapping_sub(CODE_BASE) as usize;
+        let word = match self.code.get(offset..offset + 4) {
+            Some(word) => u32::from_le_bytes(word.try_into().unwrap()),
+            None => return Err(format!("Jump to unmapped address {:#x}", self.pc)),
+        };
+        let rd = word & 31;
+        let rn = (word >> 5) & 31;
+        let rm = (word >> 16) & 31;
+        let sf = word >> 31 == 1;
+        let width = |value: u64| if sf { value } else { value as u32 as u64 };
+        let mut next = self.pc + 4;
+
+        if word & 0x3f80_0000 == 0x1100_0000 {
+            // ADD/SUB (immediate), without setting flags
+            let shift = if word & (1 << 22) != 0 { 12 } else { 0 };
+            let imm = (((word >> 10) & 0xfff) as u64) << shift;
+            let a = self.reg_sp(rn);
+            let result = if word & (1 << 30) != 0 {
+                a.wrapping_sub(imm)
+            } else {
+                a.wrapping_add(imm)
+            };
+            self.set_reg_sp(rd, width(result));
+        } else if word & 0x7fe0_fc00 == 0x6b00_0000 {
+            // SUBS (shifted register, no shift), i.e. CMP when rd is zr
+            let (a, b) = (width(self.reg(rn)), width(self.reg(rm)));
+            let result = width(a.wrapping_sub(b));
+            let top = if sf { 63 } else { 31 };
+            self.n = result >> top & 1 == 1;
+            self.z = result == 0;
+            self.c = a >= b;
+            self.v = ((a ^ b) & (a ^ result)) >> top & 1 == 1;
+            self.set_reg(rd, result);
+        } else if word & 0x7fe0_fc00 == 0x2a00_0000 {
+            // ORR (shifted register, no shift), i.e. MOV when rn is zr
+            let result = width(self.reg(rn) | self.reg(rm));
+            self.set_reg(rd, result);
+        } else if word & 0x7f80_0000 == 0x5280_0000 {
+            // MOVZ
+            let shift = ((word >> 21) & 3) * 16;
+            let imm = (((word >> 5) & 0xffff) as u64) << shift;
+            self.set_reg(rd, width(imm));
+        } else if word & 0x3f00_0000 == 0x3900_0000 {
+            // LDR/STR (unsigned offset)
+            let size = word >> 30;
+            let addr = self
+                .reg_sp(rn)
+                .wrapping_add((((word >> 10) & 0xfff) << size) as u64);
+            self.load_store(word, rd, addr, 1 << size)?;
+        } else if word & 0x3f20_0400 == 0x3800_0400 {
+            // LDR/STR (immediate, pre or post index)
+            let size = word >> 30;
+            let imm = sign_extend((word >> 12) & 0x1ff, 9) as u64;
+            let base = self.reg_sp(rn);
+            let updated = base.wrapping_add(imm);
+            let pre = word & (1 << 11) != 0;
+            self.load_store(word, rd, if pre { updated } else { base }, 1 << size)?;
+            self.set_reg_sp(rn, updated);
+        } else if word & 0xfe00_0000 == 0xa800_0000 && word & 0x0180_0000 != 0 {
+            // LDP/STP (64-bit, post index, signed offset or pre index)
+            let index = (word >> 23) & 3;
+            let imm = (sign_extend((word >> 15) & 0x7f, 7) * 8) as u64;
+            let rt2 = (word >> 10) & 31;
+            let base = self.reg_sp(rn);
+            let updated = base.wrapping_add(imm);
+            let addr = if index == 1 { base } else { updated };
+            if word & (1 << 22) != 0 {
+                let first = self.load(addr, 8)?;
+                let second = self.load(addr + 8, 8)?;
+                self.set_reg(rd, first);
+                self.set_reg(rt2, second);
+            } else {
+                self.store(addr, 8, self.reg(rd))?;
+                self.store(addr + 8, 8, self.reg(rt2))?;
+            }
+            if index != 2 {
+                self.set_reg_sp(rn, updated);
+            }
+        } else if word & 0xff00_0000 == 0x5800_0000 {
+            // LDR (literal, 64-bit)
+            let imm = sign_extend((word >> 5) & 0x7ffff, 19) * 4;
+            let value = self.load(self.pc.wrapping_add(imm as u64), 8)?;
+            self.set_reg(rd, value);
+        } else if word & 0x7e00_0000 == 0x3400_0000 {
+            // CBZ/CBNZ
+            let zero = width(self.reg(rd)) == 0;
+            let branch = if word & (1 << 24) != 0 { !zero } else { zero };
+            if branch {
+                let imm = sign_extend((word >> 5) & 0x7ffff, 19) * 4;
+                next = self.pc.wrapping_add(imm as u64);
+            }
+        } else if word & 0xff00_0010 == 0x5400_0000 {
+            // B.cond
+            if self.condition(word & 15) {
+                let imm = sign_extend((word >> 5) & 0x7ffff, 19) * 4;
+                next = self.pc.wrapping_add(imm as u64);
+            }
+        } else if word & 0xffff_fc1f == 0xd63f_0000 {
+            // BLR
+            next = self.reg(rn);
+            self.x[30] = self.pc + 4;
+        } else if word & 0xffff_fc1f == 0xd65f_0000 {
+            // RET
+            next = self.reg(rn);
+        } else {
+            return Err(format!(
+                "Unsupported instruction {word:#010x} at {:#x}",
+                self.pc
+            ));
+        }
+        self.pc = next;
+        Ok(())
+    }
+
+    /// LDR/STR of `len` bytes between `rt` and `addr`, zero extending loads.
+    fn load_store(&mut self, word: u32, rt: u32, addr: u64, len: u64) -> Result<(), String> {
+        match (word >> 22) & 3 {
+            0 => self.store(addr, len, self.reg(rt)),
+            1 => {
+                let value = self.load(addr, len)?;
+                self.set_reg(rt, value);
+                Ok(())
+            }
+            _ => Err(format!("Unsupported load/store {word:#010x}")),
+        }
+    }
+
+    /// Run until the code returns, giving back its exit code.
+    fn run(&mut self) -> Result<u8, String> {
+        loop {
+            match self.pc {
+                HOST_RETURN => break,
+                GETCHAR | PUTCHAR => self.call_helper()?,
+                _ => self.step()?,
+            }
+        }
+        if self.sp != STACK_BASE + STACK_SIZE {
+            return Err(format!("Stack pointer not restored: {:#x}", self.sp));
+        }
+        Ok(self.x[0] as u8)
+    }
+}
+
+/// Compile `prog` for aarch64 and emulate it, returning the exit code of the
+/// generated code.
+fn emulate(
+    prog: &[u8],
+    bounds_checked: bool,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<u8, Box<dyn error::Error>> {
+    let bf_ops = ir::parse(prog)?;
+    let helpers = Helpers {
+        getchar: GETCHAR,
+        putchar: PUTCHAR,
+    };
+    let code = aarch64_jit::compile(&bf_ops, bounds_checked, &helpers)?;
+
+    let mut machine = Machine {
+        x: [0; 31],
+        sp: STACK_BASE + STACK_SIZE,
+        pc: CODE_BASE + code.start.0 as u64,
+        n: false,
+        z: false,
+        c: false,
+        v: false,
+        code: &code.bytes,
+        tape: vec![0; BF_MEMORY_SIZE],
+        stack: vec![0; STACK_SIZE as usize],
+        input,
+        output,
+    };
+    machine.x[0] = STATE;
+    machine.x[1] = TAPE_BASE;
+    machine.x[2] = TAPE_BASE;
+    machine.x[3] = TAPE_BASE + BF_MEMORY_SIZE as u64;
+    machine.x[30] = HOST_RETURN;
+    Ok(machine.run()?)
+}
+
+/// Emulate `prog` on stdin and stdout, reporting errors like the aarch64
+/// backend does.
+fn run_stdio(prog: &[u8], bounds_checked: bool) -> Result<(), Box<dyn error::Error>> {
+    let mut input = BufReader::new(stdin());
+    let mut output = BufWriter::new(stdout());
+    match emulate(prog, bounds_checked, &mut input, &mut output)? {
+        0 => Ok(output.flush()?),
+        1 => Err("IO error".into()),
+        3 => Err("Out of bounds".into()),
+        code => Err(format!("Unknown exit code {code}").into()),
+    }
+}
+
+fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_stdio(prog, false)
+}
+
+fn run_bounds_checked(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_stdio(prog, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_helper::{test_hell, test_run};
+
+    use super::*;
+
+    fn emulate_bytes(prog: &[u8], bounds_checked: bool, input: &[u8]) -> (u8, Vec<u8>) {
+        let mut output = vec![];
+        let code = emulate(
+            prog,
+            bounds_checked,
+            &mut io::Cursor::new(input),
+            &mut output,
+        )
+        .unwrap();
+        (code, output)
+    }
+
+    #[test]
+    fn hello_bf() {
+        let prog = include_bytes!("../../bf/hello.bf");
+        let (code, output) = emulate_bytes(prog, false, b"");
+        assert_eq!(code, 0);
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn hello() {
+        assert!(test_run(&run).is_ok());
+    }
+
+    #[test]
+    fn hello_hell() {
+        assert!(test_hell(&run).is_ok());
+    }
+
+    #[test]
+    fn hello_hell_bounds_checked() {
+        assert!(test_hell(&run_bounds_checked).is_ok());
+    }
+
+    #[test]
+    fn echo() {
+        assert_eq!(
+            emulate_bytes(b",+.,+.,+.", false, b"HAL"),
+            (0, b"IBM".to_vec())
+        );
+        assert_eq!(emulate_bytes(b",.,.", false, b"a"), (1, b"a".to_vec()));
+    }
+
+    #[test]
+    fn bounds_checked_left() {
+        assert_eq!(emulate_bytes(b"+[<+]", true, b""), (3, vec![]));
+        let err = emulate(b"+[<+]", false, &mut io::empty(), &mut io::sink()).unwrap_err();
+        assert!(err.to_string().starts_with("Access to unmapped address"));
+    }
+}
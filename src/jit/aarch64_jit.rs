@@ -1,22 +1,28 @@
-use dynasmrt::{AssemblyOffset, ExecutableBuffer};
-use dynasmrt::{DynasmApi, DynasmLabelApi, dynasm};
+//! Code generation for aarch64.
+//!
+//! Code is assembled into a plain byte buffer, so it can be generated and
+//! tested on any host. Only running it needs an aarch64 host.
+
+// Elsewhere the code is only generated by tests.
+#![cfg_attr(not(target_arch = "aarch64"), allow(dead_code))]
 
-use itertools::Itertools;
-use itertools::multipeek;
+use dynasmrt::aarch64::Aarch64Relocation;
+use dynasmrt::{AssemblyOffset, VecAssembler};
+use dynasmrt::{DynasmApi, DynasmLabelApi, dynasm};
 
-use core::str;
+#[cfg(target_arch = "aarch64")]
 use std::error;
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::io::stdin;
-use std::io::stdout;
-use std::io::{BufRead, Read, Write};
-use std::mem;
-use std::slice;
-use std::u8;
-
-use crate::bf_types;
+#[cfg(target_arch = "aarch64")]
+use std::io::{BufRead, BufReader, BufWriter, Read, Write, stdin, stdout};
+#[cfg(target_arch = "aarch64")]
+use std::{mem, slice};
+
+#[cfg(target_arch = "aarch64")]
 use crate::bf_types::BF_MEMORY_SIZE;
+use crate::jit::bounds_check::{self, Access, CheckRange};
+#[cfg(target_arch = "aarch64")]
+use crate::jit::ir;
+use crate::jit::ir::Ops;
 
 macro_rules! arm64_bf {
     ($ops:ident $($t:tt)*) => {
@@ -64,113 +70,88 @@ macro_rules! call_extern {
     );};
 }
 
-/// BrainFuck AST node
-#[derive(Debug)]
-enum Ops {
-    Left(usize),
-    Right(usize),
-    Add(u8),
-    Sub(u8),
-    Zero,
-    LBrack,
-    RBrack,
-    Output,
-    Input,
-}
-
-type OpSequence = Vec<Ops>;
-
-fn parse(prog_src: &bf_types::BfSrc) -> Result<OpSequence, &'static str> {
-    let mut prog_ops = vec![];
-    let mut i = 0;
-    while i < prog_src.len() {
-        match prog_src[i] as char {
-            '<' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '<' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Left(count));
-                i = j - 1;
-            }
-            '>' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '>' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Right(count));
-                i = j - 1;
-            }
-            '-' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '-' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Sub(count));
-                i = j - 1;
-            }
-            '+' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '+' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Add(count));
-                i = j - 1;
-            }
-            '[' => {
-                // Check if it is [-]
-                if i + 2 < prog_src.len()
-                    && prog_src[i + 1] as char == '-'
-                    && prog_src[i + 2] as char == ']'
-                {
-                    prog_ops.push(Ops::Zero);
-                    i += 3;
-                    continue;
-                }
-                prog_ops.push(Ops::LBrack);
-            }
-            ']' => {
-                prog_ops.push(Ops::RBrack);
-            }
-            '.' => prog_ops.push(Ops::Output),
-            ',' => prog_ops.push(Ops::Input),
-            _ => (),
+/// Branch to `->outbound` unless every offset in `range` from the current
+/// pointer is inside `[a_begin, a_end)`.
+fn emit_bounds_check(
+    ops: &mut VecAssembler<Aarch64Relocation>,
+    range: &CheckRange,
+) -> Result<(), &'static str> {
+    for (offset, lower) in [(*range.start(), true), (*range.end(), false)] {
+        let magnitude = offset.unsigned_abs();
+        if magnitude >= 1 << 24 {
+            return Err("Pointer offset too large");
+        }
+        let magnitude = magnitude as u32;
+        if offset < 0 {
+            arm64_bf!(ops
+                ; sub x9, a_current, magnitude & 0xFFF
+                ; sub x9, x9, magnitude >> 12, LSL #12
+            );
+        } else {
+            arm64_bf!(ops
+                ; add x9, a_current, magnitude & 0xFFF
+                ; add x9, x9, magnitude >> 12, LSL #12
+            );
+        }
+        if lower {
+            arm64_bf!(ops
+                ; cmp x9, a_begin
+                ; b.lo ->outbound
+            );
+        } else {
+            arm64_bf!(ops
+                ; cmp x9, a_end
+                ; b.hs ->outbound
+            );
         }
-        i += 1;
     }
-    Ok(prog_ops)
+    Ok(())
 }
 
-struct State<'a> {
-    pub input: Box<dyn BufRead + 'a>,
-    pub output: Box<dyn Write + 'a>,
-    tape: [u8; BF_MEMORY_SIZE],
+/// Addresses of the I/O helpers the generated code calls.
+///
+/// Both are called as `extern "C" fn(state, cell) -> u8` and return non-zero
+/// on failure.
+pub(super) struct Helpers {
+    pub(super) getchar: u64,
+    pub(super) putchar: u64,
 }
 
-fn compile(prog: &bf_types::BfSrc) -> Result<(ExecutableBuffer, AssemblyOffset), &'static str> {
-    let bf_ops = parse(&prog)?;
-    let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
+/// Code generated for a whole program.
+///
+/// The entry point at `start` is called as
+/// `extern "C" fn(state, current, begin, end) -> u8` and returns 0 on success,
+/// 1 when I/O failed and 3 when the tape pointer left the tape, the status
+/// codes of the x86-64 backend.
+pub(super) struct Code {
+    pub(super) bytes: Vec<u8>,
+    pub(super) start: AssemblyOffset,
+}
+
+pub(super) fn compile(
+    bf_ops: &[Ops],
+    bounds_checked: bool,
+    helpers: &Helpers,
+) -> Result<Code, &'static str> {
+    let accesses: Vec<Access> = bf_ops.iter().map(Ops::access).collect();
+    let plan = bounds_checked.then(|| bounds_check::plan(&accesses));
+    let mut ops = VecAssembler::<Aarch64Relocation>::new(0);
     let mut loop_stack = vec![];
 
     // literal pool
-    dynasm!(ops
+    arm64_bf!(ops
         ; ->getchar:
-        ; .qword State::getchar as _
+        ; .qword helpers.getchar as _
         ; ->putchar:
-        ; .qword State::putchar as _
+        ; .qword helpers.putchar as _
     );
 
     let start = prologue!(ops);
 
-    for c in bf_ops {
+    for (i, &c) in bf_ops.iter().enumerate() {
+        if let Some(range) = plan.as_ref().and_then(|p| p.before[i].as_ref()) {
+            emit_bounds_check(&mut ops, range)?;
+        }
         match c {
             Ops::Left(amount) => {
                 arm64_bf!(ops
@@ -210,6 +191,11 @@ fn compile(prog: &bf_types::BfSrc) -> Result<(ExecutableBuffer, AssemblyOffset),
                 arm64_bf!(ops
                     ; ldrb w9, [a_current]
                     ; cbz w9, =>forward_label
+                );
+                if let Some(range) = plan.as_ref().and_then(|p| p.on_entry[i].as_ref()) {
+                    emit_bounds_check(&mut ops, range)?;
+                }
+                arm64_bf!(ops
                     ;=>backward_label
                 );
             }
@@ -238,86 +224,257 @@ fn compile(prog: &bf_types::BfSrc) -> Result<(ExecutableBuffer, AssemblyOffset),
             }
         }
     }
-    if loop_stack.len() != 0 {
+    if !loop_stack.is_empty() {
         return Err("[ without matching ]");
     }
 
     arm64_bf!(ops
         ;; epilogue!(ops, 0)
-        // ;->outbound:
-        // ;; epilogue!(ops, 1)
+        ;->outbound:
+        ;; epilogue!(ops, 3)
         ;->io_failure:
-        ;; epilogue!(ops, 2)
+        ;; epilogue!(ops, 1)
     );
 
-    Ok((ops.finalize().unwrap(), start))
+    // Conditional branches reach 1MiB at most.
+    let bytes = ops.finalize().map_err(|_| "Program too large")?;
+    Ok(Code { bytes, start })
 }
 
+#[cfg(target_arch = "aarch64")]
+struct State<'a> {
+    pub input: Box<dyn BufRead + 'a>,
+    pub output: Box<dyn Write + 'a>,
+    tape: [u8; BF_MEMORY_SIZE],
+}
+
+#[cfg(target_arch = "aarch64")]
 impl<'a> State<'a> {
     unsafe extern "C" fn getchar(state: *mut State, cell: *mut u8) -> u8 {
-        let state = &mut *state;
+        let state = unsafe { &mut *state };
         let err = state.output.flush().is_err();
-        (state
-            .input
-            .read_exact(slice::from_raw_parts_mut(cell, 1))
-            .is_err()
-            || err) as u8
+        let cell = unsafe { slice::from_raw_parts_mut(cell, 1) };
+        (state.input.read_exact(cell).is_err() || err) as u8
     }
 
     unsafe extern "C" fn putchar(state: *mut State, cell: *mut u8) -> u8 {
-        let state = &mut *state;
-        state
-            .output
-            .write_all(slice::from_raw_parts(cell, 1))
-            .is_err() as u8
+        let state = unsafe { &mut *state };
+        let cell = unsafe { slice::from_raw_parts(cell, 1) };
+        state.output.write_all(cell).is_err() as u8
     }
 
     fn new(input: Box<dyn BufRead + 'a>, output: Box<dyn Write + 'a>) -> State<'a> {
         State {
-            input: input,
-            output: output,
+            input,
+            output,
             tape: [0; BF_MEMORY_SIZE],
         }
     }
 }
 
-pub fn run(prog: &bf_types::BfSrc) -> Result<(), Box<dyn error::Error>> {
-    let (exe_buf, start) = compile(prog).unwrap();
+#[cfg(target_arch = "aarch64")]
+fn execute(prog: &[u8], bounds_checked: bool) -> Result<(), Box<dyn error::Error>> {
+    let bf_ops = ir::parse(prog)?;
+    let helpers = Helpers {
+        getchar: State::getchar as *const () as u64,
+        putchar: State::putchar as *const () as u64,
+    };
+    let code = compile(&bf_ops, bounds_checked, &helpers)?;
+    let mut ops = dynasmrt::aarch64::Assembler::new()?;
+    ops.extend(&code.bytes);
+    let exe_buf = ops.finalize().map_err(|_| "Failed to map generated code")?;
+
     let mut state = State::new(
         Box::new(BufReader::new(stdin())),
         Box::new(BufWriter::new(stdout())),
     );
 
     let f: extern "C" fn(*mut State, *mut u8, *mut u8, *const u8) -> u8 =
-        unsafe { mem::transmute(exe_buf.ptr(start)) };
+        unsafe { mem::transmute(exe_buf.ptr(code.start)) };
 
     let start = state.tape.as_mut_ptr();
-    let end = unsafe { start.offset(BF_MEMORY_SIZE as isize) };
+    let end = unsafe { start.add(BF_MEMORY_SIZE) };
     let res = f(&mut state, start, start, end);
 
-    if res == 0 {
-        Ok(())
-    } else if res == 1 {
-        Err(Box::<dyn error::Error>::from("Memory Error"))
-    } else if res == 2 {
-        Err(Box::<dyn error::Error>::from("IO error"))
-    } else {
-        panic!("Unknown error code");
+    match res {
+        0 => Ok(state.output.flush()?),
+        1 => Err(Box::<dyn error::Error>::from("IO error")),
+        3 => Err(Box::<dyn error::Error>::from("Out of bounds")),
+        _ => panic!("Unknown error code"),
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    execute(prog, false)
+}
+
+/// Run a bf program with explicit bounds checks compiled into the code.
+#[cfg(target_arch = "aarch64")]
+pub fn run_bounds_checked(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    execute(prog, true)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::jit::ir;
+
+    #[cfg(target_arch = "aarch64")]
     use crate::tests::test_helper::{test_hell, test_run};
 
-    use super::*;
+    const HELPERS: Helpers = Helpers {
+        getchar: 0x1111_2222_3333_4444,
+        putchar: 0x5555_6666_7777_8888,
+    };
+
+    /// Instruction words generated for `prog`, after the literal pool.
+    fn words(prog: &[u8], bounds_checked: bool) -> Vec<u32> {
+        let ops = ir::parse(prog).unwrap();
+        let code = compile(&ops, bounds_checked, &HELPERS).unwrap();
+        assert_eq!(code.start.0, 16);
+        code.bytes[code.start.0..]
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn literal_pool_holds_helpers() {
+        let code = compile(&[], false, &HELPERS).unwrap();
+        assert_eq!(
+            code.bytes[..16],
+            [
+                0x44, 0x44, 0x33, 0x33, 0x22, 0x22, 0x11, 0x11, //
+                0x88, 0x88, 0x77, 0x77, 0x66, 0x66, 0x55, 0x55,
+            ]
+        );
+    }
+
+    const PROLOGUE: [u32; 3] = [
+        0xf81f0ffe, // str x30, [sp, #-16]!
+        0xa9bf07e0, // stp x0, x1, [sp, #-16]!
+        0xa9bf0fe2, // stp x2, x3, [sp, #-16]!
+    ];
+
+    const EPILOGUES: [u32; 12] = [
+        0xd2800000, // mov x0, #0
+        0x910083ff, // add sp, sp, #32
+        0xf84107fe, // ldr x30, [sp], #16
+        0xd65f03c0, // ret
+        0xd2800060, // outbound: mov x0, #3
+        0x910083ff, // add sp, sp, #32
+        0xf84107fe, // ldr x30, [sp], #16
+        0xd65f03c0, // ret
+        0xd2800020, // io_failure: mov x0, #1
+        0x910083ff, // add sp, sp, #32
+        0xf84107fe, // ldr x30, [sp], #16
+        0xd65f03c0, // ret
+    ];
+
+    fn golden(body: &[u32]) -> Vec<u32> {
+        [&PROLOGUE[..], body, &EPILOGUES[..]].concat()
+    }
+
+    #[test]
+    fn golden_arithmetic_and_moves() {
+        let body = [
+            0x39400029, // ldrb w9, [x1]
+            0x11000529, // add w9, w9, #1
+            0x39000029, // strb w9, [x1]
+            0x91000421, // add x1, x1, #1
+            0x91400021, // add x1, x1, #0, lsl #12
+            0x39400029, // ldrb w9, [x1]
+            0x51000529, // sub w9, w9, #1
+            0x39000029, // strb w9, [x1]
+            0xd1000421, // sub x1, x1, #1
+            0xd1400021, // sub x1, x1, #0, lsl #12
+            0x3900003f, // strb wzr, [x1]
+        ];
+        assert_eq!(words(b"+>-<[-]", false), golden(&body));
+    }
+
+    #[test]
+    fn golden_long_move() {
+        let body = [
+            0x910e2021, // add x1, x1, #904
+            0x91400421, // add x1, x1, #1, lsl #12
+        ];
+        assert_eq!(words(&[b'>'; 5000], false), golden(&body));
+    }
+
+    #[test]
+    fn golden_loop_with_io() {
+        let body = [
+            0x39400029, // ldrb w9, [x1]
+            0x34000229, // cbz w9, end
+            0xf9000fe1, // head: str x1, [sp, #24]
+            0x58ffff09, // ldr x9, putchar
+            0xd63f0120, // blr x9
+            0xaa0003e9, // mov x9, x0
+            0xa94107e0, // ldp x0, x1, [sp, #16]
+            0xa9400fe2, // ldp x2, x3, [sp]
+            0xb5000249, // cbnz x9, io_failure
+            0xf9000fe1, // str x1, [sp, #24]
+            0x58fffde9, // ldr x9, getchar
+            0xd63f0120, // blr x9
+            0xaa0003e9, // mov x9, x0
+            0xa94107e0, // ldp x0, x1, [sp, #16]
+            0xa9400fe2, // ldp x2, x3, [sp]
+            0xb5000169, // cbnz x9, io_failure
+            0x39400029, // ldrb w9, [x1]
+            0x35fffe29, // cbnz w9, head
+        ];
+        assert_eq!(words(b"[.,]", false), golden(&body));
+    }
+
+    #[test]
+    fn golden_bounds_check() {
+        let body = [
+            0xd1000429, // sub x9, x1, #1
+            0xd1400129, // sub x9, x9, #0, lsl #12
+            0xeb02013f, // cmp x9, x2
+            0x540001c3, // b.lo outbound
+            0xd1000429, // sub x9, x1, #1
+            0xd1400129, // sub x9, x9, #0, lsl #12
+            0xeb03013f, // cmp x9, x3
+            0x54000142, // b.hs outbound
+            0xd1000421, // sub x1, x1, #1
+            0xd1400021, // sub x1, x1, #0, lsl #12
+            0x39400029, // ldrb w9, [x1]
+            0x11000529, // add w9, w9, #1
+            0x39000029, // strb w9, [x1]
+        ];
+        assert_eq!(words(b"<+", true), golden(&body));
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        let ops = ir::parse(b"[").unwrap();
+        assert!(compile(&ops, false, &HELPERS).is_err());
+        let ops = ir::parse(b"]").unwrap();
+        assert!(compile(&ops, false, &HELPERS).is_err());
+    }
 
+    #[cfg(target_arch = "aarch64")]
     #[test]
     fn hello() {
         assert!(test_run(&run).is_ok());
     }
+    #[cfg(target_arch = "aarch64")]
     #[test]
     fn hello_hell() {
         assert!(test_hell(&run).is_ok());
     }
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn hello_bounds_checked() {
+        assert!(test_run(&run_bounds_checked).is_ok());
+    }
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn bounds_checked_left() {
+        let err = run_bounds_checked(b"+[<+]").unwrap_err();
+        assert_eq!(err.to_string(), "Out of bounds");
+    }
 }
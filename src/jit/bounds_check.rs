@@ -0,0 +1,207 @@
+//! Placement of explicit tape bounds checks for the JIT backends.
+//!
+//! Instead of checking every access, straight-line code is split into
+//! segments and each segment gets a single check covering every cell it
+//! touches. Balanced loops (loops whose body returns the pointer to where it
+//! started) touch a fixed window relative to the loop entry, so they are
+//! checked once when the loop is entered and no checks are emitted inside.
+
+use std::ops::RangeInclusive;
+
+/// How an op interacts with the tape, as far as bounds checking cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Moves the pointer without touching a cell.
+    Move(isize),
+    /// Reads or writes the current cell.
+    Touch,
+    /// `[`: tests the current cell and may skip the body.
+    LoopStart,
+    /// `]`: tests the current cell and may jump back.
+    LoopEnd,
+}
+
+/// Cell offsets, relative to the pointer at the check, that must be in bounds.
+pub type CheckRange = RangeInclusive<isize>;
+
+#[derive(Debug, Default)]
+pub struct BoundsPlan {
+    /// Check to emit before op `i`.
+    pub before: Vec<Option<CheckRange>>,
+    /// Check to emit once on entry of the balanced loop starting at op `i`,
+    /// after the initial zero test and before the back-edge target.
+    pub on_entry: Vec<Option<CheckRange>>,
+}
+
+#[derive(Default)]
+struct Segment {
+    start: usize,
+    offset: isize,
+    range: Option<(isize, isize)>,
+}
+
+impl Segment {
+    fn new(start: usize) -> Segment {
+        Segment {
+            start,
+            ..Default::default()
+        }
+    }
+
+    fn touch(&mut self, lo: isize, hi: isize) {
+        self.range = Some(match self.range {
+            Some((min, max)) => (min.min(lo), max.max(hi)),
+            None => (lo, hi),
+        });
+    }
+
+    fn close(self, plan: &mut BoundsPlan) {
+        if let Some((min, max)) = self.range {
+            plan.before[self.start] = Some(min..=max);
+        }
+    }
+}
+
+/// Index of the matching `]` for every `[`, or `None` for unmatched brackets.
+fn match_brackets(accesses: &[Access]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; accesses.len()];
+    let mut stack = vec![];
+    for (i, access) in accesses.iter().enumerate() {
+        match access {
+            Access::LoopStart => stack.push(i),
+            Access::LoopEnd => {
+                if let Some(open) = stack.pop() {
+                    matches[open] = Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    matches
+}
+
+/// Window touched by the body of a balanced loop, relative to its entry.
+///
+/// Returns `None` if the body (or any nested loop) moves the pointer by a
+/// non-zero amount per iteration.
+fn balanced_window(
+    accesses: &[Access],
+    matches: &[Option<usize>],
+    open: usize,
+) -> Option<(isize, isize)> {
+    let close = matches[open]?;
+    let mut offset = 0;
+    let (mut min, mut max) = (0, 0);
+    let mut i = open + 1;
+    while i < close {
+        match accesses[i] {
+            Access::Move(amount) => offset += amount,
+            Access::Touch => {
+                min = min.min(offset);
+                max = max.max(offset);
+            }
+            Access::LoopStart => {
+                let (lo, hi) = balanced_window(accesses, matches, i)?;
+                min = min.min(offset + lo);
+                max = max.max(offset + hi);
+                i = matches[i]?;
+            }
+            Access::LoopEnd => return None,
+        }
+        i += 1;
+    }
+    (offset == 0).then_some((min, max))
+}
+
+/// Decide where bounds checks go for a program described by `accesses`.
+pub fn plan(accesses: &[Access]) -> BoundsPlan {
+    let matches = match_brackets(accesses);
+    let mut plan = BoundsPlan {
+        before: vec![None; accesses.len()],
+        on_entry: vec![None; accesses.len()],
+    };
+
+    let mut segment = Segment::new(0);
+    let mut i = 0;
+    while i < accesses.len() {
+        match accesses[i] {
+            Access::Move(amount) => segment.offset += amount,
+            Access::Touch => segment.touch(segment.offset, segment.offset),
+            Access::LoopStart => {
+                segment.touch(segment.offset, segment.offset);
+                if let Some((lo, hi)) = balanced_window(accesses, &matches, i) {
+                    plan.on_entry[i] = Some(lo..=hi);
+                    // The body, including the closing test, is covered by the
+                    // entry check and leaves the pointer where it was.
+                    i = matches[i].unwrap_or(i) + 1;
+                    continue;
+                }
+                segment.close(&mut plan);
+                segment = Segment::new(i + 1);
+            }
+            Access::LoopEnd => {
+                segment.touch(segment.offset, segment.offset);
+                segment.close(&mut plan);
+                segment = Segment::new(i + 1);
+            }
+        }
+        i += 1;
+    }
+    if segment.start < accesses.len() {
+        segment.close(&mut plan);
+    }
+
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Access::*;
+    use super::*;
+
+    #[test]
+    fn straight_line_is_one_check() {
+        let plan = plan(&[Touch, Move(3), Touch, Move(-5), Touch]);
+        assert_eq!(plan.before[0], Some(-2..=3));
+        assert!(plan.before[1..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn balanced_loop_is_checked_on_entry() {
+        // [->>+<<]
+        let plan = plan(&[LoopStart, Touch, Move(2), Touch, Move(-2), LoopEnd]);
+        assert_eq!(plan.before[0], Some(0..=0));
+        assert_eq!(plan.on_entry[0], Some(0..=2));
+        assert!(plan.before[1..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn unbalanced_loop_is_checked_every_iteration() {
+        // [>]
+        let plan = plan(&[LoopStart, Move(1), LoopEnd]);
+        assert_eq!(plan.before[0], Some(0..=0));
+        assert_eq!(plan.on_entry[0], None);
+        assert_eq!(plan.before[1], Some(1..=1));
+    }
+
+    #[test]
+    fn nested_balanced_loops_have_no_inner_checks() {
+        // [>[<+>-]<-]
+        let plan = plan(&[
+            LoopStart,
+            Move(1),
+            LoopStart,
+            Move(-1),
+            Touch,
+            Move(1),
+            Touch,
+            LoopEnd,
+            Move(-1),
+            Touch,
+            LoopEnd,
+        ]);
+        assert_eq!(plan.on_entry[0], Some(0..=1));
+        assert_eq!(plan.on_entry[2], None);
+        assert!(plan.before[1..].iter().all(Option::is_none));
+    }
+}
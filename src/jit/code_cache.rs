@@ -0,0 +1,309 @@
+//! Persistent cache of JIT-compiled code.
+//!
+//! Compiled programs are stored as versioned blobs under
+//! `$XDG_CACHE_HOME/bf-jit-rs` (or `~/.cache/bf-jit-rs`), one file per key.
+//! The key covers the source, the codegen config, the host CPU features and
+//! the crate version, so anything that could change the generated code also
+//! changes the file it is looked up in. Backends put a fingerprint of their
+//! code generator, such as a version and the layout of the state the code
+//! accesses, into the config.
+//!
+//! Absolute addresses of runtime helpers differ between runs, so they are
+//! stored as relocations and patched in when the code is loaded.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const MAGIC: &[u8; 8] = b"BFJITC\0\0";
+const FORMAT_VERSION: u32 = 2;
+
+/// Runtime helper whose address is baked into compiled code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Helper {
+    /// Refills the input buffer.
+    Input,
+    /// Writes out the output buffer.
+    Output,
+}
+
+impl Helper {
+    fn from_u8(v: u8) -> Option<Helper> {
+        match v {
+            0 => Some(Helper::Input),
+            1 => Some(Helper::Output),
+            _ => None,
+        }
+    }
+}
+
+/// An absolute 64-bit address of `helper` at `offset` in the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reloc {
+    pub offset: usize,
+    pub helper: Helper,
+}
+
+/// Position independent compiled code, as stored in the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedCode {
+    /// Machine code with every relocation slot zeroed.
+    pub code: Vec<u8>,
+    pub relocs: Vec<Reloc>,
+    /// Offsets of the entry point and the memory error stub.
+    pub start: usize,
+    pub memory_error: usize,
+    pub max_move: usize,
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is stable across Rust releases.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+#[cfg(target_arch = "x86_64")]
+fn cpu_features() -> u64 {
+    let features = [
+        is_x86_feature_detected!("sse2"),
+        is_x86_feature_detected!("sse4.2"),
+        is_x86_feature_detected!("avx"),
+        is_x86_feature_detected!("avx2"),
+        is_x86_feature_detected!("bmi1"),
+        is_x86_feature_detected!("bmi2"),
+        is_x86_feature_detected!("popcnt"),
+        is_x86_feature_detected!("lzcnt"),
+    ];
+    features
+        .iter()
+        .enumerate()
+        .fold(0, |mask, (i, &on)| mask | (on as u64) << i)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn cpu_features() -> u64 {
+    0
+}
+
+/// Cache key for `prog` compiled with the backend specific `config` bytes.
+pub fn key(prog: &[u8], config: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for part in [
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        env::consts::ARCH.as_bytes(),
+        &cpu_features().to_le_bytes(),
+        &(config.len() as u64).to_le_bytes(),
+        config,
+        &(prog.len() as u64).to_le_bytes(),
+        prog,
+    ] {
+        hash = fnv1a(hash, part);
+    }
+    hash
+}
+
+fn encode(key: u64, cached: &CachedCode) -> Vec<u8> {
+    let mut blob = vec![];
+    blob.extend_from_slice(MAGIC);
+    blob.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    blob.extend_from_slice(&key.to_le_bytes());
+    for value in [cached.start, cached.memory_error, cached.max_move] {
+        blob.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+    blob.extend_from_slice(&(cached.relocs.len() as u64).to_le_bytes());
+    for reloc in &cached.relocs {
+        blob.extend_from_slice(&(reloc.offset as u64).to_le_bytes());
+        blob.push(reloc.helper as u8);
+    }
+    blob.extend_from_slice(&(cached.code.len() as u64).to_le_bytes());
+    blob.extend_from_slice(&cached.code);
+    blob
+}
+
+struct Reader<'a> {
+    blob: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        if self.blob.len() < n {
+            return None;
+        }
+        let (head, tail) = self.blob.split_at(n);
+        self.blob = tail;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?).ok()
+    }
+}
+
+/// Parse a blob, rejecting anything from another format version or key.
+fn decode(key: u64, blob: &[u8]) -> Option<CachedCode> {
+    let mut r = Reader { blob };
+    if r.take(MAGIC.len())? != MAGIC || r.u32()? != FORMAT_VERSION || r.u64()? != key {
+        return None;
+    }
+    let start = r.usize()?;
+    let memory_error = r.usize()?;
+    let max_move = r.usize()?;
+    let relocs = (0..r.usize()?)
+        .map(|_| {
+            Some(Reloc {
+                offset: r.usize()?,
+                helper: Helper::from_u8(r.take(1)?[0])?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let len = r.usize()?;
+    let code = r.take(len)?.to_vec();
+    let in_code =
+        |offset: usize, len: usize| offset.checked_add(len).is_some_and(|end| end <= code.len());
+    if !r.blob.is_empty()
+        || !in_code(start, 1)
+        || !in_code(memory_error, 1)
+        || relocs.iter().any(|reloc| !in_code(reloc.offset, 8))
+    {
+        return None;
+    }
+
+    Some(CachedCode {
+        code,
+        relocs,
+        start,
+        memory_error,
+        max_move,
+    })
+}
+
+pub struct CodeCache {
+    dir: PathBuf,
+}
+
+impl CodeCache {
+    pub fn new(dir: PathBuf) -> CodeCache {
+        CodeCache { dir }
+    }
+
+    /// The cache in `$XDG_CACHE_HOME/bf-jit-rs`, falling back to
+    /// `$HOME/.cache/bf-jit-rs`.
+    pub fn open_default() -> Option<CodeCache> {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(CodeCache::new(base.join("bf-jit-rs")))
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}.bin"))
+    }
+
+    /// Look up `key`. Missing, stale and corrupt entries are all misses.
+    pub fn load(&self, key: u64) -> Option<CachedCode> {
+        decode(key, &fs::read(self.path(key)).ok()?)
+    }
+
+    pub fn store(&self, key: u64, cached: &CachedCode) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Write to a private file first so concurrent runs never read a
+        // partially written entry.
+        let tmp = self
+            .dir
+            .join(format!("{key:016x}.{}.tmp", std::process::id()));
+        fs::write(&tmp, encode(key, cached))?;
+        fs::rename(&tmp, self.path(key))
+    }
+
+    /// Where a native executable built for `key` is kept.
+    pub fn executable_path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}.exe"))
+    }
+
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> CachedCode {
+        CachedCode {
+            code: vec![0x90; 32],
+            relocs: vec![Reloc {
+                offset: 4,
+                helper: Helper::Output,
+            }],
+            start: 0,
+            memory_error: 24,
+            max_move: 3,
+        }
+    }
+
+    #[test]
+    fn blob_roundtrip() {
+        let blob = encode(42, &sample());
+        assert_eq!(decode(42, &blob), Some(sample()));
+    }
+
+    #[test]
+    fn rejects_other_key_and_truncated_blob() {
+        let blob = encode(42, &sample());
+        assert_eq!(decode(43, &blob), None);
+        assert_eq!(decode(42, &blob[..blob.len() - 1]), None);
+    }
+
+    #[test]
+    fn rejects_offsets_outside_the_code() {
+        for (start, memory_error) in [(32, 24), (0, usize::MAX), (usize::MAX, 0)] {
+            let code = CachedCode {
+                start,
+                memory_error,
+                ..sample()
+            };
+            assert_eq!(decode(42, &encode(42, &code)), None);
+        }
+        let code = CachedCode {
+            relocs: vec![Reloc {
+                offset: usize::MAX - 4,
+                helper: Helper::Input,
+            }],
+            ..sample()
+        };
+        assert_eq!(decode(42, &encode(42, &code)), None);
+    }
+
+    #[test]
+    fn key_depends_on_source_and_config() {
+        assert_ne!(key(b"+", b"\0"), key(b"-", b"\0"));
+        assert_ne!(key(b"+", b"\0"), key(b"+", b"\x01"));
+        assert_eq!(key(b"+", b"\0"), key(b"+", b"\0"));
+    }
+
+    #[test]
+    fn store_load_clear() {
+        let dir = env::temp_dir().join(format!("bf-jit-cache-test-{}", std::process::id()));
+        let cache = CodeCache::new(dir.clone());
+        cache.store(7, &sample()).unwrap();
+        assert_eq!(cache.load(7), Some(sample()));
+        cache.clear().unwrap();
+        assert_eq!(cache.load(7), None);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,338 @@
+//! Portable JIT backend built on Cranelift.
+//!
+//! Lowers the same op IR as the dynasm backends to Cranelift IR and lets
+//! Cranelift optimize and emit native code for whatever host it runs on. The
+//! tape is an ordinary allocation, so the generated code always carries the
+//! explicit bounds checks placed by `bounds_check::plan`.
+
+use std::error;
+use std::io::{BufRead, BufReader, BufWriter, Write, stdin, stdout};
+use std::mem::{self, ManuallyDrop};
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{AbiParam, Block, FuncRef, InstBuilder, MemFlags, Value, types};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module, default_libcall_names};
+
+use crate::bf_types::BF_MEMORY_SIZE;
+use crate::jit::bounds_check::{self, Access, CheckRange};
+use crate::jit::ir::{Ops, parse};
+
+struct State<'a> {
+    input: Box<dyn BufRead + 'a>,
+    output: Box<dyn Write + 'a>,
+}
+
+/// Returns the next input byte, or -1 at the end of the input or on error.
+extern "C" fn bf_getchar(state: *mut State) -> i32 {
+    let state = unsafe { &mut *state };
+    let mut byte = 0;
+    match state.input.read_exact(std::slice::from_mut(&mut byte)) {
+        Ok(()) => byte as i32,
+        Err(_) => -1,
+    }
+}
+
+/// Returns non-zero on failure.
+extern "C" fn bf_putchar(state: *mut State, byte: i32) -> i32 {
+    let state = unsafe { &mut *state };
+    state.output.write_all(&[byte as u8]).is_err() as i32
+}
+
+const STATUS_OK: i64 = 0;
+const STATUS_IO_ERROR: i64 = 1;
+const STATUS_OUT_OF_BOUNDS: i64 = 3;
+
+/// Lowers ops into the body of `bf_main(state, tape) -> status`.
+struct Lowering<'f> {
+    b: FunctionBuilder<'f>,
+    /// Address of the current cell.
+    current: Variable,
+    begin: Value,
+    end: Value,
+    state: Value,
+    getchar: FuncRef,
+    putchar: FuncRef,
+    io_failure: Block,
+    out_of_bounds: Block,
+}
+
+impl Lowering<'_> {
+    fn cell_flags() -> MemFlags {
+        // Every access is covered by a bounds check.
+        MemFlags::trusted()
+    }
+
+    fn load(&mut self) -> Value {
+        let current = self.b.use_var(self.current);
+        self.b.ins().load(types::I8, Self::cell_flags(), current, 0)
+    }
+
+    fn store(&mut self, value: Value) {
+        let current = self.b.use_var(self.current);
+        self.b.ins().store(Self::cell_flags(), value, current, 0);
+    }
+
+    fn bounds_check(&mut self, range: &CheckRange) {
+        let current = self.b.use_var(self.current);
+        let lo = self.b.ins().iadd_imm(current, *range.start() as i64);
+        let below = self.b.ins().icmp(IntCC::UnsignedLessThan, lo, self.begin);
+        let hi = self.b.ins().iadd_imm(current, *range.end() as i64);
+        let above = self
+            .b
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, hi, self.end);
+        let outside = self.b.ins().bor(below, above);
+        let next = self.b.create_block();
+        self.b
+            .ins()
+            .brif(outside, self.out_of_bounds, &[], next, &[]);
+        self.b.switch_to_block(next);
+    }
+
+    /// Continue in a new block if `failed` is zero, else fail with an I/O error.
+    fn check_io(&mut self, failed: Value) {
+        let next = self.b.create_block();
+        self.b.ins().brif(failed, self.io_failure, &[], next, &[]);
+        self.b.switch_to_block(next);
+    }
+
+    fn lower(&mut self, bf_ops: &[Ops]) -> Result<(), Box<dyn error::Error>> {
+        let accesses: Vec<Access> = bf_ops.iter().map(Ops::access).collect();
+        let plan = bounds_check::plan(&accesses);
+        // Body and exit block of every open loop.
+        let mut loop_stack: Vec<(Block, Block)> = vec![];
+
+        for (i, &op) in bf_ops.iter().enumerate() {
+            if let Some(range) = &plan.before[i] {
+                self.bounds_check(range);
+            }
+            match op {
+                Ops::Left(amount) | Ops::Right(amount) => {
+                    let delta = match op {
+                        Ops::Left(_) => -(amount as i64),
+                        _ => amount as i64,
+                    };
+                    let current = self.b.use_var(self.current);
+                    let moved = self.b.ins().iadd_imm(current, delta);
+                    self.b.def_var(self.current, moved);
+                }
+                Ops::Add(amount) => {
+                    let cell = self.load();
+                    let sum = self.b.ins().iadd_imm(cell, amount as i64);
+                    self.store(sum);
+                }
+                Ops::Sub(amount) => {
+                    let cell = self.load();
+                    let diff = self.b.ins().iadd_imm(cell, -(amount as i64));
+                    self.store(diff);
+                }
+                Ops::Zero => {
+                    let zero = self.b.ins().iconst(types::I8, 0);
+                    self.store(zero);
+                }
+                Ops::LBrack => {
+                    let entry = self.b.create_block();
+                    let body = self.b.create_block();
+                    let exit = self.b.create_block();
+                    let cell = self.load();
+                    self.b.ins().brif(cell, entry, &[], exit, &[]);
+                    self.b.switch_to_block(entry);
+                    if let Some(range) = &plan.on_entry[i] {
+                        self.bounds_check(range);
+                    }
+                    self.b.ins().jump(body, &[]);
+                    self.b.switch_to_block(body);
+                    loop_stack.push((body, exit));
+                }
+                Ops::RBrack => {
+                    let (body, exit) = loop_stack.pop().ok_or("] without matching [")?;
+                    let cell = self.load();
+                    self.b.ins().brif(cell, body, &[], exit, &[]);
+                    self.b.switch_to_block(exit);
+                }
+                Ops::Output => {
+                    let cell = self.load();
+                    let byte = self.b.ins().uextend(types::I32, cell);
+                    let call = self.b.ins().call(self.putchar, &[self.state, byte]);
+                    let failed = self.b.inst_results(call)[0];
+                    self.check_io(failed);
+                }
+                Ops::Input => {
+                    let call = self.b.ins().call(self.getchar, &[self.state]);
+                    let byte = self.b.inst_results(call)[0];
+                    let failed = self.b.ins().icmp_imm(IntCC::SignedLessThan, byte, 0);
+                    self.check_io(failed);
+                    let cell = self.b.ins().ireduce(types::I8, byte);
+                    self.store(cell);
+                }
+            }
+        }
+        if !loop_stack.is_empty() {
+            return Err("[ without matching ]".into());
+        }
+        Ok(())
+    }
+}
+
+/// Compiled code together with the module that owns its memory.
+struct Compiled {
+    module: ManuallyDrop<JITModule>,
+    main: FuncId,
+}
+
+fn compile(bf_ops: &[Ops]) -> Result<Compiled, Box<dyn error::Error>> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed")?;
+    let isa = cranelift_native::builder()?.finish(settings::Flags::new(flags))?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    builder.symbol("bf_getchar", bf_getchar as *const u8);
+    builder.symbol("bf_putchar", bf_putchar as *const u8);
+    let mut module = JITModule::new(builder);
+
+    let ptr = module.target_config().pointer_type();
+    let mut getchar_sig = module.make_signature();
+    getchar_sig.params.push(AbiParam::new(ptr));
+    getchar_sig.returns.push(AbiParam::new(types::I32));
+    let getchar = module.declare_function("bf_getchar", Linkage::Import, &getchar_sig)?;
+    let mut putchar_sig = module.make_signature();
+    putchar_sig.params.push(AbiParam::new(ptr));
+    putchar_sig.params.push(AbiParam::new(types::I32));
+    putchar_sig.returns.push(AbiParam::new(types::I32));
+    let putchar = module.declare_function("bf_putchar", Linkage::Import, &putchar_sig)?;
+
+    let mut ctx = module.make_context();
+    ctx.func.signature.params.push(AbiParam::new(ptr));
+    ctx.func.signature.params.push(AbiParam::new(ptr));
+    ctx.func.signature.returns.push(AbiParam::new(types::I32));
+    let main = module.declare_function("bf_main", Linkage::Local, &ctx.func.signature)?;
+
+    let mut fn_ctx = FunctionBuilderContext::new();
+    let mut b = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+    let entry = b.create_block();
+    b.append_block_params_for_function_params(entry);
+    b.switch_to_block(entry);
+    let state = b.block_params(entry)[0];
+    let begin = b.block_params(entry)[1];
+    let end = b.ins().iadd_imm(begin, BF_MEMORY_SIZE as i64);
+    let current = Variable::from_u32(0);
+    b.declare_var(current, ptr);
+    b.def_var(current, begin);
+
+    let getchar = module.declare_func_in_func(getchar, b.func);
+    let putchar = module.declare_func_in_func(putchar, b.func);
+    let io_failure = b.create_block();
+    let out_of_bounds = b.create_block();
+    let mut lowering = Lowering {
+        b,
+        current,
+        begin,
+        end,
+        state,
+        getchar,
+        putchar,
+        io_failure,
+        out_of_bounds,
+    };
+    lowering.lower(bf_ops)?;
+
+    let mut b = lowering.b;
+    for (block, status) in [
+        (None, STATUS_OK),
+        (Some(io_failure), STATUS_IO_ERROR),
+        (Some(out_of_bounds), STATUS_OUT_OF_BOUNDS),
+    ] {
+        if let Some(block) = block {
+            b.switch_to_block(block);
+        }
+        let status = b.ins().iconst(types::I32, status);
+        b.ins().return_(&[status]);
+    }
+    b.seal_all_blocks();
+    b.finalize();
+
+    module.define_function(main, &mut ctx)?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions()?;
+    Ok(Compiled {
+        module: ManuallyDrop::new(module),
+        main,
+    })
+}
+
+impl Compiled {
+    fn call(&self, state: &mut State, tape: &mut [u8]) -> Result<(), Box<dyn error::Error>> {
+        assert!(tape.len() >= BF_MEMORY_SIZE);
+        let code = self.module.get_finalized_function(self.main);
+        let f: extern "C" fn(*mut State, *mut u8) -> i32 = unsafe { mem::transmute(code) };
+        match f(state, tape.as_mut_ptr()) as i64 {
+            STATUS_OK => Ok(()),
+            STATUS_IO_ERROR => Err("IO error".into()),
+            STATUS_OUT_OF_BOUNDS => Err("Out of bounds".into()),
+            res => Err(format!("Unknown Error: {res}").into()),
+        }
+    }
+}
+
+impl Drop for Compiled {
+    fn drop(&mut self) {
+        // Nothing can call into the code once `self` is gone.
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() };
+    }
+}
+
+/// Run a bf program through the Cranelift backend.
+pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    let compiled = compile(&parse(prog)?)?;
+    let mut state = State {
+        input: Box::new(BufReader::new(stdin())),
+        output: Box::new(BufWriter::new(stdout())),
+    };
+    let mut tape = vec![0; BF_MEMORY_SIZE];
+    let res = compiled.call(&mut state, &mut tape);
+    state.output.flush()?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_helper::{test_hell, test_run};
+
+    use super::*;
+
+    fn run_with_input(prog: &[u8], input: &[u8]) -> (Result<(), Box<dyn error::Error>>, Vec<u8>) {
+        let compiled = compile(&parse(prog).unwrap()).unwrap();
+        let mut output = vec![];
+        let mut state = State {
+            input: Box::new(input),
+            output: Box::new(&mut output),
+        };
+        let res = compiled.call(&mut state, &mut vec![0; BF_MEMORY_SIZE]);
+        drop(state);
+        (res, output)
+    }
+
+    #[test]
+    fn hello() {
+        assert!(test_run(&run).is_ok());
+    }
+    #[test]
+    fn hello_hell() {
+        assert!(test_hell(&run).is_ok());
+    }
+    #[test]
+    fn echo() {
+        let (res, output) = run_with_input(b",[+.,]", b"HAL");
+        assert_eq!(res.unwrap_err().to_string(), "IO error");
+        assert_eq!(output, b"IBM");
+    }
+    #[test]
+    fn out_of_bounds() {
+        for prog in [&b"<+"[..], b"+[>+]", b"+[<]"] {
+            let (res, _) = run_with_input(prog, b"");
+            assert_eq!(res.unwrap_err().to_string(), "Out of bounds");
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Registration of JIT code with GDB's JIT interface.
+//!
+//! GDB puts a breakpoint on `__jit_debug_register_code` and, whenever it is
+//! hit, reads the entry `__jit_debug_descriptor` points at. Each entry is an
+//! in-memory ELF file describing one piece of compiled code: a `.text` at the
+//! address the code runs at, symbols for the unit and its loops, and DWARF
+//! line tables mapping every op back to its line and column in the `.bf`
+//! file. With that, `break prog.bf:12` and `step` work on JIT'd code.
+
+use std::ffi::OsStr;
+use std::io;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::jit::ir::line_col;
+use crate::jit::x64_disasm::label_name;
+use crate::jit::x64_jit::{CodeMap, Label};
+use crate::native::elf::{self, SHT_PROGBITS, Section, Symbol};
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next: *mut JitCodeEntry,
+    prev: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+/// The names and layout of these two are fixed by GDB.
+#[unsafe(no_mangle)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+#[unsafe(no_mangle)]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // Keeps the call from being optimized away; GDB breaks here.
+    unsafe { std::arch::asm!("", options(nostack, preserves_flags)) };
+}
+
+/// Serializes updates of the descriptor and holds the enabled source file.
+static SOURCE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Register code compiled from now on, with line tables pointing at `source`.
+pub fn enable(source: &Path) -> io::Result<()> {
+    *SOURCE.lock().unwrap() = Some(source.canonicalize()?);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    SOURCE.lock().unwrap().is_some()
+}
+
+/// Keeps a piece of code registered with GDB until dropped.
+pub struct Registration {
+    entry: Box<JitCodeEntry>,
+    _symfile: Vec<u8>,
+}
+
+// Entries are only touched while holding `SOURCE`.
+unsafe impl Send for Registration {}
+
+fn notify(action: u32, entry: *mut JitCodeEntry) {
+    unsafe {
+        let descriptor = &raw mut __jit_debug_descriptor;
+        (*descriptor).action_flag = action;
+        (*descriptor).relevant_entry = entry;
+        __jit_debug_register_code();
+        (*descriptor).action_flag = JIT_NOACTION;
+    }
+}
+
+impl Registration {
+    fn new(symfile: Vec<u8>) -> Registration {
+        let _lock = SOURCE.lock().unwrap();
+        let mut entry = Box::new(JitCodeEntry {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        });
+        unsafe {
+            let descriptor = &raw mut __jit_debug_descriptor;
+            entry.next = (*descriptor).first_entry;
+            if let Some(next) = entry.next.as_mut() {
+                next.prev = &mut *entry;
+            }
+            (*descriptor).first_entry = &mut *entry;
+        }
+        notify(JIT_REGISTER_FN, &mut *entry);
+        Registration {
+            entry,
+            _symfile: symfile,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _lock = SOURCE.lock().unwrap();
+        let entry = &mut *self.entry;
+        unsafe {
+            let descriptor = &raw mut __jit_debug_descriptor;
+            match entry.prev.as_mut() {
+                Some(prev) => prev.next = entry.next,
+                None => (*descriptor).first_entry = entry.next,
+            }
+            if let Some(next) = entry.next.as_mut() {
+                next.prev = entry.prev;
+            }
+        }
+        notify(JIT_UNREGISTER_FN, entry);
+    }
+}
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn cstr(out: &mut Vec<u8>, s: &OsStr) {
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+}
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// A DWARF 4 `.debug_line` with one sequence over `rows` of
+/// `(code offset, line, column)`. Line 0 marks code without a source line.
+fn debug_line(source: &Path, addr: u64, len: usize, rows: &[(usize, usize, usize)]) -> Vec<u8> {
+    let mut header = vec![
+        1,          // minimum_instruction_length
+        1,          // maximum_operations_per_instruction
+        1,          // default_is_stmt
+        -5i8 as u8, // line_base
+        14,         // line_range
+        13,         // opcode_base
+    ];
+    header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    header.push(0); // no include directories
+    cstr(&mut header, source.as_os_str());
+    header.extend_from_slice(&[0, 0, 0]); // directory, mtime, length
+    header.push(0);
+
+    let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+    program.extend_from_slice(&addr.to_le_bytes());
+    let (mut address, mut line) = (0, 1);
+    for &(offset, row_line, column) in rows {
+        if offset > address {
+            program.push(DW_LNS_ADVANCE_PC);
+            uleb128(&mut program, (offset - address) as u64);
+            address = offset;
+        }
+        if row_line != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb128(&mut program, row_line as i64 - line as i64);
+            line = row_line;
+        }
+        program.push(DW_LNS_SET_COLUMN);
+        uleb128(&mut program, column as u64);
+        program.push(DW_LNS_COPY);
+    }
+    program.push(DW_LNS_ADVANCE_PC);
+    uleb128(&mut program, (len - address) as u64);
+    program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+
+    let mut out = vec![];
+    let unit_length = 2 + 4 + header.len() + program.len();
+    out.extend_from_slice(&(unit_length as u32).to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());
+    out.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&program);
+    out
+}
+
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_TAG_SUBPROGRAM: u8 = 0x2e;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA8: u8 = 0x07;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+/// There is no language code for bf; this makes GDB treat it as assembly.
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+/// `.debug_abbrev` and `.debug_info` for a compile unit named after `source`
+/// containing one function, `name`, that spans all of the code.
+fn debug_info(source: &Path, name: &str, addr: u64, len: usize) -> (Vec<u8>, Vec<u8>) {
+    let abbrev = vec![
+        1,
+        DW_TAG_COMPILE_UNIT,
+        1, // DW_CHILDREN_yes
+        DW_AT_PRODUCER,
+        DW_FORM_STRING,
+        DW_AT_LANGUAGE,
+        DW_FORM_DATA2,
+        DW_AT_NAME,
+        DW_FORM_STRING,
+        DW_AT_COMP_DIR,
+        DW_FORM_STRING,
+        DW_AT_LOW_PC,
+        DW_FORM_ADDR,
+        DW_AT_HIGH_PC,
+        DW_FORM_DATA8,
+        DW_AT_STMT_LIST,
+        DW_FORM_SEC_OFFSET,
+        0,
+        0,
+        2,
+        DW_TAG_SUBPROGRAM,
+        0, // DW_CHILDREN_no
+        DW_AT_NAME,
+        DW_FORM_STRING,
+        DW_AT_LOW_PC,
+        DW_FORM_ADDR,
+        DW_AT_HIGH_PC,
+        DW_FORM_DATA8,
+        0,
+        0,
+        0,
+    ];
+
+    let mut dies = vec![1];
+    cstr(
+        &mut dies,
+        OsStr::new(concat!("bf-jit-rs ", env!("CARGO_PKG_VERSION"))),
+    );
+    dies.extend_from_slice(&DW_LANG_MIPS_ASSEMBLER.to_le_bytes());
+    cstr(&mut dies, source.as_os_str());
+    cstr(
+        &mut dies,
+        source.parent().unwrap_or(Path::new("/")).as_os_str(),
+    );
+    dies.extend_from_slice(&addr.to_le_bytes());
+    dies.extend_from_slice(&(len as u64).to_le_bytes());
+    dies.extend_from_slice(&0u32.to_le_bytes());
+    dies.push(2);
+    cstr(&mut dies, OsStr::new(name));
+    dies.extend_from_slice(&addr.to_le_bytes());
+    dies.extend_from_slice(&(len as u64).to_le_bytes());
+    dies.push(0);
+
+    let mut info = vec![];
+    let unit_length = 2 + 4 + 1 + dies.len();
+    info.extend_from_slice(&(unit_length as u32).to_le_bytes());
+    info.extend_from_slice(&4u16.to_le_bytes());
+    info.extend_from_slice(&0u32.to_le_bytes());
+    info.push(8);
+    info.extend_from_slice(&dies);
+    (abbrev, info)
+}
+
+/// The in-memory ELF file describing `code`, loaded at `addr` and compiled
+/// from ops with source `spans` in `prog`, which was read from `source`.
+pub(super) fn symfile(
+    source: &Path,
+    prog: &[u8],
+    spans: &[Range<usize>],
+    map: &CodeMap,
+    code: &[u8],
+    addr: u64,
+    root: &str,
+) -> Vec<u8> {
+    let mut rows: Vec<(usize, usize, usize)> = map
+        .op_starts
+        .iter()
+        .zip(spans)
+        .map(|(&offset, span)| {
+            let (line, col) = line_col(prog, span.start);
+            (offset, line, col)
+        })
+        .collect();
+    if let Some(&(exit, _)) = map.labels.iter().find(|(_, l)| *l == Label::Exit) {
+        rows.push((exit, 0, 0));
+    }
+
+    // Loops are local symbols spanning from their head to their exit, which
+    // ELF wants before the global unit symbol.
+    let mut heads = vec![];
+    let mut loops = vec![];
+    for &(offset, label) in &map.labels {
+        match label {
+            Label::LoopHead(_) => heads.push((offset, label)),
+            Label::LoopExit(_) => {
+                if let Some((start, head)) = heads.pop() {
+                    loops.push((label_name(prog, spans, head), start, offset));
+                }
+            }
+            _ => (),
+        }
+    }
+    let mut symbols: Vec<Symbol> = loops
+        .iter()
+        .map(|(name, start, end)| Symbol {
+            name,
+            section: 1,
+            value: addr + *start as u64,
+            size: (end - start) as u64,
+            global: false,
+        })
+        .collect();
+    symbols.push(Symbol {
+        name: root,
+        section: 1,
+        value: addr,
+        size: code.len() as u64,
+        global: true,
+    });
+
+    let (abbrev, info) = debug_info(source, root, addr, code.len());
+    let debug = vec![
+        Section::new(".debug_abbrev", SHT_PROGBITS, 0, abbrev),
+        Section::new(".debug_info", SHT_PROGBITS, 0, info),
+        Section::new(
+            ".debug_line",
+            SHT_PROGBITS,
+            0,
+            debug_line(source, addr, code.len(), &rows),
+        ),
+    ];
+    elf::debug_object(code, addr, &symbols, debug)
+}
+
+/// Register `code` with GDB if `--debug-info` is enabled.
+pub(super) fn register(
+    prog: &[u8],
+    spans: &[Range<usize>],
+    map: &CodeMap,
+    code: &[u8],
+    root: &str,
+) -> Option<Registration> {
+    let source = SOURCE.lock().unwrap().clone()?;
+    let addr = code.as_ptr() as u64;
+    let symfile = symfile(&source, prog, spans, map, code, addr, root);
+    Some(Registration::new(symfile))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::jit::{ir, x64_jit};
+    use crate::tests::test_helper::{has_tool, temp_dir};
+
+    #[test]
+    fn leb128() {
+        let mut out = vec![];
+        uleb128(&mut out, 624_485);
+        sleb128(&mut out, -123_456);
+        sleb128(&mut out, 63);
+        sleb128(&mut out, 64);
+        assert_eq!(out, [0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78, 0x3f, 0xc0, 0x00]);
+    }
+
+    #[test]
+    fn entries_are_linked_and_unlinked() {
+        let first = Registration::new(vec![1]);
+        let second = Registration::new(vec![2]);
+        let head = || unsafe { (&raw const __jit_debug_descriptor).read().first_entry };
+        let ptr = |r: &Registration| &*r.entry as *const JitCodeEntry as *mut JitCodeEntry;
+        assert_eq!(head(), ptr(&second));
+        assert_eq!(second.entry.next, ptr(&first));
+        drop(second);
+        assert_eq!(head(), ptr(&first));
+        assert!(first.entry.prev.is_null());
+        drop(first);
+    }
+
+    #[test]
+    fn line_table_maps_ops_to_source_lines() {
+        if !has_tool("readelf", "--version") {
+            return;
+        }
+        let prog = b"++\n[>+<-]\n.";
+        let (ops, spans) = ir::parse_spanned(prog).unwrap();
+        let compiled = x64_jit::compile(&ops, false).unwrap();
+        let code = compiled.code();
+        let source = Path::new("/tmp/prog.bf");
+        let obj = symfile(source, prog, &spans, &compiled.map, code, 0x1000, "bf_main");
+
+        let dir = temp_dir("bf-gdb");
+        let path = dir.join("prog.o");
+        std::fs::write(&path, &obj).unwrap();
+        let out = Command::new("readelf")
+            .arg("--debug-dump=decodedline,info")
+            .arg("--syms")
+            .arg(&path)
+            .output();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let out = out.unwrap();
+        let text = String::from_utf8_lossy(&out.stdout);
+        assert!(out.status.success(), "{text}");
+        assert!(
+            out.stderr.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert!(text.contains("prog.bf"), "{text}");
+        for line in ["1", "2", "3"] {
+            assert!(
+                text.lines()
+                    .any(|l| l.starts_with("/tmp/prog.bf")
+                        && l.split_whitespace().nth(1) == Some(line)),
+                "{text}"
+            );
+        }
+        assert!(text.contains("DW_TAG_subprogram"), "{text}");
+        assert!(text.contains("loop_2_1"), "{text}");
+        assert!(text.contains("bf_main"), "{text}");
+    }
+}
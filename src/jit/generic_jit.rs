@@ -1,21 +1,21 @@
 use std::error;
 
+use crate::cancel::CancelToken;
+use crate::jit::code_cache::CodeCache;
+use crate::jit::profile::Profile;
+
+use crate::jit::cranelift;
 #[cfg(target_arch = "x86_64")]
-use crate::jit::x64_jit;
+use crate::jit::{tiered, x64_disasm, x64_gas, x64_jit};
 
 #[cfg(target_arch = "aarch64")]
 use crate::jit::aarch64_jit;
 
-/// Run a bf program
-///
-/// # Examples
+/// Run a bf program with the JIT backend for this host.
 ///
-/// ```
-/// let prog = fs::read(env::args().nth(1).unwrap())?;
-/// inter_1::run(prog)?;
-/// ```
-///
-/// This is a naive implementation, which we will optimize further in other implementations.
+/// x86-64 and AArch64 hosts get their dynasm backend without bounds checks,
+/// where x86-64 catches stray tape accesses with guard pages. Every other
+/// host gets the Cranelift backend, which always checks bounds.
 #[allow(unreachable_code)]
 pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
     // run
@@ -27,8 +27,121 @@ pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
     {
         return aarch64_jit::run(prog);
     }
-    println!("Architecture not supported!");
-    Ok(())
+    cranelift::run(prog)
+}
+
+/// Run a bf program with explicit tape bounds checks in the generated code.
+///
+/// Out of bounds accesses are reported as an error instead of faulting. The
+/// Cranelift backend, used on other hosts, always checks bounds.
+#[allow(unreachable_code)]
+pub fn run_bounds_checked(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        return x64_jit::run_bounds_checked(prog);
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        return aarch64_jit::run_bounds_checked(prog);
+    }
+    cranelift::run(prog)
+}
+
+/// Run a bf program, reusing compiled code from `cache` when the backend
+/// supports it.
+#[allow(unreachable_code, unused_variables)]
+pub fn run_with(
+    prog: &[u8],
+    bounds_checked: bool,
+    cache: Option<&CodeCache>,
+) -> Result<(), Box<dyn error::Error>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        return x64_jit::run_with(prog, bounds_checked, cache);
+    }
+    if bounds_checked {
+        run_bounds_checked(prog)
+    } else {
+        run(prog)
+    }
+}
+
+/// Run a bf program until it finishes or `cancel` is set, in which case it
+/// fails with `Cancelled`.
+///
+/// Without a backend that supports it, the program is interpreted.
+#[allow(unreachable_code, unused_variables)]
+pub fn run_cancellable(
+    prog: &[u8],
+    bounds_checked: bool,
+    cancel: &CancelToken,
+) -> Result<(), Box<dyn error::Error>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        return x64_jit::run_cancellable(prog, bounds_checked, cancel);
+    }
+    crate::interpreter::interp::run_cancellable(prog, None, cancel)
+}
+
+/// Run a bf program, filling `profile` with how often every loop ran, even
+/// when the program fails.
+#[allow(unreachable_code, unused_variables)]
+pub fn run_profiled(
+    prog: &[u8],
+    bounds_checked: bool,
+    cache: Option<&CodeCache>,
+    profile: &mut Profile,
+) -> Result<(), Box<dyn error::Error>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        return x64_jit::run_profiled(prog, bounds_checked, cache, profile);
+    }
+    Err("Profiling is not supported on this architecture".into())
+}
+
+/// Disassemble the code the JIT generates for a bf program, annotated with
+/// the source of every op.
+#[allow(unreachable_code, unused_variables)]
+pub fn disassemble(prog: &[u8], bounds_checked: bool) -> Result<String, Box<dyn error::Error>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        return x64_disasm::disassemble(prog, bounds_checked);
+    }
+    Err("Disassembly is not supported on this architecture".into())
+}
+
+/// Assembler syntax of `emit_gas` output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GasSyntax {
+    Att,
+    Intel,
+}
+
+/// Compile a bf program into the assembly source of a C `main` function,
+/// with the instruction selection of the JIT and I/O through libc.
+#[allow(unreachable_code, unused_variables)]
+pub fn emit_gas(
+    prog: &[u8],
+    bounds_checked: bool,
+    syntax: GasSyntax,
+) -> Result<String, Box<dyn error::Error>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        return x64_gas::emit(prog, bounds_checked, syntax);
+    }
+    Err("Assembly output is not supported on this architecture".into())
+}
+
+/// Run a bf program in the interpreter, compiling only its hot loops.
+///
+/// Without a backend that supports it, the whole program is interpreted.
+#[allow(unreachable_code)]
+pub fn run_tiered(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        return tiered::run(prog);
+    }
+    crate::interpreter::interp::run(prog, None)
 }
 
 #[cfg(test)]
@@ -41,4 +154,9 @@ mod tests {
     fn it_works() {
         assert!(test_run(&run).is_ok());
     }
+
+    #[test]
+    fn bounds_checked_works() {
+        assert!(test_run(&run_bounds_checked).is_ok());
+    }
 }
@@ -0,0 +1,261 @@
+use std::cell::Cell;
+use std::error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::Once;
+
+/// Returned when JIT code touches a cell outside of the tape.
+///
+/// `pointer` is the faulting cell index relative to the start of the tape, so
+/// it is negative when the program walked off the left edge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryError {
+    pub pointer: isize,
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Memory Error: cell {} is outside of the tape",
+            self.pointer
+        )
+    }
+}
+
+impl error::Error for MemoryError {}
+
+/// A tape allocated with `mmap` and surrounded by `PROT_NONE` guard regions.
+///
+/// The usable tape is rounded up to whole pages, so running off the right end
+/// is only caught once the pointer leaves the last page.
+///
+/// Any access that lands in a guard region raises SIGSEGV, which `guard` turns
+/// into a jump to the recovery stub of the running JIT code.
+pub struct GuardedTape {
+    base: *mut u8,
+    map_len: usize,
+    guard_len: usize,
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+impl GuardedTape {
+    /// Map `tape_len` zeroed cells with guard regions larger than `max_move`
+    /// on both sides.
+    pub fn new(tape_len: usize, max_move: usize) -> io::Result<GuardedTape> {
+        let page = page_size();
+        let guard_len = round_up(max_move + 1, page);
+        let map_len = guard_len + round_up(tape_len, page) + guard_len;
+
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let base = base as *mut u8;
+
+        let res = unsafe {
+            libc::mprotect(
+                base.add(guard_len) as *mut libc::c_void,
+                map_len - 2 * guard_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+            )
+        };
+        if res != 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::munmap(base as *mut libc::c_void, map_len) };
+            return Err(err);
+        }
+
+        Ok(GuardedTape {
+            base,
+            map_len,
+            guard_len,
+        })
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        unsafe { self.base.add(self.guard_len) }
+    }
+
+    /// The whole usable tape, including the rounding up to a page.
+    pub fn cells_mut(&mut self) -> &mut [u8] {
+        let len = self.map_len - 2 * self.guard_len;
+        unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), len) }
+    }
+
+    fn contains_guard(&self, addr: usize) -> bool {
+        let start = self.base as usize;
+        let lower = start..start + self.guard_len;
+        let upper = start + self.map_len - self.guard_len..start + self.map_len;
+        lower.contains(&addr) || upper.contains(&addr)
+    }
+}
+
+impl Drop for GuardedTape {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.map_len) };
+    }
+}
+
+/// What the signal handler needs to know about the JIT code running on this
+/// thread.
+#[derive(Clone, Copy)]
+struct ActiveRegion {
+    guard_start: usize,
+    guard_end: usize,
+    tape_start: usize,
+    code_start: usize,
+    code_end: usize,
+    recovery: usize,
+}
+
+thread_local! {
+    static ACTIVE: Cell<Option<ActiveRegion>> = const { Cell::new(None) };
+    static FAULT_ADDR: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+static INSTALL: Once = Once::new();
+static mut PREVIOUS_ACTION: mem::MaybeUninit<libc::sigaction> = mem::MaybeUninit::uninit();
+
+unsafe extern "C" fn handle_segv(
+    sig: libc::c_int,
+    info: *mut libc::siginfo_t,
+    ctx: *mut libc::c_void,
+) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        let uc = &mut *(ctx as *mut libc::ucontext_t);
+        let rip = &mut uc.uc_mcontext.gregs[libc::REG_RIP as usize];
+
+        if let Some(region) = ACTIVE.with(|a| a.get())
+            && (region.guard_start..region.guard_end).contains(&addr)
+            && (region.code_start..region.code_end).contains(&(*rip as usize))
+        {
+            FAULT_ADDR.with(|f| f.set(Some(addr.wrapping_sub(region.tape_start))));
+            *rip = region.recovery as i64;
+            return;
+        }
+
+        // Not ours: hand the fault to whoever was installed before us.
+        let previous = &*(&raw const PREVIOUS_ACTION).cast::<libc::sigaction>();
+        match previous.sa_sigaction {
+            libc::SIG_DFL | libc::SIG_IGN => {
+                libc::sigaction(sig, previous, ptr::null_mut());
+            }
+            handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                let handler: unsafe extern "C" fn(
+                    libc::c_int,
+                    *mut libc::siginfo_t,
+                    *mut libc::c_void,
+                ) = mem::transmute(handler);
+                handler(sig, info, ctx);
+            }
+            handler => {
+                let handler: unsafe extern "C" fn(libc::c_int) = mem::transmute(handler);
+                handler(sig);
+            }
+        }
+    }
+}
+
+fn install_handler() {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_segv as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(
+            libc::SIGSEGV,
+            &action,
+            (&raw mut PREVIOUS_ACTION).cast::<libc::sigaction>(),
+        );
+    });
+}
+
+/// Run `f` with faults in `tape`'s guard regions redirected to `recovery`.
+///
+/// Only faults raised by instructions inside `code` are recovered; anything
+/// else is forwarded to the previously installed SIGSEGV handler. Returns the
+/// result of `f` and, if a fault was recovered, the offending cell index.
+pub fn guard<R>(
+    tape: &mut GuardedTape,
+    code: (*const u8, usize),
+    recovery: *const u8,
+    f: impl FnOnce(*mut u8) -> R,
+) -> (R, Option<MemoryError>) {
+    install_handler();
+
+    let tape_start = tape.as_mut_ptr();
+    let region = ActiveRegion {
+        guard_start: tape.base as usize,
+        guard_end: tape.base as usize + tape.map_len,
+        tape_start: tape_start as usize,
+        code_start: code.0 as usize,
+        code_end: code.0 as usize + code.1,
+        recovery: recovery as usize,
+    };
+    debug_assert!(!tape.contains_guard(region.tape_start));
+
+    FAULT_ADDR.with(|f| f.set(None));
+    let previous = ACTIVE.with(|a| a.replace(Some(region)));
+    let res = f(tape_start);
+    ACTIVE.with(|a| a.set(previous));
+
+    let fault = FAULT_ADDR.with(|f| f.take()).map(|offset| MemoryError {
+        pointer: offset as isize,
+    });
+    (res, fault)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tape_is_zeroed_and_writable() {
+        let mut tape = GuardedTape::new(30_000, 1).unwrap();
+        let cells = unsafe { std::slice::from_raw_parts_mut(tape.as_mut_ptr(), 30_000) };
+        assert!(cells.iter().all(|&c| c == 0));
+        cells[0] = 1;
+        cells[29_999] = 1;
+    }
+
+    #[test]
+    fn tape_is_rounded_up_to_a_page() {
+        let page = page_size();
+        let mut tape = GuardedTape::new(30_000, 1).unwrap();
+        let start = tape.as_mut_ptr() as usize;
+        let usable = tape.cells_mut().len();
+        assert_eq!(usable, round_up(30_000, page));
+        tape.cells_mut()[usable - 1] = 1;
+        assert!(!tape.contains_guard(start + usable - 1));
+        assert!(tape.contains_guard(start + usable));
+    }
+
+    #[test]
+    fn guard_covers_max_move() {
+        let mut tape = GuardedTape::new(100, 10_000).unwrap();
+        let start = tape.as_mut_ptr() as usize;
+        assert!(tape.contains_guard(start - 10_000));
+        assert!(tape.contains_guard(start + 100 + 10_000));
+        assert!(!tape.contains_guard(start + 99));
+    }
+}
@@ -0,0 +1,145 @@
+//! The op IR the JIT backends lower from.
+//!
+//! Runs of `+`, `-`, `<` and `>` are folded into a single op and `[-]` becomes
+//! `Zero`. Brackets are not matched here; each backend reports mismatches
+//! while lowering.
+
+use std::ops::Range;
+
+use crate::jit::bounds_check::Access;
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Ops {
+    Left(usize),
+    Right(usize),
+    Add(u8),
+    Sub(u8),
+    Zero,
+    LBrack,
+    RBrack,
+    Output,
+    Input,
+}
+
+type OpSequence = Vec<Ops>;
+
+impl Ops {
+    /// How the op touches the tape, for bounds check placement.
+    pub(super) fn access(&self) -> Access {
+        match *self {
+            Ops::Left(amount) => Access::Move(-(amount as isize)),
+            Ops::Right(amount) => Access::Move(amount as isize),
+            Ops::LBrack => Access::LoopStart,
+            Ops::RBrack => Access::LoopEnd,
+            Ops::Add(_) | Ops::Sub(_) | Ops::Zero | Ops::Output | Ops::Input => Access::Touch,
+        }
+    }
+}
+
+/// 1-based line and column of byte `offset` in `prog`.
+pub(super) fn line_col(prog: &[u8], offset: usize) -> (usize, usize) {
+    let before = &prog[..offset];
+    let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+    let col = offset
+        - before
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |nl| nl + 1)
+        + 1;
+    (line, col)
+}
+
+pub(super) fn parse(prog_src: &[u8]) -> Result<OpSequence, &'static str> {
+    Ok(parse_spanned(prog_src)?.0)
+}
+
+/// Parse `prog_src`, also returning the source bytes each op came from.
+pub(super) fn parse_spanned(
+    prog_src: &[u8],
+) -> Result<(OpSequence, Vec<Range<usize>>), &'static str> {
+    let mut prog_ops = vec![];
+    let mut spans = vec![];
+    let mut i = 0;
+    while i < prog_src.len() {
+        let start = i;
+        match prog_src[i] as char {
+            '<' => {
+                let mut count = 1;
+                let mut j = i + 1;
+                while j < prog_src.len() && prog_src[j] as char == '<' {
+                    count += 1;
+                    j += 1;
+                }
+                prog_ops.push(Ops::Left(count));
+                i = j - 1;
+            }
+            '>' => {
+                let mut count = 1;
+                let mut j = i + 1;
+                while j < prog_src.len() && prog_src[j] as char == '>' {
+                    count += 1;
+                    j += 1;
+                }
+                prog_ops.push(Ops::Right(count));
+                i = j - 1;
+            }
+            '-' => {
+                let mut count = 1;
+                let mut j = i + 1;
+                while j < prog_src.len() && prog_src[j] as char == '-' {
+                    count += 1;
+                    j += 1;
+                }
+                prog_ops.push(Ops::Sub(count));
+                i = j - 1;
+            }
+            '+' => {
+                let mut count = 1;
+                let mut j = i + 1;
+                while j < prog_src.len() && prog_src[j] as char == '+' {
+                    count += 1;
+                    j += 1;
+                }
+                prog_ops.push(Ops::Add(count));
+                i = j - 1;
+            }
+            '[' => {
+                // Check if it is [-]
+                if i + 2 < prog_src.len()
+                    && prog_src[i + 1] as char == '-'
+                    && prog_src[i + 2] as char == ']'
+                {
+                    prog_ops.push(Ops::Zero);
+                    spans.push(i..i + 3);
+                    i += 3;
+                    continue;
+                }
+                prog_ops.push(Ops::LBrack);
+            }
+            ']' => {
+                prog_ops.push(Ops::RBrack);
+            }
+            '.' => prog_ops.push(Ops::Output),
+            ',' => prog_ops.push(Ops::Input),
+            _ => (),
+        }
+        if spans.len() < prog_ops.len() {
+            spans.push(start..i + 1);
+        }
+        i += 1;
+    }
+    Ok((prog_ops, spans))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_one_based() {
+        let prog = b"+\n+[-]\n";
+        assert_eq!(line_col(prog, 0), (1, 1));
+        assert_eq!(line_col(prog, 2), (2, 1));
+        assert_eq!(line_col(prog, 3), (2, 2));
+    }
+}
@@ -1,11 +1,48 @@
 #![allow(clippy::fn_to_numeric_cast)]
 
+mod bounds_check;
+
+pub mod code_cache;
+
+mod ir;
+
+pub mod profile;
+
+mod cranelift;
+
+#[cfg(target_os = "linux")]
+pub mod perf;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod gdb;
+
+#[cfg(target_arch = "x86_64")]
+mod guarded_tape;
+
 #[cfg(target_arch = "x86_64")]
 mod x64_jit;
 
-#[cfg(target_arch = "aarch64")]
+#[cfg(target_arch = "x86_64")]
+mod x64_disasm;
+
+#[cfg(target_arch = "x86_64")]
+mod x64_gas;
+
+#[cfg(target_arch = "x86_64")]
+mod tiered;
+
 mod aarch64_jit;
 
+#[cfg(test)]
+mod aarch64_emu;
+
 mod generic_jit;
 
-pub use generic_jit::run;
+pub use cranelift::run as run_cranelift;
+pub use generic_jit::{
+    GasSyntax, disassemble, emit_gas, run, run_bounds_checked, run_cancellable, run_profiled,
+    run_tiered, run_with,
+};
+
+#[cfg(target_arch = "x86_64")]
+pub use x64_jit::{compile_object, compile_standalone};
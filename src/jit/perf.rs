@@ -0,0 +1,256 @@
+//! Symbols for `perf`, so profiles attribute time to bf loops instead of an
+//! anonymous memory region.
+//!
+//! Two formats are supported, both opt-in:
+//!
+//! - `/tmp/perf-<pid>.map`, one `start size name` line per symbol, which
+//!   `perf report` picks up on its own.
+//! - `/tmp/jit-<pid>.dump` in the jitdump format, which also records the code
+//!   itself. Record with `perf record -k mono` and run `perf inject --jit` on
+//!   the result before reporting.
+//!
+//! Compiled code is split into regions named after the innermost loop they
+//! belong to, e.g. `loop_3_7` for the loop opened on line 3, column 7. Code
+//! outside of any loop is named after the compiled unit (`bf_main` for a whole
+//! program).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::ptr;
+use std::sync::Mutex;
+
+#[cfg(target_arch = "x86_64")]
+use crate::jit::x64_disasm::label_name;
+#[cfg(target_arch = "x86_64")]
+use crate::jit::x64_jit::{CodeMap, Compiled, Label};
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JIT_CODE_LOAD: u32 = 0;
+const EM_X86_64: u32 = 62;
+
+struct JitDump {
+    file: File,
+    /// perf only finds the dump through an executable mapping of it.
+    marker: *mut libc::c_void,
+    code_index: u64,
+}
+
+// The marker mapping is never dereferenced, only kept alive.
+unsafe impl Send for JitDump {}
+
+struct Outputs {
+    map: Option<File>,
+    jitdump: Option<JitDump>,
+}
+
+static OUTPUTS: Mutex<Outputs> = Mutex::new(Outputs {
+    map: None,
+    jitdump: None,
+});
+
+fn timestamp() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+fn thread_id() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+impl JitDump {
+    fn create() -> io::Result<JitDump> {
+        let pid = std::process::id();
+        // Read access is needed to map the file.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(format!("/tmp/jit-{pid}.dump"))?;
+
+        let mut header = vec![];
+        for value in [JITDUMP_MAGIC, JITDUMP_VERSION, 40, EM_X86_64, 0, pid] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.extend_from_slice(&timestamp().to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        file.write_all(&header)?;
+
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        let marker = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                page,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if marker == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(JitDump {
+            file,
+            marker,
+            code_index: 0,
+        })
+    }
+
+    fn code_load(&mut self, addr: usize, code: &[u8], name: &str) -> io::Result<()> {
+        let size = 16 + 8 + 4 * 8 + name.len() + 1 + code.len();
+        let mut record = Vec::with_capacity(size);
+        record.extend_from_slice(&JIT_CODE_LOAD.to_le_bytes());
+        record.extend_from_slice(&(size as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp().to_le_bytes());
+        record.extend_from_slice(&std::process::id().to_le_bytes());
+        record.extend_from_slice(&thread_id().to_le_bytes());
+        for value in [addr as u64, addr as u64, code.len() as u64, self.code_index] {
+            record.extend_from_slice(&value.to_le_bytes());
+        }
+        record.extend_from_slice(name.as_bytes());
+        record.push(0);
+        record.extend_from_slice(code);
+        self.code_index += 1;
+        self.file.write_all(&record)
+    }
+}
+
+impl Drop for JitDump {
+    fn drop(&mut self) {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        unsafe { libc::munmap(self.marker, page) };
+    }
+}
+
+/// Start writing `/tmp/perf-<pid>.map` and/or `/tmp/jit-<pid>.dump` entries
+/// for code compiled from now on.
+pub fn enable(map: bool, jitdump: bool) -> io::Result<()> {
+    let mut outputs = OUTPUTS.lock().unwrap();
+    if map && outputs.map.is_none() {
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        outputs.map = Some(OpenOptions::new().create(true).append(true).open(path)?);
+    }
+    if jitdump && outputs.jitdump.is_none() {
+        outputs.jitdump = Some(JitDump::create()?);
+    }
+    Ok(())
+}
+
+/// Whether compiled code should be registered at all.
+pub fn enabled() -> bool {
+    let outputs = OUTPUTS.lock().unwrap();
+    outputs.map.is_some() || outputs.jitdump.is_some()
+}
+
+/// Record `symbols`, given as ranges of `code`, which is mapped at `addr`.
+pub fn register(addr: usize, code: &[u8], symbols: &[(Range<usize>, String)]) -> io::Result<()> {
+    let mut outputs = OUTPUTS.lock().unwrap();
+    if let Some(map) = &mut outputs.map {
+        let mut lines = String::new();
+        for (range, name) in symbols {
+            lines += &format!("{:x} {:x} {name}\n", addr + range.start, range.len());
+        }
+        map.write_all(lines.as_bytes())?;
+    }
+    if let Some(jitdump) = &mut outputs.jitdump {
+        for (range, name) in symbols {
+            jitdump.code_load(addr + range.start, &code[range.clone()], name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Split compiled code into regions named after the innermost enclosing loop.
+///
+/// `spans` are the source spans of the compiled ops and `root` names the code
+/// outside of any loop. Without a code map the whole code is one region.
+#[cfg(target_arch = "x86_64")]
+pub(super) fn regions(
+    prog: &[u8],
+    spans: &[Range<usize>],
+    map: &CodeMap,
+    len: usize,
+    root: &str,
+) -> Vec<(Range<usize>, String)> {
+    let mut regions = vec![];
+    let mut stack = vec![root.to_string()];
+    let mut start = 0;
+    for &(offset, label) in &map.labels {
+        let entered = match label {
+            Label::LoopHead(_) => Some(label_name(prog, spans, label)),
+            Label::LoopExit(_) => None,
+            _ => continue,
+        };
+        if offset > start {
+            push_region(&mut regions, start..offset, stack.last().unwrap());
+            start = offset;
+        }
+        match entered {
+            Some(name) => stack.push(name),
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    if len > start {
+        push_region(&mut regions, start..len, root);
+    }
+    regions
+}
+
+/// Append a region, merging it into the previous one if it has the same name.
+#[cfg(target_arch = "x86_64")]
+fn push_region(regions: &mut Vec<(Range<usize>, String)>, range: Range<usize>, name: &str) {
+    match regions.last_mut() {
+        Some((last, last_name)) if last_name == name && last.end == range.start => {
+            last.end = range.end
+        }
+        _ => regions.push((range, name.to_string())),
+    }
+}
+
+/// Register `compiled`, the code for the ops with source `spans`.
+#[cfg(target_arch = "x86_64")]
+pub(super) fn register_compiled(
+    prog: &[u8],
+    spans: &[Range<usize>],
+    compiled: &Compiled,
+    root: &str,
+) -> io::Result<()> {
+    let code = compiled.code();
+    let symbols = regions(prog, spans, &compiled.map, code.len(), root);
+    register(code.as_ptr() as usize, code, &symbols)
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::jit::{ir, x64_jit};
+
+    #[test]
+    fn nested_loops_get_their_own_regions() {
+        let prog = b"+[>+[>+<-]<-]";
+        let (ops, spans) = ir::parse_spanned(prog).unwrap();
+        let compiled = x64_jit::compile(&ops, false).unwrap();
+        let len = compiled.code().len();
+        let regions = regions(prog, &spans, &compiled.map, len, "bf_main");
+
+        let names: Vec<&str> = regions.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["bf_main", "loop_1_2", "loop_1_5", "loop_1_2", "bf_main"]
+        );
+        assert_eq!(regions[0].0.start, 0);
+        assert_eq!(regions[4].0.end, len);
+        assert!(regions.windows(2).all(|w| w[0].0.end == w[1].0.start));
+    }
+}
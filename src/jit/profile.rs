@@ -0,0 +1,181 @@
+//! Loop profiles of programs run with `--profile`.
+//!
+//! Profiled code keeps two counters per loop, in the order the loops open in
+//! the source: how often the loop was entered and how often its back-edge was
+//! taken. Every entry runs the body once, so the number of iterations is the
+//! sum of both.
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::jit::ir::{Ops, line_col};
+
+/// How one loop behaved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopStats {
+    /// 1-based position of the loop's `[`.
+    pub line: usize,
+    pub col: usize,
+    /// Times the loop was entered with a non-zero cell.
+    pub entries: u64,
+    /// Times the body ran.
+    pub iterations: u64,
+}
+
+impl LoopStats {
+    /// Iterations per entry.
+    pub fn average_trip_count(&self) -> f64 {
+        if self.entries == 0 {
+            0.0
+        } else {
+            self.iterations as f64 / self.entries as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// Every loop of the program, in source order.
+    pub loops: Vec<LoopStats>,
+}
+
+impl Profile {
+    /// Build a profile from the counters of profiled code compiled from
+    /// `ops`, whose source spans are `spans`.
+    pub(super) fn from_counters(
+        prog: &[u8],
+        ops: &[Ops],
+        spans: &[Range<usize>],
+        counters: &[u64],
+    ) -> Profile {
+        let opens = ops
+            .iter()
+            .zip(spans)
+            .filter(|(op, _)| matches!(op, Ops::LBrack));
+        let loops = opens
+            .zip(counters.chunks(2))
+            .map(|((_, span), counts)| {
+                let (line, col) = line_col(prog, span.start);
+                LoopStats {
+                    line,
+                    col,
+                    entries: counts[0],
+                    iterations: counts[0] + counts[1],
+                }
+            })
+            .collect();
+        Profile { loops }
+    }
+
+    /// Loops by iterations, most first; ties stay in source order.
+    pub fn hottest(&self) -> Vec<&LoopStats> {
+        let mut loops: Vec<&LoopStats> = self.loops.iter().collect();
+        loops.sort_by_key(|stats| Reverse(stats.iterations));
+        loops
+    }
+
+    /// A table of the `top` hottest loops.
+    pub fn report(&self, top: usize) -> String {
+        let hottest = self.hottest();
+        let shown = &hottest[..top.min(hottest.len())];
+        let mut out = format!("Hottest loops ({} of {}):\n", shown.len(), hottest.len());
+        let _ = writeln!(
+            out,
+            "{:>12} {:>16} {:>12} {:>12}",
+            "line:col", "iterations", "entries", "avg trips"
+        );
+        for stats in shown {
+            let _ = writeln!(
+                out,
+                "{:>12} {:>16} {:>12} {:>12.1}",
+                format!("{}:{}", stats.line, stats.col),
+                stats.iterations,
+                stats.entries,
+                stats.average_trip_count()
+            );
+        }
+        out
+    }
+
+    /// Every loop as JSON, hottest first.
+    pub fn to_json(&self) -> String {
+        let loops: Vec<String> = self
+            .hottest()
+            .iter()
+            .map(|stats| {
+                format!(
+                    "{{\"line\":{},\"column\":{},\"iterations\":{},\"entries\":{},\"average_trip_count\":{}}}",
+                    stats.line,
+                    stats.col,
+                    stats.iterations,
+                    stats.entries,
+                    stats.average_trip_count()
+                )
+            })
+            .collect();
+        format!("{{\"loops\":[{}]}}\n", loops.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jit::ir;
+
+    #[test]
+    fn counters_map_to_loops() {
+        let prog = b"++[>+++\n[>+<-]<-]";
+        let (ops, spans) = ir::parse_spanned(prog).unwrap();
+        let profile = Profile::from_counters(prog, &ops, &spans, &[1, 1, 2, 4]);
+        assert_eq!(
+            profile.loops,
+            [
+                LoopStats {
+                    line: 1,
+                    col: 3,
+                    entries: 1,
+                    iterations: 2
+                },
+                LoopStats {
+                    line: 2,
+                    col: 1,
+                    entries: 2,
+                    iterations: 6
+                },
+            ]
+        );
+        assert_eq!(profile.loops[1].average_trip_count(), 3.0);
+    }
+
+    #[test]
+    fn reports_hottest_first() {
+        let profile = Profile {
+            loops: vec![
+                LoopStats {
+                    line: 1,
+                    col: 1,
+                    entries: 0,
+                    iterations: 0,
+                },
+                LoopStats {
+                    line: 2,
+                    col: 5,
+                    entries: 4,
+                    iterations: 10,
+                },
+            ],
+        };
+        let report = profile.report(1);
+        assert!(report.starts_with("Hottest loops (1 of 2):\n"));
+        assert!(report.contains("2:5"));
+        assert!(!report.contains("1:1 "));
+        assert_eq!(
+            profile.to_json(),
+            "{\"loops\":[\
+             {\"line\":2,\"column\":5,\"iterations\":10,\"entries\":4,\"average_trip_count\":2.5},\
+             {\"line\":1,\"column\":1,\"iterations\":0,\"entries\":0,\"average_trip_count\":0}\
+             ]}\n"
+        );
+    }
+}
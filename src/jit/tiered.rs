@@ -0,0 +1,185 @@
+//! Tiered execution: start in an interpreter and only JIT the hot loops.
+//!
+//! Compiling a whole program up front costs more than interpreting short
+//! scripts. Here the program starts out interpreted, and every back-edge bumps
+//! a counter for its loop. Once a loop passes `HOT_LOOP_THRESHOLD` it is
+//! compiled on its own through `x64_jit` and entered right away at its header
+//! with the live tape pointer; later entries into the loop go straight to the
+//! compiled code. Everything else stays interpreted.
+
+use std::collections::HashMap;
+use std::error;
+use std::io::{BufReader, BufWriter, Write, stdin, stdout};
+use std::ops::Range;
+
+use crate::bf_types::BF_MEMORY_SIZE;
+use crate::jit::guarded_tape::{GuardedTape, MemoryError};
+use crate::jit::ir::{self, Ops};
+use crate::jit::x64_disasm::label_name;
+use crate::jit::x64_jit::{self, Compiled, Label, State};
+
+/// Back-edges a loop has to take before it gets compiled.
+const HOT_LOOP_THRESHOLD: u32 = 1_000;
+
+struct Engine<'p> {
+    prog: &'p [u8],
+    ops: &'p [Ops],
+    /// Source span of every op.
+    spans: &'p [Range<usize>],
+    /// Matching bracket for every `LBrack`/`RBrack`.
+    jumps: Vec<usize>,
+    back_edges: Vec<u32>,
+    /// Compiled loops, keyed by the index of their `LBrack`.
+    compiled: HashMap<usize, Compiled>,
+}
+
+fn cell(tape: &mut GuardedTape, cc: isize) -> Result<&mut u8, MemoryError> {
+    usize::try_from(cc)
+        .ok()
+        .and_then(|i| tape.cells_mut().get_mut(i))
+        .ok_or(MemoryError { pointer: cc })
+}
+
+impl<'p> Engine<'p> {
+    fn new(
+        prog: &'p [u8],
+        ops: &'p [Ops],
+        spans: &'p [Range<usize>],
+    ) -> Result<Engine<'p>, &'static str> {
+        let mut jumps = vec![usize::MAX; ops.len()];
+        let mut bracket_index_stack = vec![];
+        for (i, op) in ops.iter().enumerate() {
+            match op {
+                Ops::LBrack => bracket_index_stack.push(i),
+                Ops::RBrack => {
+                    let open = bracket_index_stack.pop().ok_or("] without matching [")?;
+                    jumps[open] = i;
+                    jumps[i] = open;
+                }
+                _ => (),
+            }
+        }
+        if !bracket_index_stack.is_empty() {
+            return Err("[ without matching ]");
+        }
+
+        Ok(Engine {
+            prog,
+            ops,
+            spans,
+            jumps,
+            back_edges: vec![0; ops.len()],
+            compiled: HashMap::new(),
+        })
+    }
+
+    fn run(
+        &mut self,
+        state: &mut State,
+        tape: &mut GuardedTape,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let mut cc = 0isize;
+        let mut pc = 0;
+        while pc < self.ops.len() {
+            match self.ops[pc] {
+                Ops::Left(v) => cc -= v as isize,
+                Ops::Right(v) => cc += v as isize,
+                Ops::Add(v) => {
+                    let c = cell(tape, cc)?;
+                    *c = c.wrapping_add(v);
+                }
+                Ops::Sub(v) => {
+                    let c = cell(tape, cc)?;
+                    *c = c.wrapping_sub(v);
+                }
+                Ops::Zero => *cell(tape, cc)? = 0,
+                Ops::LBrack => {
+                    if *cell(tape, cc)? == 0 {
+                        pc = self.jumps[pc];
+                    } else if let Some(code) = self.compiled.get(&pc) {
+                        cc = code.call(state, tape, cc as usize)? as isize;
+                        pc = self.jumps[pc];
+                    }
+                }
+                Ops::RBrack => {
+                    if *cell(tape, cc)? != 0 {
+                        let open = self.jumps[pc];
+                        self.back_edges[open] += 1;
+                        if self.back_edges[open] < HOT_LOOP_THRESHOLD {
+                            pc = open;
+                        } else {
+                            // On-stack replacement: the interpreter is at the
+                            // loop header, so the rest of this loop runs in
+                            // compiled code and we resume after it.
+                            let mut code = x64_jit::compile(&self.ops[open..=pc], false)?;
+                            let spans = &self.spans[open..=pc];
+                            let name = label_name(self.prog, spans, Label::LoopHead(0));
+                            code.register(self.prog, spans, &name)?;
+                            cc = code.call(state, tape, cc as usize)? as isize;
+                            self.compiled.insert(open, code);
+                        }
+                    }
+                }
+                Ops::Output => {
+                    let c = *cell(tape, cc)?;
+                    state.output.write_all(&[c]).map_err(|_| "IO error")?;
+                }
+                Ops::Input => {
+                    let c = cell(tape, cc)?;
+                    // Input the compiled loops prefetched comes first.
+                    *c = state.read_byte().map_err(|_| "IO error")?;
+                }
+            }
+            pc += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Run a bf program, interpreting cold code and compiling hot loops.
+pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    let (ops, spans) = ir::parse_spanned(prog)?;
+    let mut engine = Engine::new(prog, &ops, &spans)?;
+    let mut state = State::new(
+        Box::new(BufReader::new(stdin())),
+        Box::new(BufWriter::new(stdout())),
+    );
+    let mut tape = GuardedTape::new(BF_MEMORY_SIZE, x64_jit::max_unchecked_move(&ops))?;
+
+    let res = engine.run(&mut state, &mut tape);
+    state.output.flush()?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_helper::{test_hell, test_run};
+
+    use super::*;
+
+    #[test]
+    fn hello() {
+        assert!(test_run(&run).is_ok());
+    }
+    #[test]
+    fn hello_hell() {
+        assert!(test_hell(&run).is_ok());
+    }
+    #[test]
+    fn only_hot_loops_are_compiled() {
+        // The outer loop runs 10 times, the inner one 255 times per entry.
+        let prog = b"++++++++++[>-[>+<-]<-]>>.";
+        let (ops, spans) = ir::parse_spanned(prog).unwrap();
+        let mut engine = Engine::new(prog, &ops, &spans).unwrap();
+        let mut output = vec![];
+        let mut state = State::new(Box::new(&b""[..]), Box::new(&mut output));
+        let mut tape = GuardedTape::new(BF_MEMORY_SIZE, 1).unwrap();
+
+        engine.run(&mut state, &mut tape).unwrap();
+        drop(state);
+
+        assert_eq!(engine.compiled.len(), 1);
+        assert!(engine.compiled.contains_key(&4));
+        assert_eq!(output, [(10 * 255 % 256) as u8]);
+    }
+}
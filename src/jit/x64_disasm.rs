@@ -0,0 +1,143 @@
+//! Disassembly of the code the x64 JIT generates, for `--emit asm`.
+//!
+//! Every run of instructions is preceded by the op it was generated for and
+//! the source span that op was parsed from. Loops are labelled after the
+//! position of their `[`, so `loop_3_7` is the head of the loop opened on
+//! line 3, column 7 and `loop_3_7_end` is where it exits to.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt::Write;
+
+use iced_x86::{
+    Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter, OpKind, SymbolResolver,
+    SymbolResult,
+};
+
+use crate::jit::code_cache::Helper;
+use crate::jit::ir::{self, line_col};
+use crate::jit::x64_jit::{self, Label};
+
+/// Symbol name of `label`; `spans` are the source spans of the ops.
+pub(super) fn label_name(prog: &[u8], spans: &[std::ops::Range<usize>], label: Label) -> String {
+    let loop_name = |open: usize| {
+        let (line, col) = line_col(prog, spans[open].start);
+        format!("loop_{line}_{col}")
+    };
+    match label {
+        Label::Entry => "bf_main".into(),
+        Label::LoopHead(open) => loop_name(open),
+        Label::LoopExit(open) => format!("{}_end", loop_name(open)),
+        Label::Exit => "exit".into(),
+        Label::IoFailure => "io_failure".into(),
+        Label::OutOfBounds => "out_of_bounds".into(),
+        Label::MemoryError => "memory_error".into(),
+        Label::Cancelled => "cancelled".into(),
+    }
+}
+
+/// Names branch targets after labels and helper addresses after the helper.
+struct Symbols {
+    labels: HashMap<u64, String>,
+    helpers: HashMap<u64, &'static str>,
+}
+
+impl SymbolResolver for Symbols {
+    fn symbol(
+        &mut self,
+        instruction: &Instruction,
+        _operand: u32,
+        instruction_operand: Option<u32>,
+        address: u64,
+        _address_size: u32,
+    ) -> Option<SymbolResult<'_>> {
+        match instruction.op_kind(instruction_operand?) {
+            OpKind::NearBranch64 => self
+                .labels
+                .get(&address)
+                .map(|name| SymbolResult::with_str(address, name)),
+            OpKind::Immediate64 => self
+                .helpers
+                .get(&address)
+                .map(|&name| SymbolResult::with_str(address, name)),
+            _ => None,
+        }
+    }
+}
+
+/// Compile `prog` and return its annotated disassembly.
+pub fn disassemble(prog: &[u8], bounds_checked: bool) -> Result<String, Box<dyn error::Error>> {
+    let (bf_ops, spans) = ir::parse_spanned(prog)?;
+    let compiled = x64_jit::compile(&bf_ops, bounds_checked)?;
+    let map = &compiled.map;
+
+    let labels: Vec<(usize, String)> = map
+        .labels
+        .iter()
+        .map(|&(offset, label)| (offset, label_name(prog, &spans, label)))
+        .collect();
+    let symbols = Symbols {
+        labels: labels
+            .iter()
+            .map(|(offset, name)| (*offset as u64, name.clone()))
+            .collect(),
+        helpers: [Helper::Input, Helper::Output]
+            .into_iter()
+            .map(|helper| {
+                let name = match helper {
+                    Helper::Input => "fill_input",
+                    Helper::Output => "flush_output",
+                };
+                (x64_jit::helper_addr(helper) as u64, name)
+            })
+            .collect(),
+    };
+    let mut formatter = IntelFormatter::with_options(Some(Box::new(symbols)), None);
+    formatter.options_mut().set_first_operand_char_index(8);
+
+    let mut out = String::new();
+    let mut next_label = labels.iter().peekable();
+    let mut next_op = map.op_starts.iter().enumerate().peekable();
+    let mut decoder = Decoder::with_ip(64, compiled.code(), 0, DecoderOptions::NONE);
+    let mut text = String::new();
+    for instruction in &mut decoder {
+        let offset = instruction.ip() as usize;
+        while let Some((_, name)) = next_label.next_if(|(at, _)| *at <= offset) {
+            writeln!(out, "{name}:")?;
+        }
+        while let Some((i, _)) = next_op.next_if(|(_, at)| **at <= offset) {
+            let span = spans[i].clone();
+            let (line, col) = line_col(prog, span.start);
+            let (end_line, end_col) = line_col(prog, span.end - 1);
+            let source = String::from_utf8_lossy(&prog[span]);
+            writeln!(
+                out,
+                "    ; {line}:{col}-{end_line}:{end_col} `{source}` {:?}",
+                bf_ops[i]
+            )?;
+        }
+        text.clear();
+        formatter.format(&instruction, &mut text);
+        writeln!(out, "    {offset:04x}  {text}")?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loops_and_ops_are_annotated() {
+        let asm = disassemble(b"++\n[>+<-].", false).unwrap();
+        assert!(asm.starts_with("bf_main:\n"));
+        assert!(asm.contains("; 1:1-1:2 `++` Add(2)"));
+        assert!(asm.contains("\nloop_2_1:\n"));
+        assert!(asm.contains("\nloop_2_1_end:\n"));
+        assert!(asm.contains("jne     loop_2_1\n"));
+        assert!(asm.contains("je      loop_2_1_end\n"));
+        assert!(asm.contains("; 2:7-2:7 `.` Output"));
+        assert!(asm.contains("flush_output"));
+        assert!(asm.contains("\nmemory_error:\n"));
+    }
+}
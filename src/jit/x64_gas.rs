@@ -0,0 +1,231 @@
+//! Assembly source of the code the x64 JIT generates, for `--emit gas`.
+//!
+//! The program is compiled into `int main(void)` with the same instruction
+//! selection as the JIT, except that the tape lives on the stack and I/O goes
+//! through libc's `getchar` and `putchar`. The result is a `.s` file `as` or
+//! `cc` assembles, with the ops and labels annotated like `--emit asm`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt::Write;
+
+use iced_x86::{
+    Decoder, DecoderOptions, Formatter, GasFormatter, Instruction, IntelFormatter, OpKind,
+    SymbolResolver, SymbolResult,
+};
+
+use crate::jit::GasSyntax;
+use crate::jit::code_cache::Helper;
+use crate::jit::ir::{self, line_col};
+use crate::jit::x64_disasm::label_name;
+use crate::jit::x64_jit::{self, Label};
+
+/// Names branch targets after labels and the libc calls after the function
+/// they call.
+struct Symbols {
+    labels: HashMap<u64, String>,
+    /// Libc function called by the `call` at each offset.
+    calls: HashMap<u64, &'static str>,
+}
+
+impl SymbolResolver for Symbols {
+    fn symbol(
+        &mut self,
+        instruction: &Instruction,
+        _operand: u32,
+        instruction_operand: Option<u32>,
+        address: u64,
+        _address_size: u32,
+    ) -> Option<SymbolResult<'_>> {
+        if instruction.op_kind(instruction_operand?) != OpKind::NearBranch64 {
+            return None;
+        }
+        if let Some(&name) = self.calls.get(&instruction.ip()) {
+            return Some(SymbolResult::with_str(address, name));
+        }
+        self.labels
+            .get(&address)
+            .map(|name| SymbolResult::with_str(address, name))
+    }
+}
+
+/// Compile `prog` into the assembly source of a C `main` function.
+pub fn emit(
+    prog: &[u8],
+    bounds_checked: bool,
+    syntax: GasSyntax,
+) -> Result<String, Box<dyn error::Error>> {
+    let (bf_ops, spans) = ir::parse_spanned(prog)?;
+    let compiled = x64_jit::compile_libc_main(&bf_ops, bounds_checked)?;
+    let map = &compiled.map;
+
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for &(offset, label) in &map.labels {
+        let name = match label {
+            Label::Entry => "main".into(),
+            label => label_name(prog, &spans, label),
+        };
+        labels.entry(offset).or_default().push(name);
+    }
+    let calls: HashMap<u64, &'static str> = compiled
+        .relocs
+        .iter()
+        .map(|reloc| {
+            let name = match reloc.helper {
+                Helper::Input => "getchar@PLT",
+                Helper::Output => "putchar@PLT",
+            };
+            // The rel32 follows the one byte opcode of the `call`.
+            ((reloc.offset - 1) as u64, name)
+        })
+        .collect();
+
+    let instructions: Vec<Instruction> =
+        Decoder::with_ip(64, &compiled.code, 0, DecoderOptions::NONE)
+            .into_iter()
+            .collect();
+    // Every branch has to land on a label to come out as a symbol. The only
+    // calls are those to libc.
+    for instruction in &instructions {
+        let ip = instruction.ip();
+        if instruction.op0_kind() == OpKind::NearBranch64 && !calls.contains_key(&ip) {
+            let target = instruction.near_branch_target() as usize;
+            labels
+                .entry(target)
+                .or_insert_with(|| vec![format!(".L{target:x}")]);
+        }
+    }
+
+    let symbols = Symbols {
+        labels: labels
+            .iter()
+            .map(|(&offset, names)| (offset as u64, names[0].clone()))
+            .collect(),
+        calls,
+    };
+    let mut formatter: Box<dyn Formatter> = match syntax {
+        GasSyntax::Att => Box::new(GasFormatter::with_options(Some(Box::new(symbols)), None)),
+        GasSyntax::Intel => {
+            let mut formatter = IntelFormatter::with_options(Some(Box::new(symbols)), None);
+            formatter.options_mut().set_hex_prefix("0x");
+            formatter.options_mut().set_hex_suffix("");
+            formatter.options_mut().set_show_branch_size(false);
+            Box::new(formatter)
+        }
+    };
+    formatter.options_mut().set_first_operand_char_index(8);
+
+    let mut out = String::new();
+    writeln!(out, "# Generated by bf-jit-rs --emit gas")?;
+    if syntax == GasSyntax::Intel {
+        writeln!(out, "    .intel_syntax noprefix")?;
+    }
+    writeln!(out, "    .text")?;
+    writeln!(out, "    .globl main")?;
+    writeln!(out, "    .type main, @function")?;
+    let mut next_label = labels.iter().peekable();
+    let mut next_op = map.op_starts.iter().enumerate().peekable();
+    let mut text = String::new();
+    for instruction in &instructions {
+        let offset = instruction.ip() as usize;
+        while let Some((_, names)) = next_label.next_if(|(at, _)| **at <= offset) {
+            for name in names {
+                writeln!(out, "{name}:")?;
+            }
+        }
+        while let Some((i, _)) = next_op.next_if(|(_, at)| **at <= offset) {
+            let span = spans[i].clone();
+            let (line, col) = line_col(prog, span.start);
+            let (end_line, end_col) = line_col(prog, span.end - 1);
+            let source = String::from_utf8_lossy(&prog[span]);
+            writeln!(
+                out,
+                "    # {line}:{col}-{end_line}:{end_col} `{source}` {:?}",
+                bf_ops[i]
+            )?;
+        }
+        text.clear();
+        formatter.format(instruction, &mut text);
+        writeln!(out, "    {text}")?;
+    }
+    writeln!(out, "    .size main, .-main")?;
+    writeln!(out, "    .section .note.GNU-stack,\"\",@progbits")?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::HELLO_HELL_STR;
+    use std::fs;
+    use std::io::Write as _;
+    use std::process::{Command, Stdio};
+
+    /// Assemble `asm` with `cc` and run it on `input`, returning its exit
+    /// code and output; `None` without `cc`.
+    fn assemble_and_run(asm: &str, name: &str, input: &[u8]) -> Option<(i32, Vec<u8>)> {
+        Command::new("cc").arg("--version").output().ok()?;
+        let dir = std::env::temp_dir().join(format!("bf-gas-test-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (asm_file, exe) = (dir.join("prog.s"), dir.join("prog"));
+        fs::write(&asm_file, asm).unwrap();
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&asm_file)
+            .status()
+            .unwrap();
+        assert!(status.success(), "cc failed on:\n{asm}");
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        Some((output.status.code().unwrap(), output.stdout))
+    }
+
+    #[test]
+    fn loops_and_libc_calls_are_named() {
+        let asm = emit(b"++\n[>+<-].,", false, GasSyntax::Att).unwrap();
+        assert!(asm.contains("\nmain:\n"));
+        assert!(asm.contains("\nloop_2_1:\n"));
+        assert!(asm.contains("\nloop_2_1_end:\n"));
+        assert!(asm.contains("    # 2:7-2:7 `.` Output\n"));
+        assert!(asm.contains("call    putchar@PLT\n"));
+        assert!(asm.contains("call    getchar@PLT\n"));
+        assert!(asm.contains("jne     loop_2_1\n"));
+        assert!(asm.contains("movzbl  (%rbx),%r15d\n"));
+
+        let asm = emit(b"++\n[>+<-].,", false, GasSyntax::Intel).unwrap();
+        assert!(asm.contains(".intel_syntax noprefix\n"));
+        assert!(asm.contains("je      loop_2_1_end\n"));
+        assert!(asm.contains("movzx   r15d,byte ptr [rbx]\n"));
+    }
+
+    #[test]
+    fn assembled_programs_run() {
+        for (syntax, bounds_checked) in [(GasSyntax::Att, false), (GasSyntax::Intel, true)] {
+            let name = format!("{syntax:?}-{bounds_checked}");
+            let asm = emit(HELLO_HELL_STR.as_bytes(), bounds_checked, syntax).unwrap();
+            if let Some((code, out)) = assemble_and_run(&asm, &name, b"") {
+                assert_eq!((code, out.as_slice()), (0, &b"Hello World! 255\n"[..]));
+            }
+        }
+    }
+
+    #[test]
+    fn io_failures_and_bounds_set_the_exit_code() {
+        let asm = emit(b",[.,]", false, GasSyntax::Att).unwrap();
+        if let Some((code, out)) = assemble_and_run(&asm, "cat", b"piped in") {
+            // EOF is an I/O failure, as in the JIT.
+            assert_eq!((code, out.as_slice()), (1, &b"piped in"[..]));
+        }
+        let asm = emit(b"<+", true, GasSyntax::Intel).unwrap();
+        if let Some((code, _)) = assemble_and_run(&asm, "oob", b"") {
+            assert_eq!(code, 3);
+        }
+    }
+}
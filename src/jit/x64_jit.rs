@@ -7,124 +7,146 @@ use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::stdin;
 use std::io::stdout;
-use std::io::{BufRead, Read, Write};
+use std::io::{self, BufRead, Write};
 use std::mem;
-use std::slice;
+use std::ops::Range;
+use std::ptr;
+use std::sync::atomic::AtomicBool;
 
 use crate::bf_types::BF_MEMORY_SIZE;
+use crate::cancel::{CancelToken, Cancelled, POLL_INTERVAL};
+use crate::jit::bounds_check::{self, Access, CheckRange};
+use crate::jit::code_cache::{self, CachedCode, CodeCache, Helper, Reloc};
+use crate::jit::guarded_tape::{self, GuardedTape};
+use crate::jit::ir::{Ops, parse, parse_spanned};
+use crate::jit::profile::Profile;
+use crate::jit::{gdb, perf};
 
-#[derive(Debug)]
-enum Ops {
-    Left(usize),
-    Right(usize),
-    Add(u8),
-    Sub(u8),
-    Zero,
-    LBrack,
-    RBrack,
-    Output,
-    Input,
-}
-
-type OpSequence = Vec<Ops>;
-
-fn parse(prog_src: &[u8]) -> Result<OpSequence, &'static str> {
-    let mut prog_ops = vec![];
-    let mut i = 0;
-    while i < prog_src.len() {
-        match prog_src[i] as char {
-            '<' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '<' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Left(count));
-                i = j - 1;
-            }
-            '>' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '>' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Right(count));
-                i = j - 1;
-            }
-            '-' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '-' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Sub(count));
-                i = j - 1;
-            }
-            '+' => {
-                let mut count = 1;
-                let mut j = i + 1;
-                while j < prog_src.len() && prog_src[j] as char == '+' {
-                    count += 1;
-                    j += 1;
-                }
-                prog_ops.push(Ops::Add(count));
-                i = j - 1;
-            }
-            '[' => {
-                // Check if it is [-]
-                if i + 2 < prog_src.len()
-                    && prog_src[i + 1] as char == '-'
-                    && prog_src[i + 2] as char == ']'
-                {
-                    prog_ops.push(Ops::Zero);
-                    i += 3;
-                    continue;
-                }
-                prog_ops.push(Ops::LBrack);
-            }
-            ']' => {
-                prog_ops.push(Ops::RBrack);
-            }
-            '.' => prog_ops.push(Ops::Output),
-            ',' => prog_ops.push(Ops::Input),
-            _ => (),
+/// Largest distance the pointer can travel between two cell accesses.
+///
+/// Consecutive moves are only folded when they go the same way, so `>>><>>>`
+/// still moves five cells without touching the tape. The guard regions must be
+/// wider than this for every stray access to land in them.
+pub(super) fn max_unchecked_move(ops: &[Ops]) -> usize {
+    let mut max = 0;
+    let mut offset: isize = 0;
+    for op in ops {
+        match op {
+            Ops::Left(amount) => offset -= *amount as isize,
+            Ops::Right(amount) => offset += *amount as isize,
+            _ => offset = 0,
         }
-        i += 1;
+        max = max.max(offset.unsigned_abs());
     }
-    Ok(prog_ops)
+    max
 }
 
-struct State<'a> {
+/// Size of the output buffer and the input prefetch buffer of `State`.
+const IO_BUF_SIZE: usize = 4096;
+
+/// I/O state of running JIT code.
+///
+/// JIT code writes output straight into `out_buf`, keeping the write cursor
+/// in a register, and reads input from the prefetched `in_buf`. It only calls
+/// out when the output buffer is full or the input buffer is empty; output
+/// still buffered when the code returns is written by `Compiled::call`. The
+/// fields up to `poll_countdown` are read and written by generated code.
+#[repr(C)]
+pub(super) struct State<'a> {
+    /// Next free byte of `out_buf`, stored by JIT code when it returns.
+    out_cursor: *mut u8,
+    out_end: *mut u8,
+    /// Next unread byte of `in_buf` and the end of the prefetched input.
+    in_cursor: *const u8,
+    in_end: *const u8,
+    /// Token polled by code compiled as cancellable, at every
+    /// `POLL_INTERVAL`th loop back-edge.
+    cancel: *const AtomicBool,
+    /// Entry and back-edge counts of every loop, for profiled code.
+    counters: *mut u64,
+    poll_countdown: u32,
+    out_buf: Box<[u8]>,
+    in_buf: Box<[u8]>,
     pub input: Box<dyn BufRead + 'a>,
     pub output: Box<dyn Write + 'a>,
 }
 
+const STATE_OUT_CURSOR: i32 = mem::offset_of!(State<'static>, out_cursor) as i32;
+const STATE_OUT_END: i32 = mem::offset_of!(State<'static>, out_end) as i32;
+const STATE_IN_CURSOR: i32 = mem::offset_of!(State<'static>, in_cursor) as i32;
+const STATE_IN_END: i32 = mem::offset_of!(State<'static>, in_end) as i32;
+const STATE_CANCEL: i32 = mem::offset_of!(State<'static>, cancel) as i32;
+const STATE_COUNTERS: i32 = mem::offset_of!(State<'static>, counters) as i32;
+const STATE_POLL_COUNTDOWN: i32 = mem::offset_of!(State<'static>, poll_countdown) as i32;
+
 impl<'a> State<'a> {
-    unsafe extern "win64" fn getchar(state: *mut State, cell: *mut u8) -> u8 {
-        unsafe {
-            let state = &mut *state;
-            (state
-                .input
-                .read_exact(slice::from_raw_parts_mut(cell, 1))
-                .is_err()) as u8
-        }
+    /// Write out `out_buf` up to `cursor`, leaving `out_cursor` at the start
+    /// of the buffer. Returns non-zero on failure.
+    unsafe extern "sysv64" fn flush_output(state: *mut State, cursor: *mut u8) -> u8 {
+        let state = unsafe { &mut *state };
+        state.out_cursor = cursor;
+        state.write_pending().is_err() as u8
     }
 
-    unsafe extern "win64" fn putchar(state: *mut State, cell: *mut u8) -> u8 {
-        unsafe {
-            let state = &mut *state;
-            state
-                .output
-                .write_all(slice::from_raw_parts(cell, 1))
-                .is_err() as u8
+    /// Flush the output up to `cursor`, then refill `in_buf`. Returns non-zero
+    /// on failure or at the end of the input.
+    unsafe extern "sysv64" fn fill_input(state: *mut State, cursor: *mut u8) -> u8 {
+        let state = unsafe { &mut *state };
+        let failed = unsafe { State::flush_output(state, cursor) } != 0
+            || state.refill().is_err()
+            || state.in_cursor == state.in_end;
+        failed as u8
+    }
+
+    /// Write the bytes JIT code left in `out_buf`.
+    pub(super) fn write_pending(&mut self) -> io::Result<()> {
+        let start = self.out_buf.as_mut_ptr();
+        let len = self.out_cursor as usize - start as usize;
+        self.out_cursor = start;
+        self.output.write_all(&self.out_buf[..len])
+    }
+
+    fn refill(&mut self) -> io::Result<()> {
+        let available = self.input.fill_buf()?;
+        let n = available.len().min(self.in_buf.len());
+        self.in_buf[..n].copy_from_slice(&available[..n]);
+        self.input.consume(n);
+        self.in_cursor = self.in_buf.as_ptr();
+        self.in_end = self.in_cursor.wrapping_add(n);
+        Ok(())
+    }
+
+    /// Read one byte, taking input JIT code prefetched first.
+    pub(super) fn read_byte(&mut self) -> io::Result<u8> {
+        if self.in_cursor == self.in_end {
+            self.refill()?;
+            if self.in_cursor == self.in_end {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
         }
+        let byte = unsafe { *self.in_cursor };
+        self.in_cursor = self.in_cursor.wrapping_add(1);
+        Ok(byte)
     }
 
-    fn new(input: Box<dyn BufRead + 'a>, output: Box<dyn Write + 'a>) -> State<'a> {
-        State { input, output }
+    pub(super) fn new(input: Box<dyn BufRead + 'a>, output: Box<dyn Write + 'a>) -> State<'a> {
+        let mut out_buf = vec![0; IO_BUF_SIZE].into_boxed_slice();
+        let in_buf = vec![0; IO_BUF_SIZE].into_boxed_slice();
+        let out_range = out_buf.as_mut_ptr_range();
+        let in_cursor = in_buf.as_ptr();
+        State {
+            out_cursor: out_range.start,
+            out_end: out_range.end,
+            in_cursor,
+            in_end: in_cursor,
+            cancel: ptr::null(),
+            counters: ptr::null_mut(),
+            poll_countdown: POLL_INTERVAL,
+            out_buf,
+            in_buf,
+            input,
+            output,
+        }
     }
 }
 
@@ -132,128 +154,1027 @@ macro_rules! x64_bf {
     ($ops:ident $($t:tt)*) => {
         dynasm!($ops
             ; .arch x64
-            ; .alias a_state, rcx // 1st arg in win64
-            ; .alias a_current, rdx // 2nd arg in win64
+            // Everything that has to survive a call lives in callee-saved
+            // registers, so calls need no spilling.
+            ; .alias a_state, r12
+            ; .alias a_current, rbx
+            ; .alias a_begin, r13
+            ; .alias a_end, r14
+            ; .alias cell, r15b // cached value of [a_current]
+            ; .alias a_out, rbp // output cursor of `State` in JIT code
             ; .alias retval, rax
             $($t)*
         )
     }
 }
 
-macro_rules! call_extern {
-    ($ops:ident, $addr:expr) => {x64_bf!($ops
-        ; mov [rsp + 0x08], a_current
-        ; mov retval, QWORD $addr as _
-        ; call retval
-        ; mov a_state, [rsp + 0x00]
-        ; mov a_current, [rsp + 0x08]
+macro_rules! epilogue {
+    ($ops:ident, $e:expr) => {x64_bf!($ops
+        ; mov retval, $e
+        ; mov rdx, a_current
+        ; mov [a_state + STATE_OUT_CURSOR], a_out
+        ; add rsp, 8
+        ; pop rbp
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbx
+        ; ret
     );};
 }
 
-fn compile(prog: &[u8]) -> Result<(ExecutableBuffer, AssemblyOffset), Box<dyn error::Error>> {
-    let bf_ops: Vec<Ops> = parse(prog)?;
-    let mut ops: dynasmrt::Assembler<dynasmrt::x64::X64Relocation> =
-        dynasmrt::x64::Assembler::new()?;
-    let mut loop_stack: Vec<(dynasmrt::DynamicLabel, dynasmrt::DynamicLabel)> = vec![];
+/// Where generated code runs, which decides how it does I/O.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// Inside this process, calling back into `State`.
+    Jit,
+    /// As a standalone Linux program, using raw `read`/`write` syscalls.
+    Standalone,
+    /// As `bf_main` in an object file, calling the callbacks it was given.
+    Object,
+    /// As `main` of a C program, calling libc's `getchar` and `putchar`.
+    Libc,
+}
 
-    let start = ops.offset();
-    x64_bf!(ops
-        ; sub rsp, 0x18
-        ; mov [rsp + 0x00], a_state
-    );
+/// Code woven into a program on top of what it computes.
+#[derive(Debug, Clone, Copy, Default)]
+struct Instrumentation {
+    /// Poll the cancel token of `State` at loop back-edges.
+    cancellable: bool,
+    /// Count loop entries and taken back-edges in the counters of `State`,
+    /// two per loop in the order the loops open.
+    profiled: bool,
+}
 
-    for op in bf_ops {
-        match op {
-            Ops::Left(amount) => x64_bf!(ops;
-                sub a_current, (amount) as _
-            ),
-            Ops::Right(amount) => x64_bf!(ops;
-                add a_current, (amount) as _
-            ),
-            Ops::Add(amount) => x64_bf!(ops;
-                add BYTE [a_current], amount as _
-            ),
-            Ops::Sub(amount) => x64_bf!(ops;
-                sub BYTE [a_current], amount as _
-            ),
-            Ops::Zero => x64_bf!(ops;
-                mov BYTE [a_current], 0
+/// Emits code for one program while tracking what `cell` holds.
+///
+/// The current cell is loaded into `cell` on first use and arithmetic works on
+/// the register. It is written back (`flush`) before the pointer moves, before
+/// I/O and at loop tests, which then test the register instead of memory.
+struct Codegen {
+    ops: dynasmrt::x64::Assembler,
+    target: Target,
+    /// `cell` holds the value of the current cell.
+    cached: bool,
+    /// `cell` holds a value that is not in memory yet.
+    dirty: bool,
+    relocs: Vec<Reloc>,
+    map: CodeMap,
+    instrumentation: Instrumentation,
+    /// Number of loops opened so far.
+    loops: usize,
+}
+
+/// A named position in generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Label {
+    Entry,
+    /// Back-edge target of the loop whose `[` is op `i`.
+    LoopHead(usize),
+    /// Where the loop whose `[` is op `i` exits to.
+    LoopExit(usize),
+    Exit,
+    IoFailure,
+    OutOfBounds,
+    MemoryError,
+    Cancelled,
+}
+
+/// Where ops and labels ended up in generated code, for tooling that maps
+/// machine code back to the program.
+#[derive(Debug, Clone, Default)]
+pub(super) struct CodeMap {
+    /// Code offset of op `i`, including the bounds check emitted before it.
+    pub(super) op_starts: Vec<usize>,
+    /// Labels sorted by offset.
+    pub(super) labels: Vec<(usize, Label)>,
+}
+
+pub(super) fn helper_addr(helper: Helper) -> i64 {
+    match helper {
+        Helper::Input => State::fill_input as *const () as i64,
+        Helper::Output => State::flush_output as *const () as i64,
+    }
+}
+
+impl Codegen {
+    fn new(target: Target) -> Result<Codegen, Box<dyn error::Error>> {
+        Ok(Codegen {
+            ops: dynasmrt::x64::Assembler::new()?,
+            target,
+            cached: false,
+            dirty: false,
+            relocs: vec![],
+            map: CodeMap::default(),
+            instrumentation: Instrumentation::default(),
+            loops: 0,
+        })
+    }
+
+    fn label(&mut self, label: Label) {
+        let offset = self.ops.offset().0;
+        self.map.labels.push((offset, label));
+    }
+
+    fn load(&mut self) {
+        if !self.cached {
+            let ops = &mut self.ops;
+            x64_bf!(ops
+                ; movzx r15d, BYTE [a_current]
+            );
+            self.cached = true;
+            self.dirty = false;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.dirty {
+            let ops = &mut self.ops;
+            x64_bf!(ops
+                ; mov BYTE [a_current], cell
+            );
+            self.dirty = false;
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.flush();
+        self.cached = false;
+    }
+
+    /// Write back and load the current cell so `cell` is valid and clean.
+    ///
+    /// Used at both ends of a loop, so both edges into a loop header or exit
+    /// agree on the cache state.
+    fn sync(&mut self) {
+        self.flush();
+        self.load();
+    }
+
+    fn io(&mut self, helper: Helper) {
+        match self.target {
+            Target::Jit => self.buffered_io(helper),
+            Target::Standalone => self.syscall_io(helper),
+            Target::Object => self.callback_io(helper),
+            Target::Libc => self.libc_io(helper),
+        }
+    }
+
+    /// `getchar()` or `putchar(cell)`. The call is left unresolved and
+    /// recorded in `relocs`, whose offsets point at its rel32. EOF from
+    /// either is an I/O failure, as in the JIT.
+    fn libc_io(&mut self, helper: Helper) {
+        self.sync();
+        if helper == Helper::Output {
+            let ops = &mut self.ops;
+            x64_bf!(ops
+                ; movzx edi, cell
+            );
+        }
+        self.ops.extend([0xe8, 0, 0, 0, 0]);
+        self.relocs.push(Reloc {
+            offset: self.ops.offset().0 - 4,
+            helper,
+        });
+        let ops = &mut self.ops;
+        x64_bf!(ops
+            ; test eax, eax
+            ; js ->io_failure
+        );
+        if helper == Helper::Input {
+            x64_bf!(ops
+                ; mov cell, al
+            );
+            self.dirty = true;
+        }
+    }
+
+    /// `getc(ctx)` or `putc(cell, ctx)` through the callbacks `bf_main` keeps
+    /// at `[rsp]` and `[rsp + 8]`. A negative `getc` result or a non-zero
+    /// `putc` result is an I/O failure.
+    fn callback_io(&mut self, helper: Helper) {
+        self.sync();
+        let ops = &mut self.ops;
+        match helper {
+            Helper::Input => {
+                x64_bf!(ops
+                    ; mov rdi, a_state
+                    ; call QWORD [rsp]
+                    ; test eax, eax
+                    ; js ->io_failure
+                    ; mov cell, al
+                );
+                self.dirty = true;
+            }
+            Helper::Output => x64_bf!(ops
+                ; movzx edi, cell
+                ; mov rsi, a_state
+                ; call QWORD [rsp + 8]
+                ; test eax, eax
+                ; jnz ->io_failure
             ),
-            Ops::LBrack => {
-                let backward_label = ops.new_dynamic_label();
-                let forward_label = ops.new_dynamic_label();
-                loop_stack.push((backward_label, forward_label));
+        }
+    }
+
+    /// `read(0, cell, 1)` or `write(1, cell, 1)`; anything but a one byte
+    /// transfer (including EOF) is an I/O failure, as in the JIT.
+    fn syscall_io(&mut self, helper: Helper) {
+        self.sync();
+        let (nr, fd) = match helper {
+            Helper::Input => (0, 0),
+            Helper::Output => (1, 1),
+        };
+        let ops = &mut self.ops;
+        x64_bf!(ops
+            ; mov eax, nr
+            ; mov edi, fd
+            ; mov rsi, a_current
+            ; mov edx, 1
+            ; syscall
+            ; cmp retval, 1
+            ; jne ->io_failure
+        );
+        // Input lands in memory behind the cache's back.
+        self.cached &= helper != Helper::Input;
+    }
+
+    /// Call `helper` with the state and the output cursor and pick up the
+    /// cursor it leaves in the state. A non-zero return is an I/O failure.
+    fn call_extern(&mut self, helper: Helper) {
+        let ops = &mut self.ops;
+        x64_bf!(ops
+            ; mov rdi, a_state
+            ; mov rsi, a_out
+            ; mov retval, QWORD helper_addr(helper)
+        );
+        // The address is the imm64 at the end of the `mov`.
+        self.relocs.push(Reloc {
+            offset: self.ops.offset().0 - 8,
+            helper,
+        });
+        let ops = &mut self.ops;
+        x64_bf!(ops
+            ; call retval
+            ; mov a_out, [a_state + STATE_OUT_CURSOR]
+            ; test al, al
+            ; jnz ->io_failure
+        );
+    }
+
+    /// Store the cell in the output buffer, flushing it once it is full, or
+    /// take the next byte of prefetched input, refilling it once it is empty.
+    fn buffered_io(&mut self, helper: Helper) {
+        // Loading the cell also makes an out of bounds access fault here
+        // rather than at some later write-back.
+        self.load();
+        match helper {
+            Helper::Output => {
+                let ops = &mut self.ops;
                 x64_bf!(ops
-                    ; cmp BYTE [a_current], 0
-                    ; jz =>forward_label
-                    ;=>backward_label
+                    ; mov [a_out], cell
+                    ; add a_out, 1
+                    ; cmp a_out, [a_state + STATE_OUT_END]
+                    ; jb >done
+                );
+                self.call_extern(helper);
+                let ops = &mut self.ops;
+                x64_bf!(ops
+                    ; done:
                 );
             }
-            Ops::RBrack => {
-                if let Some((backward_label, forward_label)) = loop_stack.pop() {
+            Helper::Input => {
+                let ops = &mut self.ops;
+                x64_bf!(ops
+                    ; mov retval, [a_state + STATE_IN_CURSOR]
+                    ; cmp retval, [a_state + STATE_IN_END]
+                    ; jb >ready
+                );
+                self.call_extern(helper);
+                let ops = &mut self.ops;
+                x64_bf!(ops
+                    ; mov retval, [a_state + STATE_IN_CURSOR]
+                    ; ready:
+                    ; movzx r15d, BYTE [retval]
+                    ; add retval, 1
+                    ; mov [a_state + STATE_IN_CURSOR], retval
+                );
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Emit `bf_ops`, leaving the current cell written back at the end.
+    fn emit_ops(
+        &mut self,
+        bf_ops: &[Ops],
+        bounds_checked: bool,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let accesses: Vec<Access> = bf_ops.iter().map(Ops::access).collect();
+        let plan = bounds_checked.then(|| bounds_check::plan(&accesses));
+        let mut loop_stack: Vec<(usize, usize, dynasmrt::DynamicLabel, dynasmrt::DynamicLabel)> =
+            vec![];
+
+        for (i, &op) in bf_ops.iter().enumerate() {
+            self.map.op_starts.push(self.ops.offset().0);
+            if let Some(range) = plan.as_ref().and_then(|p| p.before[i].as_ref()) {
+                self.bounds_check(range)?;
+            }
+            match op {
+                Ops::Left(amount) => {
+                    self.invalidate();
+                    let ops = &mut self.ops;
+                    x64_bf!(ops
+                        ; sub a_current, (amount) as _
+                    );
+                }
+                Ops::Right(amount) => {
+                    self.invalidate();
+                    let ops = &mut self.ops;
+                    x64_bf!(ops
+                        ; add a_current, (amount) as _
+                    );
+                }
+                Ops::Add(amount) => {
+                    self.load();
+                    self.dirty = true;
+                    let ops = &mut self.ops;
+                    x64_bf!(ops
+                        ; add cell, amount as _
+                    );
+                }
+                Ops::Sub(amount) => {
+                    self.load();
+                    self.dirty = true;
+                    let ops = &mut self.ops;
+                    x64_bf!(ops
+                        ; sub cell, amount as _
+                    );
+                }
+                Ops::Zero => {
+                    self.cached = true;
+                    self.dirty = true;
+                    let ops = &mut self.ops;
+                    x64_bf!(ops
+                        ; xor r15d, r15d
+                    );
+                }
+                Ops::LBrack => {
+                    self.sync();
+                    let slot = self.loops;
+                    self.loops += 1;
+                    let ops = &mut self.ops;
+                    let backward_label = ops.new_dynamic_label();
+                    let forward_label = ops.new_dynamic_label();
+                    loop_stack.push((i, slot, backward_label, forward_label));
+                    x64_bf!(ops
+                        ; test cell, cell
+                        ; jz =>forward_label
+                    );
+                    if let Some(range) = plan.as_ref().and_then(|p| p.on_entry[i].as_ref()) {
+                        self.bounds_check(range)?;
+                    }
+                    if self.instrumentation.profiled {
+                        self.count(slot, 0);
+                    }
+                    self.label(Label::LoopHead(i));
+                    let ops = &mut self.ops;
+                    x64_bf!(ops
+                        ;=>backward_label
+                    );
+                }
+                Ops::RBrack => {
+                    let Some((open, slot, backward_label, forward_label)) = loop_stack.pop() else {
+                        return Err("] without matching [".into());
+                    };
+                    self.sync();
+                    let Instrumentation {
+                        cancellable,
+                        profiled,
+                    } = self.instrumentation;
+                    let ops = &mut self.ops;
+                    if cancellable || profiled {
+                        x64_bf!(ops
+                            ; test cell, cell
+                            ; jz =>forward_label
+                        );
+                        if profiled {
+                            self.count(slot, 1);
+                        }
+                        let ops = &mut self.ops;
+                        if cancellable {
+                            x64_bf!(ops
+                                ; sub DWORD [a_state + STATE_POLL_COUNTDOWN], 1
+                                ; jnz =>backward_label
+                                ; mov DWORD [a_state + STATE_POLL_COUNTDOWN], POLL_INTERVAL as _
+                                ; mov retval, [a_state + STATE_CANCEL]
+                                ; cmp BYTE [retval], 0
+                                ; je =>backward_label
+                                ; jmp ->cancelled
+                            );
+                        } else {
+                            x64_bf!(ops
+                                ; jmp =>backward_label
+                            );
+                        }
+                    } else {
+                        x64_bf!(ops
+                            ; test cell, cell
+                            ; jnz =>backward_label
+                        );
+                    }
+                    let ops = &mut self.ops;
                     x64_bf!(ops
-                        ; cmp BYTE [a_current], 0
-                        ; jnz =>backward_label
                         ;=>forward_label
                     );
-                } else {
-                    return Err("] without matching [".into());
+                    self.label(Label::LoopExit(open));
                 }
+                Ops::Output => self.io(Helper::Output),
+                Ops::Input => self.io(Helper::Input),
             }
-            Ops::Output => x64_bf!(ops
-                ;; call_extern!(ops, State::putchar)
-                ; cmp al, 0
-                ; jnz ->io_failure
-            ),
-            Ops::Input => x64_bf!(ops
-                ;; call_extern!(ops, State::getchar)
-                ; cmp al, 0
-                ; jnz ->io_failure
-            ),
         }
+        if !loop_stack.is_empty() {
+            return Err("[ without matching ]".into());
+        }
+        self.flush();
+        Ok(())
+    }
+
+    /// Increment counter `which` (0 for entries, 1 for back-edges) of loop
+    /// `slot`.
+    fn count(&mut self, slot: usize, which: usize) {
+        let offset = (slot * 2 + which) * mem::size_of::<u64>();
+        let ops = &mut self.ops;
+        x64_bf!(ops
+            ; mov retval, [a_state + STATE_COUNTERS]
+            ; add QWORD [retval + offset as i32], 1
+        );
+    }
+
+    fn bounds_check(&mut self, range: &CheckRange) -> Result<(), Box<dyn error::Error>> {
+        let lo = i32::try_from(*range.start()).map_err(|_| "Pointer offset too large")?;
+        let hi = i32::try_from(*range.end()).map_err(|_| "Pointer offset too large")?;
+        let ops = &mut self.ops;
+        x64_bf!(ops
+            ; lea retval, [a_current + lo]
+            ; cmp retval, a_begin
+            ; jb ->out_of_bounds
+            ; lea retval, [a_current + hi]
+            ; cmp retval, a_end
+            ; jae ->out_of_bounds
+        );
+        Ok(())
+    }
+}
+
+/// What compiled code returns: a status in rax and the final pointer in rdx.
+#[repr(C)]
+struct JitExit {
+    status: u64,
+    current: *mut u8,
+}
+
+pub(super) struct Compiled {
+    /// Declared first so GDB forgets the code before it is unmapped.
+    debug: Option<gdb::Registration>,
+    buffer: ExecutableBuffer,
+    start: AssemblyOffset,
+    memory_error: AssemblyOffset,
+    max_move: usize,
+    relocs: Vec<Reloc>,
+    /// Empty for code loaded from the cache.
+    pub(super) map: CodeMap,
+}
+
+impl Compiled {
+    /// The generated machine code, with helper addresses patched in.
+    pub(super) fn code(&self) -> &[u8] {
+        &self.buffer
     }
-    if loop_stack.is_empty() {
-        return Err("[ without matching ]".into());
+
+    /// Describe the code to profilers and debuggers that asked for it.
+    ///
+    /// `spans` are the source spans of the compiled ops and `root` names the
+    /// code outside of any loop.
+    pub(super) fn register(
+        &mut self,
+        prog: &[u8],
+        spans: &[Range<usize>],
+        root: &str,
+    ) -> Result<(), Box<dyn error::Error>> {
+        if perf::enabled() {
+            perf::register_compiled(prog, spans, self, root)?;
+        }
+        self.debug = gdb::register(prog, spans, &self.map, self.code(), root);
+        Ok(())
     }
 
+    fn to_cached(&self) -> CachedCode {
+        let mut code = self.buffer.to_vec();
+        for reloc in &self.relocs {
+            code[reloc.offset..reloc.offset + 8].fill(0);
+        }
+        CachedCode {
+            code,
+            relocs: self.relocs.clone(),
+            start: self.start.0,
+            memory_error: self.memory_error.0,
+            max_move: self.max_move,
+        }
+    }
+
+    /// Load cached code, patching in this process' helper addresses.
+    fn from_cached(cached: CachedCode) -> Result<Compiled, Box<dyn error::Error>> {
+        let mut code = cached.code;
+        for reloc in &cached.relocs {
+            code[reloc.offset..reloc.offset + 8]
+                .copy_from_slice(&helper_addr(reloc.helper).to_le_bytes());
+        }
+        let mut ops = dynasmrt::x64::Assembler::new()?;
+        ops.extend(&code);
+        let buffer = ops
+            .finalize()
+            .map_err(|e| format!("Assembler finalize error: {:?}", e))?;
+
+        Ok(Compiled {
+            debug: None,
+            buffer,
+            start: AssemblyOffset(cached.start),
+            memory_error: AssemblyOffset(cached.memory_error),
+            max_move: cached.max_move,
+            relocs: cached.relocs,
+            map: CodeMap::default(),
+        })
+    }
+
+    /// Run the code with the pointer at cell `pointer` of `tape`.
+    ///
+    /// Returns the cell index the pointer ends up at.
+    pub(super) fn call(
+        &self,
+        state: &mut State,
+        tape: &mut GuardedTape,
+        pointer: usize,
+    ) -> Result<usize, Box<dyn error::Error>> {
+        let f: extern "sysv64" fn(*mut State, *mut u8, *const u8, *const u8) -> JitExit =
+            unsafe { mem::transmute(self.buffer.ptr(self.start)) };
+
+        let code = (self.buffer.ptr(AssemblyOffset(0)), self.buffer.len());
+        let recovery = self.buffer.ptr(self.memory_error);
+        let (res, fault) = guarded_tape::guard(tape, code, recovery, |cp| {
+            let exit = f(
+                state,
+                cp.wrapping_add(pointer),
+                cp,
+                cp.wrapping_add(BF_MEMORY_SIZE),
+            );
+            (
+                exit.status,
+                (exit.current as usize).wrapping_sub(cp as usize),
+            )
+        });
+
+        // Whatever the code wrote before it stopped is still output.
+        let flushed = state.write_pending();
+        match res {
+            (0 | 4, _) if flushed.is_err() => Err("IO error".into()),
+            (0, pointer) => Ok(pointer),
+            (1, _) => Err("IO error".into()),
+            (4, pointer) => Err(Cancelled { pointer }.into()),
+            (2, _) if fault.is_some() => Err(fault.unwrap().into()),
+            (3, _) => Err("Out of bounds".into()),
+            (res, _) => Err(format!("Unknown Error: {res}").into()),
+        }
+    }
+}
+
+/// Compile `bf_ops` into a function that runs them from the pointer it is
+/// given. The ops don't have to be a whole program; any balanced slice, such
+/// as a single loop, can be compiled on its own.
+pub(super) fn compile(
+    bf_ops: &[Ops],
+    bounds_checked: bool,
+) -> Result<Compiled, Box<dyn error::Error>> {
+    compile_with(bf_ops, bounds_checked, Instrumentation::default())
+}
+
+/// `compile` with `instrumentation` woven into the code.
+fn compile_with(
+    bf_ops: &[Ops],
+    bounds_checked: bool,
+    instrumentation: Instrumentation,
+) -> Result<Compiled, Box<dyn error::Error>> {
+    let max_move = max_unchecked_move(bf_ops);
+    let mut cg = Codegen::new(Target::Jit)?;
+    cg.instrumentation = instrumentation;
+
+    cg.label(Label::Entry);
+    let ops = &mut cg.ops;
+    let start = ops.offset();
+    // Six pushes and the return address leave rsp 8 bytes short of 16-byte
+    // alignment.
+    x64_bf!(ops
+        ; push rbx
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; push rbp
+        ; sub rsp, 8
+        ; mov a_state, rdi
+        ; mov a_current, rsi
+        ; mov a_begin, rdx
+        ; mov a_end, rcx
+        ; mov a_out, [a_state + STATE_OUT_CURSOR]
+    );
+    cg.emit_ops(bf_ops, bounds_checked)?;
+
+    cg.label(Label::Exit);
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ;; epilogue!(ops, 0)
+    );
+    cg.label(Label::IoFailure);
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ;->io_failure:
+        ;; epilogue!(ops, 1)
+    );
+    cg.label(Label::OutOfBounds);
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ;->out_of_bounds:
+        ;; epilogue!(ops, 3)
+    );
+    if instrumentation.cancellable {
+        cg.label(Label::Cancelled);
+        let ops = &mut cg.ops;
+        x64_bf!(ops
+            ;->cancelled:
+            ;; epilogue!(ops, 4)
+        );
+    }
+    // The SIGSEGV handler resumes here when the tape guard is hit. The stack
+    // is only adjusted in the prologue, so it is safe to unwind from any op.
+    cg.label(Label::MemoryError);
+    let ops = &mut cg.ops;
+    let memory_error = ops.offset();
+    x64_bf!(ops
+        ;; epilogue!(ops, 2)
+    );
+
+    let buffer = cg
+        .ops
+        .finalize()
+        .map_err(|e| format!("Assembler finalize error: {:?}", e))?;
+
+    Ok(Compiled {
+        debug: None,
+        buffer,
+        start,
+        memory_error,
+        max_move,
+        relocs: cg.relocs,
+        map: cg.map,
+    })
+}
+
+/// Compile `prog` into the code of a standalone x86-64 Linux program.
+///
+/// Returns the code and the offset of its entry point. The code maps its own
+/// tape, does I/O through syscalls and exits with status 0 on success, 1 on
+/// I/O failure and 3 when the program leaves the tape. It is position
+/// independent, so it can be loaded anywhere.
+pub fn compile_standalone(prog: &[u8]) -> Result<(Vec<u8>, usize), Box<dyn error::Error>> {
+    let bf_ops = parse(prog)?;
+    let mut cg = Codegen::new(Target::Standalone)?;
+
+    const PROT_READ_WRITE: i32 = 0x3;
+    const MAP_PRIVATE_ANONYMOUS: i32 = 0x22;
+    let ops = &mut cg.ops;
+    let start = ops.offset();
+    x64_bf!(ops
+        // mmap(NULL, BF_MEMORY_SIZE, PROT_READ | PROT_WRITE,
+        //      MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+        ; mov eax, 9
+        ; xor edi, edi
+        ; mov esi, BF_MEMORY_SIZE as _
+        ; mov edx, PROT_READ_WRITE
+        ; mov r10d, MAP_PRIVATE_ANONYMOUS
+        ; mov r8, -1
+        ; xor r9d, r9d
+        ; syscall
+        ; test retval, retval
+        ; js ->io_failure
+        ; mov a_current, retval
+        ; mov a_begin, retval
+        ; lea a_end, [retval + BF_MEMORY_SIZE as _]
+    );
+    cg.emit_ops(&bf_ops, true)?;
+
+    let ops = &mut cg.ops;
     x64_bf!(ops
-        ; mov retval, 0
+        ; xor edi, edi
+        ; mov eax, 60 // exit
+        ; syscall
+        ;->io_failure:
+        ; mov edi, 1
+        ; mov eax, 60
+        ; syscall
+        ;->out_of_bounds:
+        ; mov edi, 3
+        ; mov eax, 60
+        ; syscall
+    );
+
+    let buffer = cg
+        .ops
+        .finalize()
+        .map_err(|e| format!("Assembler finalize error: {:?}", e))?;
+    Ok((buffer.to_vec(), start.0))
+}
+
+/// Compile `prog` into the code of an object file's `bf_main` function:
+///
+/// `int bf_main(uint8_t *tape, bf_getc_fn getc, bf_putc_fn putc, void *ctx)`
+///
+/// The tape has to hold `BF_MEMORY_SIZE` cells and every access is bounds
+/// checked. Returns the code and the offset of `bf_main`, which returns 0 on
+/// success, 1 on I/O failure and 3 when the program leaves the tape.
+pub fn compile_object(prog: &[u8]) -> Result<(Vec<u8>, usize), Box<dyn error::Error>> {
+    let bf_ops = parse(prog)?;
+    let mut cg = Codegen::new(Target::Object)?;
+
+    let ops = &mut cg.ops;
+    let start = ops.offset();
+    // Six pushes and the callback slots on top of the return address keep
+    // rsp 16-byte aligned.
+    x64_bf!(ops
+        ; push rbx
+        ; push rbp
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; sub rsp, 0x18
+        ; mov [rsp], rsi
+        ; mov [rsp + 8], rdx
+        ; mov a_state, rcx
+        ; mov a_current, rdi
+        ; mov a_begin, rdi
+        ; lea a_end, [rdi + BF_MEMORY_SIZE as _]
+    );
+    cg.emit_ops(&bf_ops, true)?;
+
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ; xor eax, eax
+        ;->exit:
         ; add rsp, 0x18
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbp
+        ; pop rbx
         ; ret
         ;->io_failure:
-        ; mov retval, 1
-        ; add rsp, 0x18
+        ; mov eax, 1
+        ; jmp ->exit
+        ;->out_of_bounds:
+        ; mov eax, 3
+        ; jmp ->exit
+    );
+
+    let buffer = cg
+        .ops
+        .finalize()
+        .map_err(|e| format!("Assembler finalize error: {:?}", e))?;
+    Ok((buffer.to_vec(), start.0))
+}
+
+/// Code for the `main` function of a C program, with the calls to `getchar`
+/// and `putchar` left for the assembler to resolve.
+pub(super) struct LibcMain {
+    pub(super) code: Vec<u8>,
+    /// Call sites of the libc functions; `Helper::Input` is `getchar` and
+    /// `Helper::Output` is `putchar`.
+    pub(super) relocs: Vec<Reloc>,
+    pub(super) map: CodeMap,
+}
+
+/// Compile `bf_ops` into `int main(void)` with the same instruction selection
+/// as `compile`, but with the tape on the stack and I/O through libc.
+///
+/// `main` returns 0 on success, 1 on I/O failure (including EOF) and 3 when a
+/// bounds checked program leaves the tape.
+pub(super) fn compile_libc_main(
+    bf_ops: &[Ops],
+    bounds_checked: bool,
+) -> Result<LibcMain, Box<dyn error::Error>> {
+    // Six pushes and the return address leave rsp 8 bytes short of 16-byte
+    // alignment, which the frame makes up for.
+    const FRAME: i32 = (BF_MEMORY_SIZE.next_multiple_of(16) + 8) as i32;
+    let mut cg = Codegen::new(Target::Libc)?;
+
+    cg.label(Label::Entry);
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ; push rbx
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; push rbp
+        ; sub rsp, FRAME
+        ; mov rdi, rsp
+        ; mov ecx, BF_MEMORY_SIZE as _
+        ; xor eax, eax
+        ; rep stosb
+        ; mov a_current, rsp
+        ; mov a_begin, rsp
+        ; lea a_end, [rsp + BF_MEMORY_SIZE as _]
+    );
+    cg.emit_ops(bf_ops, bounds_checked)?;
+
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ; xor eax, eax
+    );
+    cg.label(Label::Exit);
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ;->exit:
+        ; add rsp, FRAME
+        ; pop rbp
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbx
         ; ret
     );
+    cg.label(Label::IoFailure);
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ;->io_failure:
+        ; mov eax, 1
+        ; jmp ->exit
+    );
+    cg.label(Label::OutOfBounds);
+    let ops = &mut cg.ops;
+    x64_bf!(ops
+        ;->out_of_bounds:
+        ; mov eax, 3
+        ; jmp ->exit
+    );
 
-    let buffer = ops
+    let buffer = cg
+        .ops
         .finalize()
         .map_err(|e| format!("Assembler finalize error: {:?}", e))?;
+    Ok(LibcMain {
+        code: buffer.to_vec(),
+        relocs: cg.relocs,
+        map: cg.map,
+    })
+}
+
+/// Version of the code `compile_with` generates. Bump it whenever the
+/// generated code changes in a way nothing in `codegen_fingerprint` reflects.
+const CODEGEN_VERSION: u32 = 1;
 
-    Ok((buffer, start))
+/// What cached code depends on besides the program and its config: the code
+/// generator version and the `State` layout and constants baked into the code.
+fn codegen_fingerprint() -> Vec<u8> {
+    [
+        CODEGEN_VERSION as i32,
+        STATE_OUT_CURSOR,
+        STATE_OUT_END,
+        STATE_IN_CURSOR,
+        STATE_IN_END,
+        STATE_CANCEL,
+        STATE_COUNTERS,
+        STATE_POLL_COUNTDOWN,
+        POLL_INTERVAL as i32,
+    ]
+    .iter()
+    .flat_map(|value| value.to_le_bytes())
+    .collect()
 }
 
-pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
-    let (exe_buf, start) = compile(prog)?;
+/// Compile `prog`, going through `cache` when there is one.
+fn compile_cached(
+    prog: &[u8],
+    bounds_checked: bool,
+    instrumentation: Instrumentation,
+    cache: Option<&CodeCache>,
+) -> Result<Compiled, Box<dyn error::Error>> {
+    let Some(cache) = cache else {
+        return compile_with(&parse(prog)?, bounds_checked, instrumentation);
+    };
+    let mut config = vec![
+        bounds_checked as u8,
+        instrumentation.cancellable as u8,
+        instrumentation.profiled as u8,
+    ];
+    config.extend(codegen_fingerprint());
+    let key = code_cache::key(prog, &config);
+    if let Some(cached) = cache.load(key) {
+        return Compiled::from_cached(cached);
+    }
+    let compiled = compile_with(&parse(prog)?, bounds_checked, instrumentation)?;
+    if let Err(e) = cache.store(key, &compiled.to_cached()) {
+        eprintln!("Failed to write JIT code cache: {e}");
+    }
+    Ok(compiled)
+}
+
+/// Run a bf program, optionally with bounds checks and a code cache.
+pub fn run_with(
+    prog: &[u8],
+    bounds_checked: bool,
+    cache: Option<&CodeCache>,
+) -> Result<(), Box<dyn error::Error>> {
+    execute(prog, bounds_checked, cache, None, None)
+}
+
+/// Run a bf program until it finishes or `cancel` is set.
+pub fn run_cancellable(
+    prog: &[u8],
+    bounds_checked: bool,
+    cancel: &CancelToken,
+) -> Result<(), Box<dyn error::Error>> {
+    execute(prog, bounds_checked, None, Some(cancel), None)
+}
+
+/// Run a bf program counting how often every loop is entered and iterates.
+///
+/// `profile` is filled in even when the program fails.
+pub fn run_profiled(
+    prog: &[u8],
+    bounds_checked: bool,
+    cache: Option<&CodeCache>,
+    profile: &mut Profile,
+) -> Result<(), Box<dyn error::Error>> {
+    let (bf_ops, spans) = parse_spanned(prog)?;
+    let loops = bf_ops.iter().filter(|op| matches!(op, Ops::LBrack)).count();
+    let mut counters = vec![0; 2 * loops];
+    let res = execute(prog, bounds_checked, cache, None, Some(&mut counters));
+    *profile = Profile::from_counters(prog, &bf_ops, &spans, &counters);
+    res
+}
+
+/// Run a bf program, polling `cancel` and counting loops in `counters` when
+/// they are given.
+fn execute(
+    prog: &[u8],
+    bounds_checked: bool,
+    cache: Option<&CodeCache>,
+    cancel: Option<&CancelToken>,
+    counters: Option<&mut [u64]>,
+) -> Result<(), Box<dyn error::Error>> {
+    // Cached code has no code map to derive symbols and line tables from.
+    let tooling = perf::enabled() || gdb::enabled();
+    let cache = cache.filter(|_| !tooling);
+    let instrumentation = Instrumentation {
+        cancellable: cancel.is_some(),
+        profiled: counters.is_some(),
+    };
+    let mut compiled = compile_cached(prog, bounds_checked, instrumentation, cache)?;
+    if tooling {
+        let (_, spans) = parse_spanned(prog)?;
+        compiled.register(prog, &spans, "bf_main")?;
+    }
     let mut state = State::new(
         Box::new(BufReader::new(stdin())),
         Box::new(BufWriter::new(stdout())),
     );
-    let mut cells: [u8; BF_MEMORY_SIZE] = [0; BF_MEMORY_SIZE];
-    let cp = cells.as_mut_ptr();
+    if let Some(cancel) = cancel {
+        state.cancel = cancel.as_ptr();
+    }
+    if let Some(counters) = counters {
+        state.counters = counters.as_mut_ptr();
+    }
+    let mut tape = GuardedTape::new(BF_MEMORY_SIZE, compiled.max_move)?;
 
-    let f: extern "win64" fn(*mut State, *mut u8) -> u8 =
-        unsafe { mem::transmute(exe_buf.ptr(start)) };
+    let res = compiled.call(&mut state, &mut tape, 0);
+    state.output.flush()?;
+    res.map(|_| ())
+}
 
-    let res = f(&mut state, cp);
+/// Run a bf program, relying on guard pages to catch stray accesses.
+///
+/// The guarded tape is rounded up to whole pages, so cells past
+/// `BF_MEMORY_SIZE` up to the end of the last page are usable and only a
+/// pointer beyond them is reported.
+pub fn run(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_with(prog, false, None)
+}
 
-    if res == 0 {
-        Ok(())
-    } else if res == 1 {
-        Err("IO error".into())
-    } else {
-        Err(format!("Unknown Error: {res}").into())
-    }
+/// Run a bf program with explicit bounds checks compiled into the code.
+pub fn run_bounds_checked(prog: &[u8]) -> Result<(), Box<dyn error::Error>> {
+    run_with(prog, true, None)
 }
 
 #[cfg(test)]
@@ -270,4 +1191,161 @@ mod tests {
     fn hello_hell() {
         assert!(test_hell(&run).is_ok());
     }
+    #[test]
+    fn hello_bounds_checked() {
+        assert!(test_run(&run_bounds_checked).is_ok());
+    }
+    #[test]
+    fn hello_hell_bounds_checked() {
+        assert!(test_hell(&run_bounds_checked).is_ok());
+    }
+    #[test]
+    fn bounds_checked_left() {
+        let err = run_bounds_checked(b"+[<+]").unwrap_err();
+        assert_eq!(err.to_string(), "Out of bounds");
+    }
+    #[test]
+    fn bounds_checked_right() {
+        let err = run_bounds_checked(b"+[>+]").unwrap_err();
+        assert_eq!(err.to_string(), "Out of bounds");
+    }
+    #[test]
+    fn cached_code_runs() {
+        let dir = std::env::temp_dir().join(format!("bf-jit-x64-cache-{}", std::process::id()));
+        // The first run compiles and stores, the second loads and patches.
+        for _ in 0..2 {
+            let cache = CodeCache::new(dir.clone());
+            assert!(test_run(&move |prog| run_with(prog, false, Some(&cache))).is_ok());
+        }
+        CodeCache::new(dir).clear().unwrap();
+    }
+    #[test]
+    fn cached_code_is_position_independent() {
+        let compiled = compile(&parse(b"+[.-]").unwrap(), true).unwrap();
+        let cached = compiled.to_cached();
+        assert_eq!(cached.relocs.len(), 1);
+        let reloaded = Compiled::from_cached(cached.clone()).unwrap();
+        assert_eq!(reloaded.to_cached(), cached);
+    }
+    #[test]
+    fn out_of_bounds_left() {
+        let err = run(b"+[<+]").unwrap_err();
+        let err = err.downcast_ref::<guarded_tape::MemoryError>().unwrap();
+        assert_eq!(err.pointer, -1);
+    }
+    #[test]
+    fn out_of_bounds_right() {
+        let err = run(b"+[>+]").unwrap_err();
+        assert!(
+            err.downcast_ref::<guarded_tape::MemoryError>()
+                .unwrap()
+                .pointer
+                >= BF_MEMORY_SIZE as isize
+        );
+    }
+    #[test]
+    fn out_of_bounds_right_at_the_page_end() {
+        let usable = GuardedTape::new(BF_MEMORY_SIZE, 1)
+            .unwrap()
+            .cells_mut()
+            .len();
+        assert!(usable > BF_MEMORY_SIZE);
+        let err = run(b"+[>+]").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<guarded_tape::MemoryError>()
+                .unwrap()
+                .pointer,
+            usable as isize
+        );
+    }
+    #[test]
+    fn out_of_bounds_output() {
+        let err = run(b"<<<<.").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<guarded_tape::MemoryError>()
+                .unwrap()
+                .pointer,
+            -4
+        );
+    }
+
+    fn run_buffered(prog: &[u8], input: &[u8]) -> (Result<usize, Box<dyn error::Error>>, Vec<u8>) {
+        let compiled = compile(&parse(prog).unwrap(), false).unwrap();
+        let mut output = vec![];
+        let mut state = State::new(Box::new(input), Box::new(&mut output));
+        let mut tape = GuardedTape::new(BF_MEMORY_SIZE, compiled.max_move).unwrap();
+        let res = compiled.call(&mut state, &mut tape, 0);
+        drop(state);
+        (res, output)
+    }
+    #[test]
+    fn buffered_io_spans_many_buffers() {
+        let input: Vec<u8> = (0..3 * IO_BUF_SIZE + 7).map(|i| i as u8).collect();
+        let prog = b",.".repeat(input.len());
+        let (res, output) = run_buffered(&prog, &input);
+        assert!(res.is_ok());
+        assert_eq!(output, input);
+    }
+    #[test]
+    fn output_is_flushed_on_error() {
+        let (res, output) = run_buffered(b"+.,", b"");
+        assert_eq!(res.unwrap_err().to_string(), "IO error");
+        assert_eq!(output, [1]);
+        let (res, output) = run_buffered(b"++.<<.", b"");
+        assert!(res.unwrap_err().is::<guarded_tape::MemoryError>());
+        assert_eq!(output, [2]);
+    }
+    #[test]
+    fn cancelled_loop_stops_with_output_flushed() {
+        let prog = b"+++++[>+++++++++++++<-]>.+[]";
+        let instrumentation = Instrumentation {
+            cancellable: true,
+            profiled: false,
+        };
+        let compiled = compile_with(&parse(prog).unwrap(), false, instrumentation).unwrap();
+        let token = CancelToken::new();
+        token.cancel();
+        let mut output = vec![];
+        let mut state = State::new(Box::new(&b""[..]), Box::new(&mut output));
+        state.cancel = token.as_ptr();
+        let mut tape = GuardedTape::new(BF_MEMORY_SIZE, compiled.max_move).unwrap();
+        let res = compiled.call(&mut state, &mut tape, 0);
+        drop(state);
+        let err = res.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Cancelled>(),
+            Some(&Cancelled { pointer: 1 })
+        );
+        assert_eq!(output, b"A");
+    }
+    #[test]
+    fn cancel_from_another_thread() {
+        let token = CancelToken::new();
+        let canceller = token.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            canceller.cancel();
+        });
+        let err = run_cancellable(b">>+[]", false, &token).unwrap_err();
+        thread.join().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Cancelled>(),
+            Some(&Cancelled { pointer: 2 })
+        );
+    }
+    #[test]
+    fn profiled_code_counts_entries_and_back_edges() {
+        let prog = b"++[>+++[>+<-]<-]";
+        let instrumentation = Instrumentation {
+            cancellable: false,
+            profiled: true,
+        };
+        let compiled = compile_with(&parse(prog).unwrap(), false, instrumentation).unwrap();
+        let mut counters = [0; 4];
+        let mut state = State::new(Box::new(&b""[..]), Box::new(io::sink()));
+        state.counters = counters.as_mut_ptr();
+        let mut tape = GuardedTape::new(BF_MEMORY_SIZE, compiled.max_move).unwrap();
+        assert!(compiled.call(&mut state, &mut tape, 0).is_ok());
+        assert_eq!(counters, [1, 1, 2, 4]);
+    }
 }
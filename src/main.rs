@@ -3,18 +3,30 @@ use std::{error, fs};
 #[macro_use]
 extern crate lazy_static;
 pub mod bf_types;
+pub mod cancel;
 pub mod interpreter;
 pub mod jit;
+pub mod native;
 pub mod tests;
 pub mod transpiler;
 
-use clap::Parser;
+use clap::builder::PossibleValuesParser;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use interpreter::interp::OptimizationLevel;
+use jit::code_cache::CodeCache;
+use transpiler::backend::{self, Backend, OptionSpec};
+use transpiler::ir::{CellWidth, EofMode};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// Set the running mode (int, src, jit)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Set the running mode (int, src, jit, jit-checked, jit-cranelift, tiered,
+    /// aot-c, or bf2<target> for a target of `--target list`)
     #[arg(short, long, default_value_t =String::from("jit"))]
     mode: String,
 
@@ -23,18 +35,268 @@ struct Cli {
     output: Option<PathBuf>,
 
     /// Set the brainfuck file to run
-    #[arg(short, long)]
-    input: PathBuf,
+    #[arg(short, long, required_unless_present = "target")]
+    input: Option<PathBuf>,
+
+    /// Transpile to a target language, the same as `-m bf2<target>`;
+    /// `list` shows the targets and what they support
+    #[arg(long)]
+    target: Option<String>,
+
+    /// Always compile from scratch instead of using the JIT code cache
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Write the generated code to the output location (or stdout) instead
+    /// of running it (jit and jit-checked modes)
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
+
+    /// Write symbols for JIT code to /tmp/perf-<pid>.map for `perf report`
+    #[arg(long)]
+    perf_map: bool,
+
+    /// Write JIT code and its symbols to /tmp/jit-<pid>.dump for
+    /// `perf inject --jit`
+    #[arg(long)]
+    jitdump: bool,
+
+    /// Register JIT code with GDB, with line tables for the brainfuck file
+    #[arg(long)]
+    debug_info: bool,
+
+    /// Count loop iterations and print the hottest loops to stderr at exit
+    /// (jit and jit-checked modes)
+    #[arg(long)]
+    profile: bool,
+
+    /// Write the loop profile of every loop to a JSON file
+    #[arg(long, value_name = "FILE")]
+    profile_json: Option<PathBuf>,
+
+    /// Number of tape cells of transpiled programs
+    #[arg(long, default_value_t = bf_types::BF_MEMORY_SIZE)]
+    tape_size: usize,
+
+    /// Cell width in bits of transpiled programs
+    #[arg(long, value_enum, default_value = "8")]
+    cell_width: CellWidth,
+
+    /// What `,` stores at the end of input in transpiled programs
+    #[arg(long, value_enum, default_value = "unchanged")]
+    eof: EofMode,
+
+    /// C compiler of aot-c mode
+    #[arg(long, default_value = "cc")]
+    cc: String,
+
+    /// Flags passed to the C compiler in aot-c mode, separated by spaces
+    #[arg(long, default_value = "-O2", allow_hyphen_values = true)]
+    cflags: String,
+}
+
+/// Number of loops in the `--profile` report.
+const PROFILE_TOP_LOOPS: usize = 10;
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Emit {
+    /// Disassembly of the JIT code, annotated with the source of every op
+    Asm,
+    /// AT&T syntax assembly source of a C `main` function, for `as` or `cc`
+    Gas,
+    /// `gas` in Intel syntax
+    GasIntel,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compile a brainfuck file into a standalone x86-64 Linux executable or
+    /// object file
+    Build {
+        /// The brainfuck file to compile
+        input: PathBuf,
+
+        /// Where to write the executable
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Write a relocatable object exporting `bf_main` and a C header
+        /// next to it instead of an executable
+        #[arg(long)]
+        object: bool,
+    },
+
+    /// Manage the JIT code cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Remove all cached code
+    Clear,
+}
+
+/// The command line arguments: those of `Cli` and the options of every
+/// backend.
+fn command() -> clap::Command {
+    backend::BACKENDS
+        .iter()
+        .flat_map(|backend| backend.options())
+        .fold(Cli::command(), |command, spec| {
+            command.arg(option_arg(spec))
+        })
+}
+
+fn option_arg(spec: OptionSpec) -> Arg {
+    let arg = Arg::new(spec.name)
+        .long(spec.name)
+        .value_name(spec.name.to_uppercase().replace('-', "_"))
+        .help(spec.help)
+        .default_value(spec.default);
+    if spec.values.is_empty() {
+        arg
+    } else {
+        arg.value_parser(PossibleValuesParser::new(spec.values))
+    }
+}
+
+/// The backend options given on the command line. The target rejects those
+/// it does not take.
+fn backend_options(matches: &ArgMatches) -> backend::Options {
+    let mut options = backend::Options::default();
+    for spec in backend::BACKENDS
+        .iter()
+        .flat_map(|backend| backend.options())
+    {
+        if matches.value_source(spec.name) == Some(ValueSource::CommandLine)
+            && let Some(value) = matches.get_one::<String>(spec.name)
+        {
+            options.set(spec.name, value.as_str());
+        }
+    }
+    options
 }
 
 fn main() -> Result<(), Box<dyn error::Error>> {
-    let cli = Cli::parse();
+    let matches = command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    match cli.command {
+        Some(Command::Build {
+            input,
+            output,
+            object,
+        }) => {
+            let prog = fs::read(input)?;
+            if object {
+                native::build_object(&prog, &output, &output.with_extension("h"))?;
+            } else {
+                native::build_executable(&prog, &output)?;
+            }
+            return Ok(());
+        }
+        Some(Command::Cache { action }) => {
+            let cache = CodeCache::open_default().ok_or("No cache directory found")?;
+            match action {
+                CacheAction::Clear => cache.clear()?,
+            }
+            return Ok(());
+        }
+        None => (),
+    }
+
+    let target: Option<&dyn Backend> = match cli.target.as_deref() {
+        Some("list") => {
+            print!("{}", backend::list());
+            return Ok(());
+        }
+        Some(name) => Some(backend::find(name).ok_or(format!("Unknown target: {name}"))?),
+        None => cli.mode.strip_prefix("bf2").and_then(backend::find),
+    };
 
     let mode = cli.mode;
+    let transpile_config = transpiler::ir::Config {
+        tape_size: cli.tape_size,
+        cell_width: cli.cell_width,
+        eof: cli.eof,
+    };
+
+    let bf_file = cli.input.ok_or("Input file required")?;
+    let cache = if cli.no_cache {
+        None
+    } else {
+        CodeCache::open_default()
+    };
 
-    let bf_file = cli.input;
+    let prog: Vec<u8> = fs::read(&bf_file)?;
+
+    #[cfg(target_os = "linux")]
+    if cli.perf_map || cli.jitdump {
+        jit::perf::enable(cli.perf_map, cli.jitdump)?;
+    }
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    if cli.debug_info {
+        jit::gdb::enable(&bf_file)?;
+    }
 
-    let prog: Vec<u8> = fs::read(bf_file)?;
+    if let Some(target) = target {
+        let name = target.name();
+        let output_file = cli
+            .output
+            .ok_or(format!("Output file required for bf2{name} mode"))?;
+        let source_name = bf_file.file_name().unwrap_or_default().to_string_lossy();
+        let options = backend_options(&matches);
+        let request = backend::Request {
+            src: &prog,
+            source_name: &source_name,
+            config: &transpile_config,
+            options: &options,
+            output: &output_file,
+        };
+        for artifact in backend::transpile(target, &request)? {
+            fs::write(&artifact.path, &artifact.contents)?;
+        }
+        println!("Transpiled to {}: {:?}", target.language(), output_file);
+        return Ok(());
+    }
+
+    if let Some(emit) = cli.emit {
+        let bounds_checked = match mode.as_str() {
+            "jit" => false,
+            "jit-checked" => true,
+            _ => return Err(format!("--emit is not supported in {mode} mode").into()),
+        };
+        let text = match emit {
+            Emit::Asm => jit::disassemble(&prog, bounds_checked)?,
+            Emit::Gas => jit::emit_gas(&prog, bounds_checked, jit::GasSyntax::Att)?,
+            Emit::GasIntel => jit::emit_gas(&prog, bounds_checked, jit::GasSyntax::Intel)?,
+        };
+        match cli.output {
+            Some(path) => fs::write(path, text)?,
+            None => print!("{text}"),
+        }
+        return Ok(());
+    }
+
+    if cli.profile || cli.profile_json.is_some() {
+        let bounds_checked = match mode.as_str() {
+            "jit" => false,
+            "jit-checked" => true,
+            _ => return Err(format!("--profile is not supported in {mode} mode").into()),
+        };
+        let mut profile = jit::profile::Profile::default();
+        let res = jit::run_profiled(&prog, bounds_checked, cache.as_ref(), &mut profile);
+        if cli.profile {
+            eprint!("{}", profile.report(PROFILE_TOP_LOOPS));
+        }
+        if let Some(path) = cli.profile_json {
+            fs::write(path, profile.to_json())?;
+        }
+        return res;
+    }
 
     match mode.as_str() {
         "int" => {
@@ -56,30 +318,44 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             interpreter::interp::run(&prog, Some(OptimizationLevel::High))?;
         }
         "jit" => {
-            jit::run(&prog)?;
-        }
-        "bf2c" => {
-            let output_file = cli.output.ok_or("Output file required for bf2c mode")?;
-            transpiler::bf2c::transpile_to_file(
-                &prog,
-                output_file.to_str().ok_or("Invalid output file path")?,
-            )
-            .expect("Failed to transpile Brainfuck to C");
-            println!("Transpiled to C: {:?}", output_file);
-        }
-        "bf2js" => {
-            let output_file = cli.output.ok_or("Output file required for bf2js mode")?;
-            transpiler::bf2js::transpile_to_file(
-                &prog,
-                output_file.to_str().ok_or("Invalid output file path")?,
-            )
-            .expect("Failed to transpile Brainfuck to JavaScript");
-            println!("Transpiled to JavaScript: {:?}", output_file);
-        }
-        _ => panic!("Unknown mode: {}", mode),
+            jit::run_with(&prog, false, cache.as_ref())?;
+        }
+        "jit-checked" => {
+            jit::run_with(&prog, true, cache.as_ref())?;
+        }
+        "tiered" => {
+            jit::run_tiered(&prog)?;
+        }
+        "jit-cranelift" => {
+            jit::run_cranelift(&prog)?;
+        }
+        "aot-c" => {
+            let compiler = transpiler::aot_c::Compiler {
+                cc: cli.cc,
+                flags: cli.cflags.split_whitespace().map(String::from).collect(),
+            };
+            // Compiler diagnostics are only readable printed as they are.
+            if let Err(e) =
+                transpiler::aot_c::run(&prog, &transpile_config, &compiler, cache.as_ref())
+            {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        _ => return Err(format!("Unknown mode: {mode}").into()),
     }
 
     // benchmarks(&prog)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+
+    #[test]
+    fn backend_options_fit_the_command_line() {
+        command().debug_assert();
+    }
+}
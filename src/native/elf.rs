@@ -0,0 +1,321 @@
+//! Minimal ELF64 writer for x86-64 Linux.
+
+/// Where the single load segment of an executable is mapped.
+pub const BASE_ADDR: u64 = 0x40_0000;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_R: u32 = 4;
+
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHF_ALLOC: u64 = 2;
+pub const SHF_EXECINSTR: u64 = 4;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+fn push_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, v: u64) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+/// `e_ident` plus the fields shared by every header we write.
+fn ehdr(
+    out: &mut Vec<u8>,
+    e_type: u16,
+    entry: u64,
+    phnum: u16,
+    shoff: u64,
+    shnum: u16,
+    shstrndx: u16,
+) {
+    out.extend_from_slice(b"\x7fELF");
+    out.push(2); // ELFCLASS64
+    out.push(1); // ELFDATA2LSB
+    out.push(1); // EV_CURRENT
+    out.push(0); // ELFOSABI_SYSV
+    out.extend_from_slice(&[0; 8]);
+    push_u16(out, e_type);
+    push_u16(out, EM_X86_64);
+    push_u32(out, 1);
+    push_u64(out, entry);
+    push_u64(out, if phnum > 0 { EHDR_SIZE as u64 } else { 0 });
+    push_u64(out, shoff);
+    push_u32(out, 0);
+    push_u16(out, EHDR_SIZE as u16);
+    push_u16(out, if phnum > 0 { PHDR_SIZE as u16 } else { 0 });
+    push_u16(out, phnum);
+    push_u16(out, 64);
+    push_u16(out, shnum);
+    push_u16(out, shstrndx);
+}
+
+/// A static executable whose only segment holds the headers and `code`.
+///
+/// `entry` is the offset of the entry point in `code`. The code has to be
+/// position independent and must never return from its entry point.
+pub fn executable(code: &[u8], entry: usize) -> Vec<u8> {
+    let code_offset = EHDR_SIZE + PHDR_SIZE;
+    let file_size = (code_offset + code.len()) as u64;
+
+    let mut out = Vec::with_capacity(file_size as usize);
+    ehdr(
+        &mut out,
+        ET_EXEC,
+        BASE_ADDR + (code_offset + entry) as u64,
+        1,
+        0,
+        0,
+        0,
+    );
+    push_u32(&mut out, PT_LOAD);
+    push_u32(&mut out, PF_R | PF_X);
+    push_u64(&mut out, 0);
+    push_u64(&mut out, BASE_ADDR);
+    push_u64(&mut out, BASE_ADDR);
+    push_u64(&mut out, file_size);
+    push_u64(&mut out, file_size);
+    push_u64(&mut out, 0x1000);
+    out.extend_from_slice(code);
+    out
+}
+
+/// A section of an object file. `name` and the header fields are written as
+/// is; offsets are filled in by `object`.
+pub struct Section {
+    pub name: &'static str,
+    pub kind: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub data: Vec<u8>,
+    pub link: u32,
+    pub info: u32,
+    pub align: u64,
+    pub entsize: u64,
+}
+
+impl Section {
+    pub fn new(name: &'static str, kind: u32, flags: u64, data: Vec<u8>) -> Section {
+        Section {
+            name,
+            kind,
+            flags,
+            addr: 0,
+            data,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        }
+    }
+}
+
+/// A symbol in `.symtab`.
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// Index of the section the symbol is defined in.
+    pub section: u16,
+    pub value: u64,
+    pub size: u64,
+    pub global: bool,
+}
+
+/// Build `.symtab` and `.strtab` for a section index `section` and `symbols`.
+///
+/// The symbol table starts with the null symbol and a section symbol, and
+/// expects `symbols` to list locals before globals, as ELF requires. The
+/// returned `.symtab` links to the `.strtab` at section index `strtab`.
+pub fn symbol_table(section: u16, symbols: &[Symbol], strtab: u32) -> (Section, Section) {
+    let mut syms = vec![0; SYM_SIZE];
+    let mut strs = vec![0];
+    let mut first_global = None;
+
+    let push = |syms: &mut Vec<u8>, name: u32, info: u8, shndx: u16, value: u64, size: u64| {
+        push_u32(syms, name);
+        syms.push(info);
+        syms.push(0);
+        push_u16(syms, shndx);
+        push_u64(syms, value);
+        push_u64(syms, size);
+    };
+    push(&mut syms, 0, STB_LOCAL << 4 | STT_SECTION, section, 0, 0);
+    for (i, sym) in symbols.iter().enumerate() {
+        let name = strs.len() as u32;
+        strs.extend_from_slice(sym.name.as_bytes());
+        strs.push(0);
+        let bind = if sym.global { STB_GLOBAL } else { STB_LOCAL };
+        if sym.global && first_global.is_none() {
+            first_global = Some(i as u32 + 2);
+        }
+        push(
+            &mut syms,
+            name,
+            bind << 4 | STT_FUNC,
+            sym.section,
+            sym.value,
+            sym.size,
+        );
+    }
+
+    let mut symtab = Section::new(".symtab", SHT_SYMTAB, 0, syms);
+    symtab.link = strtab;
+    symtab.info = first_global.unwrap_or(symbols.len() as u32 + 2);
+    symtab.align = 8;
+    symtab.entsize = SYM_SIZE as u64;
+    (symtab, Section::new(".strtab", SHT_STRTAB, 0, strs))
+}
+
+/// Write an ELF file of type `e_type` containing `sections`, after the null
+/// section and before a generated `.shstrtab`.
+fn object(e_type: u16, entry: u64, sections: &[Section]) -> Vec<u8> {
+    let mut shstrtab = vec![0];
+    let mut names = vec![];
+    for section in sections.iter().map(|s| s.name).chain([".shstrtab"]) {
+        names.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(section.as_bytes());
+        shstrtab.push(0);
+    }
+
+    let mut body = vec![];
+    let mut offsets = vec![];
+    for data in sections.iter().map(|s| &s.data[..]).chain([&shstrtab[..]]) {
+        body.resize((EHDR_SIZE + body.len()).next_multiple_of(16) - EHDR_SIZE, 0);
+        offsets.push((EHDR_SIZE + body.len()) as u64);
+        body.extend_from_slice(data);
+    }
+    body.resize((EHDR_SIZE + body.len()).next_multiple_of(8) - EHDR_SIZE, 0);
+    let shoff = (EHDR_SIZE + body.len()) as u64;
+    let shnum = sections.len() + 2;
+
+    let mut out = Vec::with_capacity(shoff as usize + shnum * SHDR_SIZE);
+    ehdr(
+        &mut out,
+        e_type,
+        entry,
+        0,
+        shoff,
+        shnum as u16,
+        shnum as u16 - 1,
+    );
+    out.extend_from_slice(&body);
+
+    out.extend_from_slice(&[0; SHDR_SIZE]);
+    for (i, section) in sections.iter().enumerate() {
+        push_u32(&mut out, names[i]);
+        push_u32(&mut out, section.kind);
+        push_u64(&mut out, section.flags);
+        push_u64(&mut out, section.addr);
+        push_u64(&mut out, offsets[i]);
+        push_u64(&mut out, section.data.len() as u64);
+        push_u32(&mut out, section.link);
+        push_u32(&mut out, section.info);
+        push_u64(&mut out, section.align);
+        push_u64(&mut out, section.entsize);
+    }
+    push_u32(&mut out, names[sections.len()]);
+    push_u32(&mut out, SHT_STRTAB);
+    push_u64(&mut out, 0);
+    push_u64(&mut out, 0);
+    push_u64(&mut out, offsets[sections.len()]);
+    push_u64(&mut out, shstrtab.len() as u64);
+    push_u64(&mut out, 0);
+    push_u64(&mut out, 1);
+    push_u64(&mut out, 0);
+    out
+}
+
+/// A relocatable object whose `.text` is `code`, exporting `symbol` at
+/// offset `entry` and spanning the rest of the code.
+pub fn relocatable(code: &[u8], symbol: &str, entry: usize) -> Vec<u8> {
+    let mut text = Section::new(
+        ".text",
+        SHT_PROGBITS,
+        SHF_ALLOC | SHF_EXECINSTR,
+        code.to_vec(),
+    );
+    text.align = 16;
+    // Marks the stack as non-executable for the linker.
+    let note = Section::new(".note.GNU-stack", SHT_PROGBITS, 0, vec![]);
+    let (symtab, strtab) = symbol_table(
+        1,
+        &[Symbol {
+            name: symbol,
+            section: 1,
+            value: entry as u64,
+            size: (code.len() - entry) as u64,
+            global: true,
+        }],
+        4,
+    );
+    object(ET_REL, 0, &[text, note, symtab, strtab])
+}
+
+/// An object describing `code` that is already loaded at `addr`, for the
+/// debugger's JIT interface.
+///
+/// Section addresses and symbol values are absolute, so nothing needs to be
+/// relocated. `symbols` are defined in `.text` and `debug` sections such as
+/// `.debug_line` are appended as is.
+pub fn debug_object(code: &[u8], addr: u64, symbols: &[Symbol], debug: Vec<Section>) -> Vec<u8> {
+    let mut text = Section::new(
+        ".text",
+        SHT_PROGBITS,
+        SHF_ALLOC | SHF_EXECINSTR,
+        code.to_vec(),
+    );
+    text.addr = addr;
+    text.align = 16;
+    let (symtab, strtab) = symbol_table(1, symbols, 3);
+    let mut sections = vec![text, symtab, strtab];
+    sections.extend(debug);
+    object(ET_EXEC, addr, &sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn executable_layout() {
+        let elf = executable(&[0x90, 0xcc], 1);
+        assert_eq!(&elf[..4], b"\x7fELF");
+        assert_eq!(elf.len(), EHDR_SIZE + PHDR_SIZE + 2);
+        let entry = u64::from_le_bytes(elf[24..32].try_into().unwrap());
+        assert_eq!(entry, BASE_ADDR + (EHDR_SIZE + PHDR_SIZE + 1) as u64);
+        assert_eq!(&elf[EHDR_SIZE + PHDR_SIZE..], &[0x90, 0xcc]);
+    }
+
+    #[test]
+    fn relocatable_layout() {
+        let obj = relocatable(&[0x90, 0xc3], "bf_main", 1);
+        assert_eq!(u16::from_le_bytes([obj[16], obj[17]]), ET_REL);
+        let shoff = u64::from_le_bytes(obj[40..48].try_into().unwrap()) as usize;
+        let shnum = u16::from_le_bytes([obj[60], obj[61]]) as usize;
+        assert_eq!(shnum, 6);
+        assert_eq!(obj.len(), shoff + shnum * SHDR_SIZE);
+        // .text comes right after the null section header.
+        let text = &obj[shoff + SHDR_SIZE..shoff + 2 * SHDR_SIZE];
+        let offset = u64::from_le_bytes(text[24..32].try_into().unwrap()) as usize;
+        assert_eq!(&obj[offset..offset + 2], &[0x90, 0xc3]);
+    }
+}
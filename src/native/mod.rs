@@ -0,0 +1,177 @@
+pub mod elf;
+
+use std::error;
+use std::fs;
+use std::path::Path;
+
+/// Compile `prog` into a static x86-64 Linux executable at `output`.
+///
+/// The executable needs neither libc nor any other toolchain: it maps its own
+/// tape, does I/O through raw `read`/`write` syscalls and exits with status 3
+/// if the program leaves the tape.
+#[cfg(target_arch = "x86_64")]
+pub fn build_executable(prog: &[u8], output: &Path) -> Result<(), Box<dyn error::Error>> {
+    let (code, entry) = crate::jit::compile_standalone(prog)?;
+    fs::write(output, elf::executable(&code, entry))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(output, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// C header describing the ABI of the `bf_main` an object file exports.
+pub fn object_header() -> String {
+    format!(
+        r#"/* Generated by bf-jit-rs. */
+#ifndef BF_MAIN_H
+#define BF_MAIN_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {{
+#endif
+
+/* Number of cells the tape passed to bf_main must hold. */
+#define BF_TAPE_SIZE {tape_size}
+
+/* bf_main return values. */
+#define BF_OK 0
+#define BF_IO_ERROR 1
+#define BF_OUT_OF_BOUNDS 3
+
+/* Return the next input byte (0-255), or a negative value on EOF or error. */
+typedef int (*bf_getc_fn)(void *ctx);
+
+/* Write one output byte and return 0, or return non-zero on error. */
+typedef int (*bf_putc_fn)(uint8_t c, void *ctx);
+
+/* Run the program on a zeroed tape of BF_TAPE_SIZE cells, which it is free
+ * to modify. ctx is passed through to the callbacks unchanged. */
+int bf_main(uint8_t *tape, bf_getc_fn getc, bf_putc_fn putc, void *ctx);
+
+#ifdef __cplusplus
+}}
+#endif
+
+#endif /* BF_MAIN_H */
+"#,
+        tape_size = crate::bf_types::BF_MEMORY_SIZE
+    )
+}
+
+/// Compile `prog` into a relocatable object at `output` exporting `bf_main`,
+/// and write the matching C header to `header`.
+#[cfg(target_arch = "x86_64")]
+pub fn build_object(
+    prog: &[u8],
+    output: &Path,
+    header: &Path,
+) -> Result<(), Box<dyn error::Error>> {
+    let (code, entry) = crate::jit::compile_object(prog)?;
+    fs::write(output, elf::relocatable(&code, "bf_main", entry))?;
+    fs::write(header, object_header())?;
+    Ok(())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn build_object(
+    _prog: &[u8],
+    _output: &Path,
+    _header: &Path,
+) -> Result<(), Box<dyn error::Error>> {
+    Err("Building object files requires an x86_64 host".into())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn build_executable(_prog: &[u8], _output: &Path) -> Result<(), Box<dyn error::Error>> {
+    Err("Building executables requires an x86_64 host".into())
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    fn build_and_run(prog: &[u8], input: &[u8]) -> std::process::Output {
+        let exe = std::env::temp_dir().join(format!(
+            "bf-native-test-{}-{}",
+            std::process::id(),
+            prog.len()
+        ));
+        build_executable(prog, &exe).unwrap();
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        fs::remove_file(&exe).unwrap();
+        output
+    }
+
+    #[test]
+    fn hello_executable() {
+        let output = build_and_run(
+            b"++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.",
+            b"",
+        );
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Hello World!\n");
+    }
+
+    #[test]
+    fn object_links_with_c() {
+        if Command::new("cc").arg("--version").output().is_err() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("bf-native-obj-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        build_object(b",[+.,]", &dir.join("echo.o"), &dir.join("bf_main.h")).unwrap();
+        fs::write(
+            dir.join("main.c"),
+            r#"#include <stdio.h>
+#include "bf_main.h"
+
+static const char *input = "HAL";
+static int get(void *ctx) { (void)ctx; return *input ? *input++ : -1; }
+static int put(uint8_t c, void *ctx) { (void)ctx; return putchar(c) == EOF; }
+
+int main(void) {
+    static uint8_t tape[BF_TAPE_SIZE];
+    return bf_main(tape, get, put, NULL) == BF_IO_ERROR ? 0 : 1;
+}
+"#,
+        )
+        .unwrap();
+        let status = Command::new("cc")
+            .current_dir(&dir)
+            .args(["-o", "main", "main.c", "echo.o"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new(dir.join("main")).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"IBM");
+    }
+
+    #[test]
+    fn echo_executable_reports_eof() {
+        let output = build_and_run(b",[.,]", b"abc");
+        assert_eq!(output.stdout, b"abc");
+        assert_eq!(output.status.code(), Some(1));
+    }
+
+    #[test]
+    fn executable_stops_at_the_tape_edge() {
+        let output = build_and_run(b"+.[<]", b"");
+        assert_eq!(output.stdout, b"\x01");
+        assert_eq!(output.status.code(), Some(3));
+    }
+}
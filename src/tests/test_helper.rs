@@ -1,6 +1,13 @@
 use std::fs::{self};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use stdio_override::StdoutOverride;
 
+use crate::transpiler::backend::{self, Artifact, Backend, Options, Request};
+use crate::transpiler::ir::Config;
+
 use std::sync::Mutex;
 
 lazy_static! {
@@ -9,7 +16,7 @@ lazy_static! {
 
 use std::error::Error;
 
-type RunFn = (dyn Fn(&[u8]) -> Result<(), Box<dyn Error>>);
+type RunFn = dyn Fn(&[u8]) -> Result<(), Box<dyn Error>>;
 
 pub fn test_run(run_func: &RunFn) -> Result<(), Box<dyn Error>> {
     let test_lock = TEST_MUTEX.lock().unwrap();
@@ -56,7 +63,7 @@ pub fn test_run(run_func: &RunFn) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-const HELLO_HELL_STR: &str = r#"[
+pub const HELLO_HELL_STR: &str = r#"[
     This routine is a demonstration of checking for the three cell sizes
     that are normal for Brainfuck. The demo code also checks for bugs
     that have been noted in various interpreters and compilers.
@@ -381,3 +388,125 @@ pub fn test_hell(run_func: &RunFn) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// Prints "Hello World!\n".
+pub const HELLO: &[u8] = b"++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.";
+
+/// Folds into every kind of op the transpilers emit: runs of `+-<>` at
+/// offsets, a clear, a multiply and a scan loop, a clear of two cells, a
+/// loop that is left as one, output and input.
+pub const FOLDED_OPS: &[u8] = b"+++>>--<[-]>[->+++<]>[<][-]>[-]>[.>>],";
+
+/// The lines of `code` from the first one that is `first` but for its
+/// indentation up to and including the next one that is `last` exactly, for
+/// comparing a transpiled program with its expected listing.
+pub fn lines_between<'a>(code: &'a str, first: &str, last: &str) -> Vec<&'a str> {
+    let lines: Vec<&str> = code
+        .lines()
+        .skip_while(|line| line.trim() != first)
+        .collect();
+    let end = lines
+        .iter()
+        .position(|line| *line == last)
+        .map_or(lines.len(), |i| i + 1);
+    lines[..end].to_vec()
+}
+
+/// How tests run the programs a transpiler backend writes.
+pub struct Runner {
+    /// Name of the main artifact; its extension may pick the format.
+    pub file: &'static str,
+    /// Options to test besides the defaults, one set per run.
+    pub variants: &'static [&'static [(&'static str, &'static str)]],
+    /// Run the program whose main artifact is at `path` on `input` and
+    /// return its output, or `None` if the tools it needs are missing.
+    pub run: fn(path: &Path, options: &Options, input: &[u8]) -> Option<Vec<u8>>,
+}
+
+/// Whether `tool` runs with `arg`, such as `--version`.
+pub fn has_tool(tool: &str, arg: &str) -> bool {
+    Command::new(tool).arg(arg).output().is_ok()
+}
+
+/// A new directory for the files of one test, named after `name`.
+pub fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "{name}-test-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run `command` on `input`, check that it succeeds and return its output.
+pub fn output_of(command: &mut Command, input: &[u8]) -> Vec<u8> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{command:?} failed");
+    output.stdout
+}
+
+/// `options` with the defaults of `backend` filled in.
+fn backend_options(backend: &dyn Backend, options: &[(&str, &str)]) -> Options {
+    let mut set = Options::default();
+    for (name, value) in options {
+        set.set(name, *value);
+    }
+    set.for_backend(backend).unwrap()
+}
+
+/// The artifacts `backend` writes for `src` to `output`, with `options` set.
+pub fn transpile(
+    backend: &dyn Backend,
+    src: &[u8],
+    config: &Config,
+    options: &[(&str, &str)],
+    output: &Path,
+) -> Vec<Artifact> {
+    let request = Request {
+        src,
+        source_name: "prog.bf",
+        config,
+        options: &backend_options(backend, options),
+        output,
+    };
+    backend::transpile(backend, &request).unwrap()
+}
+
+/// The main artifact `backend` writes for `src`, as text.
+pub fn transpile_text(
+    backend: &dyn Backend,
+    src: &[u8],
+    config: &Config,
+    options: &[(&str, &str)],
+) -> String {
+    let artifacts = transpile(backend, src, config, options, Path::new("prog"));
+    String::from_utf8(artifacts[0].contents.clone()).unwrap()
+}
+
+/// Transpile `src` with `backend` and run it with `runner` on `input`.
+pub fn run_transpiled(
+    backend: &dyn Backend,
+    runner: &Runner,
+    options: &[(&str, &str)],
+    src: &[u8],
+    config: &Config,
+    input: &[u8],
+) -> Option<Vec<u8>> {
+    let dir = temp_dir(&format!("bf2{}", backend.name()));
+    let path = dir.join(runner.file);
+    let artifacts = transpile(backend, src, config, options, &path);
+    for artifact in &artifacts {
+        fs::write(&artifact.path, &artifact.contents).unwrap();
+    }
+    let output = (runner.run)(&path, &backend_options(backend, options), input);
+    fs::remove_dir_all(&dir).unwrap();
+    output
+}
@@ -0,0 +1,290 @@
+//! Ahead-of-time compilation through the system C compiler.
+//!
+//! The program is transpiled with `bf2c`, compiled with `cc` (or whatever
+//! compiler is configured) and the executable is run with the standard
+//! streams of this process. Executables are kept in the code cache, keyed by
+//! the source, the tape config, the `bf2c` code generator version and the
+//! compiler, its version and its flags, so running the same program again
+//! skips the compiler.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::backend::{self, Options, Request};
+use super::bf2c;
+use super::ir::Config;
+use crate::jit::code_cache::{self, CodeCache};
+
+/// How to invoke the C compiler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compiler {
+    /// The compiler executable.
+    pub cc: String,
+    /// Flags passed before the source file.
+    pub flags: Vec<String>,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler {
+            cc: "cc".to_string(),
+            flags: vec!["-O2".to_string()],
+        }
+    }
+}
+
+impl Compiler {
+    /// The compiler and its flags as typed on a command line.
+    fn command_line(&self) -> String {
+        let mut line = self.cc.clone();
+        for flag in &self.flags {
+            line.push(' ');
+            line.push_str(flag);
+        }
+        line
+    }
+
+    /// What the compiler prints for `--version`, which changes when it is
+    /// upgraded. Empty when it does not run, in which case compiling fails
+    /// anyway.
+    fn version(&self) -> Vec<u8> {
+        Command::new(&self.cc)
+            .arg("--version")
+            .output()
+            .map(|output| output.stdout)
+            .unwrap_or_default()
+    }
+}
+
+/// The C compiler rejected the generated program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    /// The compiler and flags that were run.
+    pub command: String,
+    /// What the compiler printed to stderr.
+    pub stderr: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "C compiler `{}` failed", self.command)?;
+        match self.stderr.trim_end() {
+            "" => Ok(()),
+            stderr => write!(f, ":\n{stderr}"),
+        }
+    }
+}
+
+impl error::Error for CompileError {}
+
+/// Cache key of the executable for `prog`.
+fn key(prog: &[u8], config: &Config, compiler: &Compiler) -> u64 {
+    let mut key = b"aot-c\0".to_vec();
+    key.extend((config.tape_size as u64).to_le_bytes());
+    key.extend([config.cell_width.bits() as u8, config.eof as u8]);
+    key.extend(bf2c::CODEGEN_VERSION.to_le_bytes());
+    for part in [&compiler.cc].into_iter().chain(&compiler.flags) {
+        key.extend(part.as_bytes());
+        key.push(0);
+    }
+    key.extend(compiler.version());
+    code_cache::key(prog, &key)
+}
+
+/// Transpile `prog` to C and compile it into an executable at `output`.
+pub fn compile(
+    prog: &[u8],
+    config: &Config,
+    compiler: &Compiler,
+    output: &Path,
+) -> Result<(), Box<dyn error::Error>> {
+    let c_file = output.with_extension("c");
+    let request = Request {
+        src: prog,
+        source_name: "",
+        config,
+        options: &Options::default(),
+        output: &c_file,
+    };
+    for artifact in backend::transpile(&bf2c::CBackend, &request)? {
+        fs::write(&artifact.path, &artifact.contents)?;
+    }
+    let result = Command::new(&compiler.cc)
+        .args(&compiler.flags)
+        .arg("-o")
+        .arg(output)
+        .arg(&c_file)
+        .output();
+    let _ = fs::remove_file(&c_file);
+    let result = result.map_err(|e| format!("Could not run C compiler `{}`: {e}", compiler.cc))?;
+    if !result.status.success() {
+        return Err(CompileError {
+            command: compiler.command_line(),
+            stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The executable for `prog`, from `cache` if it has one, compiled and
+/// stored there otherwise.
+pub fn executable(
+    prog: &[u8],
+    config: &Config,
+    compiler: &Compiler,
+    cache: &CodeCache,
+) -> Result<PathBuf, Box<dyn error::Error>> {
+    let path = cache.executable_path(key(prog, config, compiler));
+    if path.is_file() {
+        return Ok(path);
+    }
+    fs::create_dir_all(path.parent().ok_or("Invalid cache path")?)?;
+    // Build under a private name first so concurrent runs never start a
+    // partially written executable.
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    compile(prog, config, compiler, &tmp)?;
+    fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+/// Compile `prog` with the C compiler and run it on this process's stdin and
+/// stdout. Without a cache the executable is built in a temporary directory
+/// and removed afterwards.
+pub fn run(
+    prog: &[u8],
+    config: &Config,
+    compiler: &Compiler,
+    cache: Option<&CodeCache>,
+) -> Result<(), Box<dyn error::Error>> {
+    let (exe, temporary) = match cache {
+        Some(cache) => (executable(prog, config, compiler, cache)?, false),
+        None => {
+            let exe = std::env::temp_dir().join(format!(
+                "bf-aot-c-{}-{:016x}",
+                std::process::id(),
+                key(prog, config, compiler)
+            ));
+            compile(prog, config, compiler, &exe)?;
+            (exe, true)
+        }
+    };
+    let status = Command::new(&exe).status();
+    if temporary {
+        let _ = fs::remove_file(&exe);
+    }
+    let status = status?;
+    if !status.success() {
+        return Err(format!("Compiled program failed: {status}").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{temp_dir, test_hell, test_run};
+
+    fn have_cc() -> bool {
+        Command::new("cc").arg("--version").output().is_ok()
+    }
+
+    #[test]
+    fn hello() {
+        if have_cc() {
+            assert!(
+                test_run(&|prog| run(prog, &Config::default(), &Compiler::default(), None)).is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn hello_hell() {
+        if have_cc() {
+            assert!(
+                test_hell(&|prog| run(prog, &Config::default(), &Compiler::default(), None))
+                    .is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn executables_are_cached() {
+        if !have_cc() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("bf-aot-c-cache-{}", std::process::id()));
+        let cache = CodeCache::new(dir.clone());
+        let compiler = Compiler::default();
+        let exe = executable(b"+.", &Config::default(), &compiler, &cache).unwrap();
+        let built = fs::metadata(&exe).unwrap().modified().unwrap();
+        assert_eq!(
+            executable(b"+.", &Config::default(), &compiler, &cache).unwrap(),
+            exe
+        );
+        assert_eq!(fs::metadata(&exe).unwrap().modified().unwrap(), built);
+
+        let other = Compiler {
+            flags: vec!["-O0".to_string()],
+            ..compiler
+        };
+        assert_ne!(
+            executable(b"+.", &Config::default(), &other, &cache).unwrap(),
+            exe
+        );
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn key_covers_the_compiler_version() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("bf-aot-c-version");
+        let cc = dir.join("cc");
+        let compiler = Compiler {
+            cc: cc.to_str().unwrap().to_string(),
+            flags: vec![],
+        };
+        let mut keys = Vec::new();
+        for version in ["1.0", "1.1"] {
+            fs::write(&cc, format!("#!/bin/sh\necho fake cc {version}\n")).unwrap();
+            fs::set_permissions(&cc, fs::Permissions::from_mode(0o755)).unwrap();
+            keys.push(key(b"+.", &Config::default(), &compiler));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(keys[0], keys[1]);
+    }
+
+    #[test]
+    fn compiler_errors_are_reported() {
+        if !have_cc() {
+            return;
+        }
+        let compiler = Compiler {
+            cc: "cc".to_string(),
+            flags: vec!["-DX=(".to_string(), "-Dputchar=X".to_string()],
+        };
+        let exe = std::env::temp_dir().join(format!("bf-aot-c-error-{}", std::process::id()));
+        let err = compile(b"+.", &Config::default(), &compiler, &exe).unwrap_err();
+        let err = err.downcast::<CompileError>().unwrap();
+        assert_eq!(err.command, "cc -DX=( -Dputchar=X");
+        assert!(
+            err.to_string()
+                .starts_with("C compiler `cc -DX=( -Dputchar=X` failed:\n")
+        );
+        assert!(!exe.exists());
+
+        let missing = Compiler {
+            cc: "no-such-cc".to_string(),
+            flags: vec![],
+        };
+        let err = compile(b"+.", &Config::default(), &missing, &exe).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Could not run C compiler `no-such-cc`")
+        );
+    }
+}
@@ -0,0 +1,486 @@
+//! The transpiler backends and the registry `main` finds them in.
+//!
+//! A backend turns the optimized IR of a program into the files of an
+//! equivalent program in another language. Backends are only reached through
+//! `BACKENDS`, so adding a language means adding a module with a `Backend`
+//! and listing it there. Backends declare their own command line options,
+//! which `main` adds to its arguments.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use clap::builder::PossibleValue;
+
+use super::ir::{self, CellWidth, Config, EofMode, Node};
+use super::{bf2c, bf2go, bf2js, bf2llvm, bf2py, bf2rs, bf2wasm};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+/// Every backend, in the order `--target list` shows them.
+pub static BACKENDS: &[&dyn Backend] = &[
+    &bf2c::CBackend,
+    &bf2js::JsBackend,
+    &bf2wasm::WasmBackend,
+    &bf2llvm::LlvmBackend,
+    &bf2rs::RustBackend,
+    &bf2py::PythonBackend,
+    &bf2go::GoBackend,
+];
+
+/// What programs a backend can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub cell_widths: &'static [CellWidth],
+    pub eof_modes: &'static [EofMode],
+    /// Whether programs read input as they need it and write output as they
+    /// produce it, rather than taking all input up front.
+    pub streaming_io: bool,
+}
+
+impl Capabilities {
+    /// Every cell width and EOF mode, with streaming I/O.
+    pub const ALL: Capabilities = Capabilities {
+        cell_widths: &[CellWidth::U8, CellWidth::U16, CellWidth::U32],
+        eof_modes: &[EofMode::Unchanged, EofMode::Zero, EofMode::MinusOne],
+        streaming_io: true,
+    };
+}
+
+/// An option of a backend, `--<name>` on the command line.
+#[derive(Debug, Clone)]
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub help: &'static str,
+    /// The values the option takes, or any value if there are none.
+    pub values: Vec<PossibleValue>,
+    pub default: String,
+}
+
+impl OptionSpec {
+    /// An option taking the values of `T`, by default `T::default()`.
+    pub fn choice<T: ValueEnum + Default>(name: &'static str, help: &'static str) -> OptionSpec {
+        OptionSpec {
+            name,
+            help,
+            values: T::value_variants()
+                .iter()
+                .filter_map(ValueEnum::to_possible_value)
+                .collect(),
+            default: value_name(&T::default()),
+        }
+    }
+
+    /// An option taking any value, by default `default`.
+    pub fn text(name: &'static str, help: &'static str, default: &str) -> OptionSpec {
+        OptionSpec {
+            name,
+            help,
+            values: Vec::new(),
+            default: default.into(),
+        }
+    }
+
+    fn accepts(&self, value: &str) -> bool {
+        self.values.is_empty() || self.values.iter().any(|v| v.matches(value, false))
+    }
+}
+
+/// Values of backend options by name. `transpile` fills in the defaults of
+/// options that are not set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options(BTreeMap<String, String>);
+
+impl Options {
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        self.0.insert(name.into(), value.into());
+    }
+
+    /// The value of option `name`.
+    pub fn get(&self, name: &str) -> Result<&str, String> {
+        self.0
+            .get(name)
+            .map(String::as_str)
+            .ok_or_else(|| format!("Missing option --{name}"))
+    }
+
+    /// These options with the defaults of `backend` filled in, if it takes
+    /// all of them.
+    pub fn for_backend(&self, backend: &dyn Backend) -> Result<Options, String> {
+        let specs = backend.options();
+        for (name, value) in &self.0 {
+            let spec = specs
+                .iter()
+                .find(|spec| spec.name == name)
+                .ok_or_else(|| format!("The {} target has no option --{name}", backend.name()))?;
+            if !spec.accepts(value) {
+                return Err(format!("Invalid value `{value}` for --{name}"));
+            }
+        }
+        let mut options = self.clone();
+        for spec in specs {
+            options.0.entry(spec.name.into()).or_insert(spec.default);
+        }
+        Ok(options)
+    }
+
+    /// The value of option `name`, which takes the values of `T`.
+    pub fn choice<T: ValueEnum>(&self, name: &str) -> Result<T, String> {
+        let value = self.get(name)?;
+        T::from_str(value, false).map_err(|_| format!("Invalid value `{value}` for --{name}"))
+    }
+}
+
+/// A program to transpile and where it goes.
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    /// The bf source the IR is built from.
+    pub src: &'a [u8],
+    /// How generated code refers to the source, e.g. in source maps.
+    pub source_name: &'a str,
+    pub config: &'a Config,
+    pub options: &'a Options,
+    /// Where the main artifact goes. Backends may pick its format from the
+    /// extension and put further artifacts next to it.
+    pub output: &'a Path,
+}
+
+impl Request<'_> {
+    /// The main artifact, holding `contents`.
+    pub fn artifact(&self, contents: impl Into<Vec<u8>>) -> Artifact {
+        Artifact {
+            path: self.output.to_path_buf(),
+            contents: contents.into(),
+        }
+    }
+}
+
+/// A file written by a backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+}
+
+pub trait Backend: Sync {
+    /// Name for `--target`; the matching mode is `bf2<name>`.
+    fn name(&self) -> &'static str;
+
+    /// The language programs are written in.
+    fn language(&self) -> &'static str;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::ALL
+    }
+
+    /// The options the backend takes besides the tape configuration. Their
+    /// names are unique across backends.
+    fn options(&self) -> Vec<OptionSpec> {
+        Vec::new()
+    }
+
+    /// Translate `nodes`, the optimized IR of `request.src`, into the
+    /// artifacts of the program.
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>>;
+
+    /// How tests run the programs of the backend.
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        None
+    }
+}
+
+/// The backend called `name`.
+pub fn find(name: &str) -> Option<&'static dyn Backend> {
+    BACKENDS
+        .iter()
+        .copied()
+        .find(|backend| backend.name() == name)
+}
+
+/// Build the IR of `request.src` and transpile it with `backend`, if the
+/// backend supports the configuration and takes the options. Options that are
+/// not set get their defaults.
+pub fn transpile(
+    backend: &dyn Backend,
+    request: &Request,
+) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+    let capabilities = backend.capabilities();
+    let config = request.config;
+    if !capabilities.cell_widths.contains(&config.cell_width) {
+        return Err(format!(
+            "The {} target does not support {}-bit cells",
+            backend.name(),
+            config.cell_width.bits()
+        )
+        .into());
+    }
+    if !capabilities.eof_modes.contains(&config.eof) {
+        return Err(format!(
+            "The {} target does not support --eof {}",
+            backend.name(),
+            value_name(&config.eof)
+        )
+        .into());
+    }
+    let options = request.options.for_backend(backend)?;
+    let nodes = ir::build(request.src)?;
+    backend.transpile(
+        &nodes,
+        &Request {
+            options: &options,
+            ..*request
+        },
+    )
+}
+
+/// What `--target list` prints: a table of the backends and their
+/// capabilities.
+pub fn list() -> String {
+    let mut rows = vec![[
+        "TARGET".to_string(),
+        "MODE".into(),
+        "LANGUAGE".into(),
+        "CELL WIDTHS".into(),
+        "EOF MODES".into(),
+        "STREAMING I/O".into(),
+    ]];
+    for backend in BACKENDS {
+        let capabilities = backend.capabilities();
+        let names = |values: &[String]| values.join(",");
+        rows.push([
+            backend.name().into(),
+            format!("bf2{}", backend.name()),
+            backend.language().into(),
+            names(
+                &capabilities
+                    .cell_widths
+                    .iter()
+                    .map(value_name)
+                    .collect::<Vec<_>>(),
+            ),
+            names(
+                &capabilities
+                    .eof_modes
+                    .iter()
+                    .map(value_name)
+                    .collect::<Vec<_>>(),
+            ),
+            if capabilities.streaming_io {
+                "yes"
+            } else {
+                "no"
+            }
+            .into(),
+        ]);
+    }
+
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(widths) {
+            let _ = write!(line, "{cell:width$}  ");
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// How `value` is spelled on the command line.
+fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{HELLO, HELLO_HELL_STR, run_transpiled};
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn names_are_unique_and_found() {
+        let names: HashSet<&str> = BACKENDS.iter().map(|backend| backend.name()).collect();
+        assert_eq!(names.len(), BACKENDS.len());
+        for name in names {
+            assert_eq!(find(name).unwrap().name(), name);
+        }
+        assert!(find("cobol").is_none());
+    }
+
+    #[test]
+    fn list_shows_every_backend() {
+        let table = list();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), BACKENDS.len() + 1);
+        assert!(lines[0].starts_with("TARGET  MODE     LANGUAGE"));
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.starts_with("c       bf2c     C ") && line.ends_with(" yes"))
+        );
+        assert!(table.contains(" 8,16,32 "));
+        assert!(table.contains(" unchanged,zero,minus-one "));
+    }
+
+    #[test]
+    fn option_names_are_unique() {
+        let mut names = HashSet::new();
+        for backend in BACKENDS {
+            for spec in backend.options() {
+                assert!(names.insert(spec.name), "--{} is taken", spec.name);
+                assert!(spec.accepts(&spec.default));
+            }
+        }
+    }
+
+    #[test]
+    fn options_get_defaults_and_are_checked() {
+        let options = Options::default().for_backend(&bf2go::GoBackend).unwrap();
+        assert_eq!(options.get("go-package"), Ok("main"));
+
+        let mut options = Options::default();
+        options.set("js-target", "esm");
+        let js = options.for_backend(&bf2js::JsBackend).unwrap();
+        assert_eq!(js.choice("js-target"), Ok(bf2js::JsTarget::Esm));
+        assert_eq!(
+            options.for_backend(&bf2c::CBackend),
+            Err("The c target has no option --js-target".to_string())
+        );
+        options.set("js-target", "deno");
+        assert_eq!(
+            options.for_backend(&bf2js::JsBackend),
+            Err("Invalid value `deno` for --js-target".to_string())
+        );
+    }
+
+    #[test]
+    fn every_backend_transpiles_hello() {
+        let hello = b"++++++++[>+++++++++<-]>.";
+        let options = Options::default();
+        for backend in BACKENDS {
+            let request = Request {
+                src: hello,
+                source_name: "hello.bf",
+                config: &Config::default(),
+                options: &options,
+                output: Path::new("out/hello"),
+            };
+            let artifacts = transpile(*backend, &request).unwrap();
+            assert_eq!(artifacts[0].path, Path::new("out/hello"));
+            assert!(
+                artifacts
+                    .iter()
+                    .all(|artifact| !artifact.contents.is_empty())
+            );
+        }
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        let request = Request {
+            src: b"[[]",
+            source_name: "bad.bf",
+            config: &Config::default(),
+            options: &Options::default(),
+            output: Path::new("bad.c"),
+        };
+        assert!(transpile(&bf2c::CBackend, &request).is_err());
+    }
+
+    /// Run programs of every backend that has a runner, with every set of
+    /// options its runner names, and check what they print.
+    #[test]
+    fn programs_round_trip() {
+        let hell: [(CellWidth, &[u8]); 3] = [
+            (CellWidth::U8, b"Hello World! 255\n"),
+            (CellWidth::U16, b"Hello world! 65535\n"),
+            (CellWidth::U32, b"Hello, world!\n"),
+        ];
+        // Read a cell preset to 7 past the end of input and print it.
+        let eof: [(CellWidth, EofMode, &[u8]); 5] = [
+            (CellWidth::U8, EofMode::Unchanged, b"\x07"),
+            (CellWidth::U8, EofMode::Zero, b"\x00"),
+            (CellWidth::U8, EofMode::MinusOne, b"\xff"),
+            (CellWidth::U16, EofMode::MinusOne, b"\xff"),
+            (CellWidth::U32, EofMode::MinusOne, b"\xff"),
+        ];
+        let runners: Vec<(&dyn Backend, Runner)> = BACKENDS
+            .iter()
+            .filter_map(|&backend| Some((backend, backend.runner()?)))
+            .collect();
+        thread::scope(|scope| {
+            for &(backend, ref runner) in &runners {
+                let variants = [&[][..]].into_iter().chain(runner.variants.iter().copied());
+                for options in variants {
+                    scope.spawn(move || {
+                        let capabilities = backend.capabilities();
+                        let run = |src: &[u8], config: Config, input: &[u8]| {
+                            let supported = capabilities.cell_widths.contains(&config.cell_width)
+                                && capabilities.eof_modes.contains(&config.eof);
+                            supported
+                                .then(|| {
+                                    run_transpiled(backend, runner, options, src, &config, input)
+                                })
+                                .flatten()
+                        };
+                        let case = format!("bf2{} {options:?}", backend.name());
+                        if let Some(out) = run(HELLO, Config::default(), b"") {
+                            assert_eq!(out, b"Hello World!\n", "{case}");
+                        }
+                        // Programs with nothing left to run after the
+                        // trailing move is dropped.
+                        for src in [&b""[..], b"a comment", b">>>"] {
+                            if let Some(out) = run(src, Config::default(), b"") {
+                                assert_eq!(out, b"", "{case} {src:?}");
+                            }
+                        }
+                        for (cell_width, expected) in hell {
+                            let config = Config {
+                                cell_width,
+                                ..Config::default()
+                            };
+                            if let Some(out) = run(HELLO_HELL_STR.as_bytes(), config, b"") {
+                                assert_eq!(out, expected, "{case} {cell_width:?}");
+                            }
+                        }
+                        for (cell_width, eof, expected) in eof {
+                            let config = Config {
+                                cell_width,
+                                eof,
+                                ..Config::default()
+                            };
+                            if let Some(out) = run(b"+++++++,.", config, b"") {
+                                assert_eq!(out, expected, "{case} {cell_width:?} {eof:?}");
+                            }
+                        }
+                        // `,[.,]` only stops at the end of input when EOF
+                        // reads as 0.
+                        let config = Config {
+                            eof: EofMode::Zero,
+                            ..Config::default()
+                        };
+                        if let Some(out) = run(b",[.,][]", config, b"piped in") {
+                            assert_eq!(out, b"piped in", "{case}");
+                        }
+                    });
+                }
+            }
+        });
+    }
+}
@@ -1,39 +1,271 @@
-use std::{fs::File, io::Write};
+use std::{error, fmt::Write as _};
 
-pub fn transpile_to_string(bf_src: &[u8]) -> String {
-    let mut c_program = String::new();
+use super::backend::{Artifact, Backend, Request};
+use super::ir::{self, CellWidth, Config, EofMode, Group, Node, Op};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+/// Version of the C `transpile` generates. Bump it whenever the output
+/// changes, so that cached `aot-c` executables are rebuilt.
+pub const CODEGEN_VERSION: u32 = 1;
+
+/// Translate the optimized IR of a bf program into a C program.
+///
+/// Runs of `+`, `-`, `<` and `>` become one statement each and clear,
+/// multiply and scan loops become straight-line code, `memset` and `memchr`.
+pub fn transpile(ops: &[Node], config: &Config) -> String {
+    let mut emitter = Emitter {
+        config,
+        body: String::new(),
+        depth: 1,
+        uses_char: false,
+    };
+    emitter.emit(ops);
 
-    // C program header
+    let mut c_program = String::new();
     c_program.push_str("#include <stdio.h>\n");
-    c_program.push_str("int main() {\n");
-    c_program.push_str("    char array[30000] = {0};\n");
-    c_program.push_str("    char *ptr = array;\n");
-
-    // Convert Brainfuck instructions to C
-    for &command in bf_src {
-        match command {
-            b'>' => c_program.push_str("    ++ptr;\n"),
-            b'<' => c_program.push_str("    --ptr;\n"),
-            b'+' => c_program.push_str("    ++*ptr;\n"),
-            b'-' => c_program.push_str("    --*ptr;\n"),
-            b'.' => c_program.push_str("    putchar(*ptr);\n"),
-            b',' => c_program.push_str("    *ptr = getchar();\n"),
-            b'[' => c_program.push_str("    while (*ptr) {\n"),
-            b']' => c_program.push_str("    }\n"),
-            _ => (), // Ignore other characters (comments or invalid)
-        }
+    c_program.push_str("#include <string.h>\n");
+    if config.cell_width != CellWidth::U8 {
+        c_program.push_str("#include <stdint.h>\n");
     }
-
-    // C program footer
+    let cell = cell_type(config.cell_width);
+    let _ = writeln!(c_program, "\nstatic {cell} tape[{}];\n", config.tape_size);
+    c_program.push_str("int main(void) {\n");
+    let _ = writeln!(c_program, "    {cell} *ptr = tape;");
+    if emitter.uses_char {
+        c_program.push_str("    int c;\n");
+    }
+    c_program.push_str(&emitter.body);
     c_program.push_str("    return 0;\n");
     c_program.push_str("}\n");
-
     c_program
 }
 
-pub fn transpile_to_file(bf_src: &[u8], filename: &str) -> std::io::Result<()> {
-    let c_program = transpile_to_string(bf_src);
-    let mut file = File::create(filename)?;
-    file.write_all(c_program.as_bytes())?;
-    Ok(())
+/// The `bf2c` backend.
+pub struct CBackend;
+
+impl Backend for CBackend {
+    fn name(&self) -> &'static str {
+        "c"
+    }
+
+    fn language(&self) -> &'static str {
+        "C"
+    }
+
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+        Ok(vec![request.artifact(transpile(nodes, request.config))])
+    }
+
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        Some(tests::RUNNER)
+    }
+}
+
+fn cell_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "unsigned char",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    }
+}
+
+/// The cell at `offset` as a C lvalue.
+fn cell(offset: isize) -> String {
+    if offset == 0 {
+        "*ptr".to_string()
+    } else {
+        format!("ptr[{offset}]")
+    }
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    body: String,
+    depth: usize,
+    /// Whether `int c` is needed to hold the result of `getchar`.
+    uses_char: bool,
+}
+
+impl Emitter<'_> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.body.push_str("    ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+
+    fn emit(&mut self, nodes: &[Node]) {
+        for group in ir::mul_groups(nodes) {
+            match group {
+                Group::MulAdd { src, nodes } => {
+                    self.line(&format!("if ({}) {{", cell(src)));
+                    self.depth += 1;
+                    nodes.iter().for_each(|node| self.emit_op(&node.op));
+                    self.depth -= 1;
+                    self.line("}");
+                }
+                Group::Single(node) => self.emit_op(&node.op),
+            }
+        }
+    }
+
+    fn emit_op(&mut self, op: &Op) {
+        let width = self.config.cell_width;
+        match *op {
+            Op::Move(n) if n < 0 => self.line(&format!("ptr -= {};", -n)),
+            Op::Move(n) => self.line(&format!("ptr += {n};")),
+            Op::Add { offset, amount } => {
+                let cell = cell(offset);
+                match width.signed(amount) {
+                    0 => (),
+                    n if n < 0 => self.line(&format!("{cell} -= {};", -n)),
+                    n => self.line(&format!("{cell} += {n};")),
+                }
+            }
+            Op::Set { offset, value } => {
+                let line = format!("{} = {};", cell(offset), width.unsigned(value));
+                self.line(&line);
+            }
+            Op::Clear { offset, len } => {
+                let start = match offset {
+                    0 => "ptr".to_string(),
+                    o if o < 0 => format!("ptr - {}", -o),
+                    o => format!("ptr + {o}"),
+                };
+                let size = if width == CellWidth::U8 {
+                    len.to_string()
+                } else {
+                    format!("{len} * sizeof *ptr")
+                };
+                self.line(&format!("memset({start}, 0, {size});"));
+            }
+            Op::MulAdd { src, dst, factor } => {
+                let (dst, src) = (cell(dst), cell(src));
+                match width.signed(factor) {
+                    0 => (),
+                    1 => self.line(&format!("{dst} += {src};")),
+                    -1 => self.line(&format!("{dst} -= {src};")),
+                    k if k < 0 => self.line(&format!("{dst} -= {src} * {};", -k)),
+                    k => self.line(&format!("{dst} += {src} * {k};")),
+                }
+            }
+            Op::Scan(1) if width == CellWidth::U8 => {
+                self.line("ptr = memchr(ptr, 0, sizeof tape - (ptr - tape));")
+            }
+            Op::Scan(n) if n < 0 => self.line(&format!("while (*ptr) ptr -= {};", -n)),
+            Op::Scan(n) => self.line(&format!("while (*ptr) ptr += {n};")),
+            Op::Output { offset } => self.line(&format!("putchar({});", cell(offset))),
+            Op::Input { offset } => {
+                let cell = cell(offset);
+                match self.config.eof {
+                    EofMode::Unchanged => {
+                        self.uses_char = true;
+                        self.line(&format!("if ((c = getchar()) != EOF) {cell} = c;"));
+                    }
+                    EofMode::Zero => {
+                        self.uses_char = true;
+                        self.line(&format!("{cell} = (c = getchar()) == EOF ? 0 : c;"));
+                    }
+                    // EOF is -1, which converts to the largest cell value.
+                    EofMode::MinusOne => self.line(&format!("{cell} = getchar();")),
+                }
+            }
+            Op::Loop(ref body) => {
+                self.line("while (*ptr) {");
+                self.depth += 1;
+                self.emit(body);
+                self.depth -= 1;
+                self.line("}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{
+        FOLDED_OPS, has_tool, lines_between, output_of, transpile_text,
+    };
+    use crate::transpiler::backend::Options;
+    use std::path::Path;
+    use std::process::Command;
+
+    pub(super) const RUNNER: Runner = Runner {
+        file: "prog.c",
+        variants: &[],
+        run: compile_and_run,
+    };
+
+    /// Compile the program at `path` with `cc` and run it on `input`; `None`
+    /// without `cc`.
+    fn compile_and_run(path: &Path, _options: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        if !has_tool("cc", "--version") {
+            return None;
+        }
+        let exe = path.with_extension("");
+        let status = Command::new("cc")
+            .args(["-O1", "-o"])
+            .arg(&exe)
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        Some(output_of(&mut Command::new(&exe), input))
+    }
+
+    #[test]
+    fn emits_folded_ops() {
+        let c = transpile_text(&CBackend, FOLDED_OPS, &Config::default(), &[]);
+        assert!(c.contains("static unsigned char tape[30000];"));
+        assert_eq!(
+            lines_between(&c, "unsigned char *ptr = tape;", "}"),
+            [
+                "    unsigned char *ptr = tape;",
+                "    int c;",
+                "    *ptr += 3;",
+                "    ptr[2] -= 2;",
+                "    ptr[1] = 0;",
+                "    if (ptr[2]) {",
+                "        ptr[3] += ptr[2] * 3;",
+                "    }",
+                "    ptr[2] = 0;",
+                "    ptr += 3;",
+                "    while (*ptr) ptr -= 1;",
+                "    memset(ptr, 0, 2);",
+                "    ptr += 2;",
+                "    while (*ptr) {",
+                "        putchar(*ptr);",
+                "        ptr += 2;",
+                "    }",
+                "    if ((c = getchar()) != EOF) *ptr = c;",
+                "    return 0;",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn emits_memset_and_memchr() {
+        let c = transpile_text(&CBackend, b"[-]>[-]>[-]>[>]", &Config::default(), &[]);
+        assert!(c.contains("    memset(ptr, 0, 3);\n"));
+        assert!(c.contains("    ptr = memchr(ptr, 0, sizeof tape - (ptr - tape));\n"));
+
+        let config = Config {
+            cell_width: CellWidth::U16,
+            tape_size: 100,
+            ..Config::default()
+        };
+        let c = transpile_text(&CBackend, b"[-]>[-]>[>]", &config, &[]);
+        assert!(c.contains("static uint16_t tape[100];"));
+        assert!(c.contains("    memset(ptr, 0, 2 * sizeof *ptr);\n"));
+        assert!(c.contains("    while (*ptr) ptr += 1;\n"));
+    }
 }
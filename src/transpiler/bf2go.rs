@@ -0,0 +1,299 @@
+use std::{error, fmt::Write as _};
+
+use super::backend::{Artifact, Backend, OptionSpec, Request};
+use super::ir::{self, CellWidth, Config, EofMode, Group, Node, Op};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+const GET: &str = "\tget := func() int {
+\t\tout.Flush()
+\t\tc, err := in.ReadByte()
+\t\tif err != nil {
+\t\t\treturn -1
+\t\t}
+\t\treturn int(c)
+\t}
+";
+
+const MAIN: &str = "
+func main() {
+\tif err := Run(os.Stdin, os.Stdout); err != nil {
+\t\tfmt.Fprintln(os.Stderr, err)
+\t\tos.Exit(1)
+\t}
+}
+";
+
+/// Translate the optimized IR of a bf program into a Go source file of
+/// package `package`.
+///
+/// Runs of `+`, `-`, `<` and `>` become one statement each, clear, multiply
+/// and scan loops become straight-line code and the remaining loops are
+/// `for` loops. The program is the exported `Run` function; package `main`
+/// also gets a `main` running it on stdin and stdout. The output is
+/// formatted as `gofmt` would.
+pub fn transpile(nodes: &[Node], config: &Config, package: &str) -> String {
+    // Go rejects a pointer that is only ever assigned.
+    let nodes = ir::trim_trailing_move(nodes);
+    let mut emitter = Emitter {
+        config,
+        ty: cell_type(config.cell_width),
+        body: String::new(),
+        depth: 1,
+        reads: false,
+    };
+    emitter.emit(nodes);
+    if nodes.is_empty() {
+        emitter.line("_ = tape[p]");
+    }
+
+    let mut go_code = String::new();
+    go_code.push_str("// Code generated by bf2go. DO NOT EDIT.\n\n");
+    let _ = writeln!(go_code, "package {package}\n");
+    go_code.push_str("import (\n\t\"bufio\"\n");
+    if package == "main" {
+        go_code.push_str("\t\"fmt\"\n");
+    }
+    go_code.push_str("\t\"io\"\n");
+    if package == "main" {
+        go_code.push_str("\t\"os\"\n");
+    }
+    go_code.push_str(")\n\n");
+    let _ = writeln!(go_code, "const tapeSize = {}\n", config.tape_size);
+    go_code.push_str("// Run runs the program, reading r and writing w.\n");
+    go_code.push_str("func Run(r io.Reader, w io.Writer) error {\n");
+    if emitter.reads {
+        go_code.push_str("\tin := bufio.NewReader(r)\n");
+    }
+    go_code.push_str("\tout := bufio.NewWriter(w)\n");
+    if emitter.reads {
+        go_code.push_str(GET);
+    }
+    let _ = writeln!(go_code, "\ttape := make([]{}, tapeSize)", emitter.ty);
+    go_code.push_str("\tp := 0\n");
+    go_code.push_str(&emitter.body);
+    go_code.push_str("\treturn out.Flush()\n");
+    go_code.push_str("}\n");
+    if package == "main" {
+        go_code.push_str(MAIN);
+    }
+    go_code
+}
+
+/// The `bf2go` backend.
+pub struct GoBackend;
+
+impl Backend for GoBackend {
+    fn name(&self) -> &'static str {
+        "go"
+    }
+
+    fn language(&self) -> &'static str {
+        "Go"
+    }
+
+    fn options(&self) -> Vec<OptionSpec> {
+        vec![OptionSpec::text(
+            "go-package",
+            "Package of the Go file bf2go mode writes; only `main` gets a `main`",
+            "main",
+        )]
+    }
+
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+        let go_code = transpile(nodes, request.config, request.options.get("go-package")?);
+        Ok(vec![request.artifact(go_code)])
+    }
+
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        Some(tests::RUNNER)
+    }
+}
+
+fn cell_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "uint8",
+        CellWidth::U16 => "uint16",
+        CellWidth::U32 => "uint32",
+    }
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    /// The type of a cell.
+    ty: &'static str,
+    body: String,
+    depth: usize,
+    /// Whether the input reader and `get` are needed.
+    reads: bool,
+}
+
+impl Emitter<'_> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.body.push('\t');
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+
+    /// Emit `header {`, what `body` emits and the closing `}`.
+    fn block(&mut self, header: &str, body: impl FnOnce(&mut Self)) {
+        self.line(&format!("{header} {{"));
+        self.depth += 1;
+        body(self);
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn emit(&mut self, nodes: &[Node]) {
+        for group in ir::mul_groups(nodes) {
+            match group {
+                Group::MulAdd { src, nodes } => {
+                    let header = format!("if {} != 0", ir::cell(src, ""));
+                    self.block(&header, |e| {
+                        nodes.iter().for_each(|node| e.emit_op(&node.op))
+                    })
+                }
+                Group::Single(node) => self.emit_op(&node.op),
+            }
+        }
+    }
+
+    fn emit_op(&mut self, op: &Op) {
+        let width = self.config.cell_width;
+        let ty = self.ty;
+        match *op {
+            Op::Move(n) if n < 0 => self.line(&format!("p -= {}", -n)),
+            Op::Move(n) => self.line(&format!("p += {n}")),
+            Op::Add { offset, amount } => {
+                let cell = ir::cell(offset, "");
+                match width.signed(amount) {
+                    0 => (),
+                    n if n < 0 => self.line(&format!("{cell} -= {}", -n)),
+                    n => self.line(&format!("{cell} += {n}")),
+                }
+            }
+            Op::Set { offset, value } => {
+                let line = format!("{} = {}", ir::cell(offset, ""), width.unsigned(value));
+                self.line(&line);
+            }
+            Op::Clear { offset, len } => {
+                // At least one bound is a sum, so gofmt spaces out the colon.
+                let (start, end) = (ir::index(offset, ""), ir::index(offset + len as isize, ""));
+                self.line(&format!("clear(tape[{start} : {end}])"));
+            }
+            Op::MulAdd { src, dst, factor } => {
+                let (dst, src) = (ir::cell(dst, ""), ir::cell(src, ""));
+                match width.signed(factor) {
+                    0 => (),
+                    1 => self.line(&format!("{dst} += {src}")),
+                    -1 => self.line(&format!("{dst} -= {src}")),
+                    k if k < 0 => self.line(&format!("{dst} -= {src} * {}", -k)),
+                    k => self.line(&format!("{dst} += {src} * {k}")),
+                }
+            }
+            Op::Scan(n) => self.block("for tape[p] != 0", |e| e.emit_op(&Op::Move(n))),
+            Op::Output { offset } => match width {
+                CellWidth::U8 => self.line(&format!("out.WriteByte({})", ir::cell(offset, ""))),
+                _ => self.line(&format!("out.WriteByte(byte({}))", ir::cell(offset, ""))),
+            },
+            Op::Input { offset } => {
+                self.reads = true;
+                let cell = ir::cell(offset, "");
+                match self.config.eof {
+                    EofMode::Unchanged => self.block("if c := get(); c >= 0", |e| {
+                        e.line(&format!("{cell} = {ty}(c)"))
+                    }),
+                    EofMode::Zero => self.line(&format!("{cell} = {ty}(max(get(), 0))")),
+                    // -1 converts to the largest cell value.
+                    EofMode::MinusOne => self.line(&format!("{cell} = {ty}(get())")),
+                }
+            }
+            Op::Loop(ref body) => self.block("for tape[p] != 0", |e| e.emit(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{
+        FOLDED_OPS, has_tool, lines_between, output_of, transpile_text,
+    };
+    use crate::transpiler::backend::Options;
+    use std::path::Path;
+    use std::process::Command;
+
+    pub(super) const RUNNER: Runner = Runner {
+        file: "prog.go",
+        variants: &[],
+        run: run_go,
+    };
+
+    /// Run the program at `path` with `go run` on `input`; `None` without
+    /// `go`.
+    fn run_go(path: &Path, _options: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        if !has_tool("go", "version") {
+            return None;
+        }
+        let mut command = Command::new("go");
+        command.arg("run").arg(path).env("GO111MODULE", "off");
+        Some(output_of(&mut command, input))
+    }
+
+    #[test]
+    fn emits_folded_ops() {
+        let go_code = transpile_text(&GoBackend, FOLDED_OPS, &Config::default(), &[]);
+        assert_eq!(
+            lines_between(&go_code, "p := 0", "}"),
+            [
+                "\tp := 0",
+                "\ttape[p] += 3",
+                "\ttape[p+2] -= 2",
+                "\ttape[p+1] = 0",
+                "\tif tape[p+2] != 0 {",
+                "\t\ttape[p+3] += tape[p+2] * 3",
+                "\t}",
+                "\ttape[p+2] = 0",
+                "\tp += 3",
+                "\tfor tape[p] != 0 {",
+                "\t\tp -= 1",
+                "\t}",
+                "\tclear(tape[p : p+2])",
+                "\tp += 2",
+                "\tfor tape[p] != 0 {",
+                "\t\tout.WriteByte(tape[p])",
+                "\t\tp += 2",
+                "\t}",
+                "\tif c := get(); c >= 0 {",
+                "\t\ttape[p] = uint8(c)",
+                "\t}",
+                "\treturn out.Flush()",
+                "}",
+            ]
+        );
+        assert!(go_code.contains("func main() {"));
+    }
+
+    #[test]
+    fn library_package() {
+        let go_code = transpile_text(
+            &GoBackend,
+            b"+>>",
+            &Config::default(),
+            &[("go-package", "routines")],
+        );
+        assert!(go_code.contains("package routines\n"));
+        assert!(go_code.contains("func Run(r io.Reader, w io.Writer) error {"));
+        assert!(!go_code.contains("func main"));
+        assert!(!go_code.contains("\"os\""));
+        // Nothing reads the pointer after the final move.
+        assert!(!go_code.contains("p += 2"));
+    }
+}
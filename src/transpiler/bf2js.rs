@@ -1,80 +1,510 @@
-use std::{fs::File, io::Write};
-
-pub fn transpile_to_string(bf_src: &[u8]) -> String {
-    // Initialize the JavaScript code with necessary setup
-    let mut js_code = String::from(
-        "const memory = new Uint8Array(30000);\n\
-        let pointer = 0;\n\
-        const input = [];\n\
-        let output = '';\n\
-        const readInput = () => input.shift().charCodeAt(0);\n\
-        const writeOutput = (charCode) => { output += String.fromCharCode(charCode); };\n\
-        // Brainfuck program start\n",
-    );
+use std::{
+    error,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+
+use super::backend::{Artifact, Backend, OptionSpec, Request};
+use super::ir::{self, CellWidth, Config, EofMode, Group, Node, Op};
+use super::source_map::{LineIndex, SourceMap};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+/// The kind of JavaScript program to generate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum JsTarget {
+    /// A Node.js script reading stdin and writing stdout as it runs.
+    #[default]
+    Node,
+    /// An ES module exporting `run(inputBytes, onOutput)`.
+    Esm,
+    /// A classic script defining `brainfuck.run(inputBytes, onOutput)`.
+    Browser,
+}
+
+const NODE_PRELUDE: &str = r#""use strict";
+const fs = require("fs");
+
+const out = Buffer.alloc(65536);
+let outLen = 0;
+const inBuf = Buffer.alloc(65536);
+let inPos = 0;
+let inLen = 0;
+
+function flush() {
+    let done = 0;
+    while (done < outLen) {
+        done += fs.writeSync(1, out, done, outLen - done);
+    }
+    outLen = 0;
+}
+
+function put(c) {
+    c &= 0xff;
+    out[outLen++] = c;
+    if (c === 10 || outLen === out.length) flush();
+}
+
+function readSome() {
+    for (;;) {
+        try {
+            return fs.readSync(0, inBuf, 0, inBuf.length, null);
+        } catch (e) {
+            if (e.code === "EOF") return 0;
+            if (e.code !== "EAGAIN") throw e;
+            // Non-blocking stdin without data yet.
+            Atomics.wait(new Int32Array(new SharedArrayBuffer(4)), 0, 0, 10);
+        }
+    }
+}
+
+/** The next input byte, or -1 at the end of input. */
+function get() {
+    if (inPos === inLen) {
+        flush();
+        inPos = 0;
+        inLen = readSome();
+        if (inLen === 0) return -1;
+    }
+    return inBuf[inPos++];
+}
+
+function main() {
+"#;
+
+const NODE_EPILOGUE: &str = "}
 
-    let mut comment_start = false;
+main();
+flush();
+";
 
-    let mut comment_str;
+const RUN_PRELUDE: &str = "/**
+ * Run the program on `input`, a Uint8Array, passing what it prints to
+ * `onOutput` as Uint8Array chunks. Reads past the end of `input` see EOF.
+ */
+function run(input, onOutput) {
+    const out = new Uint8Array(4096);
+    let outLen = 0;
+    let inPos = 0;
+    const flush = () => {
+        if (outLen > 0) onOutput(out.slice(0, outLen));
+        outLen = 0;
+    };
+    const put = (c) => {
+        out[outLen++] = c;
+        if (outLen === out.length) flush();
+    };
+    const get = () => (inPos < input.length ? input[inPos++] : -1);
+";
 
-    for &command in bf_src.iter() {
-        let js_command: &str = match command {
-            b'>' => {
-                comment_start = false;
-                "pointer++;\n"
+const RUN_EPILOGUE: &str = "    flush();
+}
+";
+
+fn typed_array(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "Uint8Array",
+        CellWidth::U16 => "Uint16Array",
+        CellWidth::U32 => "Uint32Array",
+    }
+}
+
+fn is_command(c: u8) -> bool {
+    b"<>+-.,[]".contains(&c)
+}
+
+/// `text` with every non-empty line indented by `depth` levels.
+fn indent(text: &str, depth: usize) -> String {
+    let prefix = "    ".repeat(depth);
+    text.lines()
+        .map(|line| match line {
+            "" => "\n".to_string(),
+            line => format!("{prefix}{line}\n"),
+        })
+        .collect()
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    src: &'a [u8],
+    lines: LineIndex,
+    js_code: String,
+    map: SourceMap,
+    depth: usize,
+    /// Source before this offset has had its comments emitted.
+    commented: usize,
+}
+
+impl Emitter<'_> {
+    /// Append `text`, which has no source and may span several lines.
+    fn text(&mut self, text: &str) {
+        self.js_code.push_str(text);
+        for _ in text.matches('\n') {
+            self.map.next_line();
+        }
+    }
+
+    /// Emit one line of JS, mapped to source offset `at` if there is one.
+    fn line(&mut self, text: &str, at: Option<usize>) {
+        let indent = self.depth * 4;
+        if let Some(at) = at {
+            let (line, col) = self.lines.position(at);
+            self.map.add(indent, line, col);
+        }
+        self.js_code.extend(std::iter::repeat_n(' ', indent));
+        self.js_code.push_str(text);
+        self.js_code.push('\n');
+        self.map.next_line();
+    }
+
+    /// Emit the comments in the source up to `end`: a `//` line for each run
+    /// of characters other than commands on a source line.
+    fn comments(&mut self, end: usize) {
+        let mut start = self.commented;
+        while start < end {
+            let c = self.src[start];
+            if is_command(c) || c == b'\n' {
+                start += 1;
+                continue;
             }
-            b'<' => {
-                comment_start = false;
-                "pointer--;\n"
+            let len = self.src[start..end]
+                .iter()
+                .position(|&c| is_command(c) || c == b'\n')
+                .unwrap_or(end - start);
+            let text = String::from_utf8_lossy(&self.src[start..start + len]);
+            let text = text.trim();
+            if !text.is_empty() {
+                let leading = self.src[start..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_whitespace());
+                let at = start + leading.count();
+                self.line(&format!("// {text}"), Some(at));
             }
-            b'+' => {
-                comment_start = false;
-                "memory[pointer]++;\n"
+            start += len;
+        }
+        self.commented = self.commented.max(end);
+    }
+
+    fn emit(&mut self, nodes: &[Node]) {
+        for group in ir::mul_groups(nodes) {
+            match group {
+                Group::MulAdd { src, nodes } => {
+                    self.comments(nodes[0].at);
+                    self.line(
+                        &format!("if ({}) {{", ir::cell(src, " ")),
+                        Some(nodes[0].at),
+                    );
+                    self.depth += 1;
+                    nodes.iter().for_each(|node| self.emit_node(node));
+                    self.depth -= 1;
+                    self.line("}", None);
+                }
+                Group::Single(node) => self.emit_node(node),
             }
-            b'-' => {
-                comment_start = false;
-                "memory[pointer]--;\n"
+        }
+    }
+
+    fn emit_node(&mut self, node: &Node) {
+        let width = self.config.cell_width;
+        self.comments(node.at);
+        let at = Some(node.at);
+        match node.op {
+            Op::Move(n) if n < 0 => self.line(&format!("p -= {};", -n), at),
+            Op::Move(n) => self.line(&format!("p += {n};"), at),
+            Op::Add { offset, amount } => {
+                let cell = ir::cell(offset, " ");
+                match width.signed(amount) {
+                    0 => (),
+                    n if n < 0 => self.line(&format!("{cell} -= {};", -n), at),
+                    n => self.line(&format!("{cell} += {n};"), at),
+                }
             }
-            b'.' => {
-                comment_start = false;
-                "writeOutput(memory[pointer]);\n"
+            Op::Set { offset, value } => {
+                let line = format!("{} = {};", ir::cell(offset, " "), width.unsigned(value));
+                self.line(&line, at);
             }
-            b',' => {
-                comment_start = false;
-                "memory[pointer] = readInput();\n"
+            Op::Clear { offset, len } => {
+                let (start, end) = (
+                    ir::index(offset, " "),
+                    ir::index(offset + len as isize, " "),
+                );
+                self.line(&format!("tape.fill(0, {start}, {end});"), at);
             }
-            b'[' => {
-                comment_start = false;
-                "while (memory[pointer] !== 0) {\n"
+            Op::MulAdd { src, dst, factor } => {
+                let (dst, src) = (ir::cell(dst, " "), ir::cell(src, " "));
+                match width.signed(factor) {
+                    0 => (),
+                    1 => self.line(&format!("{dst} += {src};"), at),
+                    -1 => self.line(&format!("{dst} -= {src};"), at),
+                    // Products of 32-bit cells can lose bits as doubles;
+                    // `Math.imul` keeps the low 32.
+                    k if width == CellWidth::U32 => {
+                        self.line(&format!("{dst} += Math.imul({src}, {k});"), at)
+                    }
+                    k if k < 0 => self.line(&format!("{dst} -= {src} * {};", -k), at),
+                    k => self.line(&format!("{dst} += {src} * {k};"), at),
+                }
             }
-            b']' => {
-                comment_start = false;
-                "}\n"
+            Op::Scan(1) => self.line("p = tape.indexOf(0, p);", at),
+            Op::Scan(-1) => self.line("p = tape.lastIndexOf(0, p);", at),
+            Op::Scan(n) if n < 0 => self.line(&format!("while (tape[p] !== 0) p -= {};", -n), at),
+            Op::Scan(n) => self.line(&format!("while (tape[p] !== 0) p += {n};"), at),
+            Op::Output { offset } => self.line(&format!("put({});", ir::cell(offset, " ")), at),
+            Op::Input { offset } => {
+                let cell = ir::cell(offset, " ");
+                let line = match self.config.eof {
+                    EofMode::Unchanged => {
+                        format!("{{ const c = get(); if (c >= 0) {cell} = c; }}")
+                    }
+                    EofMode::Zero => format!("{{ const c = get(); {cell} = c < 0 ? 0 : c; }}"),
+                    // Typed arrays store -1 as the largest cell value.
+                    EofMode::MinusOne => format!("{cell} = get();"),
+                };
+                self.line(&line, at);
             }
-            _ => match comment_start {
-                true => {
-                    comment_str = format!("{}", command as char);
-                    &comment_str
-                }
-                false => {
-                    comment_str = format!("//{}", command as char);
-                    comment_start = true;
-                    &comment_str
-                }
-            }, // Ignore any non-Brainfuck characters
-        };
-        js_code.push_str(js_command);
+            Op::Loop(ref body) => {
+                self.line("while (tape[p] !== 0) {", at);
+                self.depth += 1;
+                self.emit(body);
+                self.depth -= 1;
+                self.line("}", None);
+            }
+        }
     }
+}
 
-    // Add return statement for output and close the function
-    js_code.push_str("console.log(output);\n");
+/// Translate the optimized IR of `bf_src` into JavaScript for `target`,
+/// along with the source map of the generated code.
+///
+/// Comments in the source become `//` lines, one per run of text on a source
+/// line.
+pub fn transpile(
+    bf_src: &[u8],
+    nodes: &[Node],
+    config: &Config,
+    target: JsTarget,
+) -> (String, SourceMap) {
+    let mut emitter = Emitter {
+        config,
+        src: bf_src,
+        lines: LineIndex::new(bf_src),
+        js_code: String::new(),
+        map: SourceMap::new(),
+        depth: 1,
+        commented: 0,
+    };
 
-    // Return the transpiled JavaScript code
-    js_code
+    match target {
+        JsTarget::Node => emitter.text(NODE_PRELUDE),
+        JsTarget::Esm => emitter.text(&format!("export {RUN_PRELUDE}")),
+        JsTarget::Browser => {
+            emitter.text("(function (global) {\n    \"use strict\";\n\n");
+            emitter.text(&indent(RUN_PRELUDE, 1));
+            emitter.depth = 2;
+        }
+    }
+    let tape = format!(
+        "const tape = new {}({});",
+        typed_array(config.cell_width),
+        config.tape_size
+    );
+    emitter.line(&tape, None);
+    emitter.line("let p = 0;", None);
+    emitter.emit(nodes);
+    emitter.comments(bf_src.len());
+    match target {
+        JsTarget::Node => emitter.text(NODE_EPILOGUE),
+        JsTarget::Esm => emitter.text(RUN_EPILOGUE),
+        JsTarget::Browser => {
+            emitter.text(&indent(RUN_EPILOGUE, 1));
+            emitter.text("\n    global.brainfuck = { run };\n})(globalThis);\n");
+        }
+    }
+    (emitter.js_code, emitter.map)
 }
 
-pub fn transpile_to_file(bf_src: &[u8], filename: &str) -> std::io::Result<()> {
-    let c_program = transpile_to_string(bf_src);
-    let mut file = File::create(filename)?;
-    file.write_all(c_program.as_bytes())?;
-    Ok(())
+/// The `bf2js` backend. The source map goes next to the program, in a file
+/// named like it with `.map` appended, and embeds the bf source.
+pub struct JsBackend;
+
+impl Backend for JsBackend {
+    fn name(&self) -> &'static str {
+        "js"
+    }
+
+    fn language(&self) -> &'static str {
+        "JavaScript"
+    }
+
+    fn options(&self) -> Vec<OptionSpec> {
+        vec![OptionSpec::choice::<JsTarget>(
+            "js-target",
+            "Kind of JavaScript program bf2js mode writes",
+        )]
+    }
+
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+        let target = request.options.choice("js-target")?;
+        let (mut js_code, map) = transpile(request.src, nodes, request.config, target);
+        let mut map_path = OsString::from(request.output);
+        map_path.push(".map");
+        let map_path = PathBuf::from(map_path);
+        let base_name = |path: &Path| {
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        js_code.push_str(&format!("//# sourceMappingURL={}\n", base_name(&map_path)));
+        let json = map.to_json(&base_name(request.output), request.source_name, request.src);
+        Ok(vec![
+            request.artifact(js_code),
+            Artifact {
+                path: map_path,
+                contents: json.into_bytes(),
+            },
+        ])
+    }
+
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        Some(tests::RUNNER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{self, FOLDED_OPS, has_tool, output_of, transpile_text};
+    use crate::transpiler::backend::Options;
+    use std::fs;
+    use std::process::Command;
+
+    pub(super) const RUNNER: Runner = Runner {
+        file: "prog.js",
+        variants: &[&[("js-target", "esm")], &[("js-target", "browser")]],
+        run: run_node,
+    };
+
+    const ESM_DRIVER: &str = r#"import { readFileSync } from "fs";
+import { run } from "./prog.js";
+run(readFileSync(0), (bytes) => process.stdout.write(bytes));
+"#;
+
+    const BROWSER_DRIVER: &str = r#"import { readFileSync } from "fs";
+const script = readFileSync(new URL("./prog.js", import.meta.url), "utf8");
+new Function(script)();
+globalThis.brainfuck.run(readFileSync(0), (bytes) => process.stdout.write(bytes));
+"#;
+
+    /// Run the program at `path` with node on `input`, through a driver
+    /// module next to it unless it is a script; `None` without node.
+    fn run_node(path: &Path, options: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        if !has_tool("node", "--version") {
+            return None;
+        }
+        let dir = path.parent().unwrap();
+        let driver = match options.choice("js-target").unwrap() {
+            JsTarget::Node => return Some(output_of(Command::new("node").arg(path), input)),
+            JsTarget::Esm => {
+                fs::write(dir.join("package.json"), r#"{ "type": "module" }"#).unwrap();
+                ESM_DRIVER
+            }
+            JsTarget::Browser => BROWSER_DRIVER,
+        };
+        fs::write(dir.join("driver.mjs"), driver).unwrap();
+        Some(output_of(
+            Command::new("node").arg(dir.join("driver.mjs")),
+            input,
+        ))
+    }
+
+    /// The lines of the program itself: the indented lines after
+    /// `let p = 0;`, unindented.
+    fn program_lines(js_code: &str) -> Vec<&str> {
+        js_code
+            .lines()
+            .skip_while(|line| line.trim() != "let p = 0;")
+            .skip(1)
+            .take_while(|line| line.starts_with("    "))
+            .map(|line| &line[4..])
+            .collect()
+    }
+
+    #[test]
+    fn emits_folded_ops() {
+        let js_code = transpile_text(&JsBackend, FOLDED_OPS, &Config::default(), &[]);
+        assert_eq!(
+            program_lines(&js_code),
+            [
+                "tape[p] += 3;",
+                "tape[p + 2] -= 2;",
+                "tape[p + 1] = 0;",
+                "if (tape[p + 2]) {",
+                "    tape[p + 3] += tape[p + 2] * 3;",
+                "}",
+                "tape[p + 2] = 0;",
+                "p += 3;",
+                "p = tape.lastIndexOf(0, p);",
+                "tape.fill(0, p, p + 2);",
+                "p += 2;",
+                "while (tape[p] !== 0) {",
+                "    put(tape[p]);",
+                "    p += 2;",
+                "}",
+                "{ const c = get(); if (c >= 0) tape[p] = c; }",
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_get_their_own_lines() {
+        let js_code = transpile_text(
+            &JsBackend,
+            b"a+ b c\n  d[.]",
+            &Config::default(),
+            &[("js-target", "esm")],
+        );
+        assert_eq!(
+            program_lines(&js_code),
+            [
+                "// a",
+                "tape[p] += 1;",
+                "// b c",
+                "// d",
+                "while (tape[p] !== 0) {",
+                "    put(tape[p]);",
+                "}",
+                "flush();",
+            ]
+        );
+    }
+
+    #[test]
+    fn ops_map_to_their_source() {
+        let artifacts = test_helper::transpile(
+            &JsBackend,
+            b"x+\n [-]>.",
+            &Config::default(),
+            &[],
+            Path::new("a.js"),
+        );
+        let js_code = String::from_utf8_lossy(&artifacts[0].contents);
+        let json = String::from_utf8_lossy(&artifacts[1].contents);
+        // `// x` maps to 1:1, the clear that absorbed the `+` to its `[` at
+        // 2:2, the output to its `.` at 2:6 and the move folded out of it to
+        // the `>` at 2:5.
+        let unmapped = js_code.lines().take_while(|line| !line.ends_with("// x"));
+        assert!(json.contains(&format!(
+            "\"mappings\":\"{}IAAA;IACC;IAAI;IAAD;",
+            ";".repeat(unmapped.count())
+        )));
+    }
 }
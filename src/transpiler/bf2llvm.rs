@@ -0,0 +1,381 @@
+use std::{error, fmt::Write as _};
+
+use super::backend::{Artifact, Backend, Request};
+use super::ir::{self, CellWidth, Config, EofMode, Group, Node, Op};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+/// Translate the optimized IR of a bf program into a textual LLVM IR module
+/// defining `main`.
+///
+/// The pointer lives in an `alloca` that `opt` promotes to a register, offset
+/// ops address their cell with a `getelementptr` from it, clears call
+/// `llvm.memset` and I/O goes through `getchar` and `putchar`. Opaque
+/// pointers are used throughout, so LLVM 14 needs `-opaque-pointers` to read
+/// it.
+pub fn transpile(nodes: &[Node], config: &Config) -> String {
+    let mut emitter = Emitter {
+        config,
+        ty: int_type(config.cell_width),
+        body: String::new(),
+        tmps: 0,
+        labels: 0,
+    };
+    emitter.emit(nodes);
+
+    let ty = emitter.ty;
+    let mut module = String::new();
+    let _ = writeln!(
+        module,
+        "@tape = internal global [{} x {ty}] zeroinitializer\n",
+        config.tape_size
+    );
+    module.push_str("declare i32 @getchar()\n");
+    module.push_str("declare i32 @putchar(i32)\n");
+    module.push_str("declare void @llvm.memset.p0.i64(ptr, i8, i64, i1)\n\n");
+    module.push_str("define i32 @main() {\n");
+    module.push_str("entry:\n");
+    module.push_str("  %ptr = alloca ptr\n");
+    module.push_str("  store ptr @tape, ptr %ptr\n");
+    module.push_str(&emitter.body);
+    module.push_str("  ret i32 0\n");
+    module.push_str("}\n");
+    module
+}
+
+/// The `bf2llvm` backend.
+pub struct LlvmBackend;
+
+impl Backend for LlvmBackend {
+    fn name(&self) -> &'static str {
+        "llvm"
+    }
+
+    fn language(&self) -> &'static str {
+        "LLVM IR"
+    }
+
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+        let module = transpile(nodes, request.config);
+        Ok(vec![request.artifact(module)])
+    }
+
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        Some(tests::RUNNER)
+    }
+}
+
+fn int_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "i8",
+        CellWidth::U16 => "i16",
+        CellWidth::U32 => "i32",
+    }
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    /// The type of a cell.
+    ty: &'static str,
+    body: String,
+    /// Number of temporaries and labels used so far.
+    tmps: usize,
+    labels: usize,
+}
+
+impl Emitter<'_> {
+    fn inst(&mut self, inst: &str) {
+        self.body.push_str("  ");
+        self.body.push_str(inst);
+        self.body.push('\n');
+    }
+
+    /// Start the basic block `label`.
+    fn label(&mut self, label: &str) {
+        let _ = writeln!(self.body, "{label}:");
+    }
+
+    fn tmp(&mut self) -> String {
+        self.tmps += 1;
+        format!("%t{}", self.tmps)
+    }
+
+    /// A fresh label number for a construct's blocks.
+    fn next_label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels
+    }
+
+    /// Assign the value of `expr` to a new temporary and return it.
+    fn value(&mut self, expr: &str) -> String {
+        let tmp = self.tmp();
+        self.inst(&format!("{tmp} = {expr}"));
+        tmp
+    }
+
+    /// A pointer to the cell at `offset`.
+    fn cell_ptr(&mut self, offset: isize) -> String {
+        let ptr = self.value("load ptr, ptr %ptr");
+        match offset {
+            0 => ptr,
+            offset => self.value(&format!(
+                "getelementptr inbounds {}, ptr {ptr}, i64 {offset}",
+                self.ty
+            )),
+        }
+    }
+
+    /// A pointer to the cell at `offset` and its value.
+    fn load(&mut self, offset: isize) -> (String, String) {
+        let ptr = self.cell_ptr(offset);
+        let value = self.value(&format!("load {}, ptr {ptr}", self.ty));
+        (ptr, value)
+    }
+
+    fn store(&mut self, value: &str, ptr: &str) {
+        self.inst(&format!("store {} {value}, ptr {ptr}", self.ty));
+    }
+
+    fn emit(&mut self, nodes: &[Node]) {
+        for group in ir::mul_groups(nodes) {
+            match group {
+                Group::MulAdd { src, nodes } => {
+                    let n = self.next_label();
+                    let (_, value) = self.load(src);
+                    let nonzero = self.value(&format!("icmp ne {} {value}, 0", self.ty));
+                    self.inst(&format!(
+                        "br i1 {nonzero}, label %mul{n}, label %mul{n}.end"
+                    ));
+                    self.label(&format!("mul{n}"));
+                    nodes.iter().for_each(|node| self.emit_op(&node.op));
+                    self.inst(&format!("br label %mul{n}.end"));
+                    self.label(&format!("mul{n}.end"));
+                }
+                Group::Single(node) => self.emit_op(&node.op),
+            }
+        }
+    }
+
+    /// Run `body` while the current cell is not zero, in blocks named after
+    /// `name`.
+    fn while_nonzero(&mut self, name: &str, body: impl FnOnce(&mut Self)) {
+        let n = self.next_label();
+        self.inst(&format!("br label %{name}{n}"));
+        self.label(&format!("{name}{n}"));
+        let (_, value) = self.load(0);
+        let nonzero = self.value(&format!("icmp ne {} {value}, 0", self.ty));
+        self.inst(&format!(
+            "br i1 {nonzero}, label %{name}{n}.body, label %{name}{n}.end"
+        ));
+        self.label(&format!("{name}{n}.body"));
+        body(self);
+        self.inst(&format!("br label %{name}{n}"));
+        self.label(&format!("{name}{n}.end"));
+    }
+
+    fn move_by(&mut self, n: isize) {
+        let ptr = self.cell_ptr(n);
+        self.inst(&format!("store ptr {ptr}, ptr %ptr"));
+    }
+
+    fn emit_op(&mut self, op: &Op) {
+        let width = self.config.cell_width;
+        let ty = self.ty;
+        match *op {
+            Op::Move(n) => self.move_by(n),
+            Op::Add { offset, amount } => {
+                let (ptr, value) = self.load(offset);
+                let sum = self.value(&format!("add {ty} {value}, {}", width.signed(amount)));
+                self.store(&sum, &ptr);
+            }
+            Op::Set { offset, value } => {
+                let ptr = self.cell_ptr(offset);
+                self.store(&width.signed(value).to_string(), &ptr);
+            }
+            Op::Clear { offset, len } => {
+                let ptr = self.cell_ptr(offset);
+                let size = len * width.bytes();
+                self.inst(&format!(
+                    "call void @llvm.memset.p0.i64(ptr {ptr}, i8 0, i64 {size}, i1 false)"
+                ));
+            }
+            Op::MulAdd { src, dst, factor } => {
+                let factor = width.signed(factor);
+                if factor == 0 {
+                    return;
+                }
+                let (_, src) = self.load(src);
+                let (ptr, dst) = self.load(dst);
+                let sum = match factor {
+                    1 => self.value(&format!("add {ty} {dst}, {src}")),
+                    -1 => self.value(&format!("sub {ty} {dst}, {src}")),
+                    k => {
+                        let product = self.value(&format!("mul {ty} {src}, {k}"));
+                        self.value(&format!("add {ty} {dst}, {product}"))
+                    }
+                };
+                self.store(&sum, &ptr);
+            }
+            Op::Scan(n) => self.while_nonzero("scan", |e| e.move_by(n)),
+            Op::Output { offset } => {
+                let (_, value) = self.load(offset);
+                let c = match width {
+                    CellWidth::U32 => value,
+                    _ => self.value(&format!("zext {ty} {value} to i32")),
+                };
+                self.value(&format!("call i32 @putchar(i32 {c})"));
+            }
+            Op::Input { offset } => {
+                let c = self.value("call i32 @getchar()");
+                let new = match width {
+                    CellWidth::U32 => c.clone(),
+                    _ => self.value(&format!("trunc i32 {c} to {ty}")),
+                };
+                let ptr = self.cell_ptr(offset);
+                let value = match self.config.eof {
+                    EofMode::Unchanged => {
+                        let eof = self.value(&format!("icmp eq i32 {c}, -1"));
+                        let old = self.value(&format!("load {ty}, ptr {ptr}"));
+                        self.value(&format!("select i1 {eof}, {ty} {old}, {ty} {new}"))
+                    }
+                    EofMode::Zero => {
+                        let eof = self.value(&format!("icmp eq i32 {c}, -1"));
+                        self.value(&format!("select i1 {eof}, {ty} 0, {ty} {new}"))
+                    }
+                    // EOF is -1, which truncates to the largest cell value.
+                    EofMode::MinusOne => new,
+                };
+                self.store(&value, &ptr);
+            }
+            Op::Loop(ref body) => self.while_nonzero("loop", |e| e.emit(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{
+        FOLDED_OPS, HELLO_HELL_STR, lines_between, output_of, transpile_text,
+    };
+    use crate::transpiler::backend::Options;
+    use std::io::Write;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    pub(super) const RUNNER: Runner = Runner {
+        file: "prog.ll",
+        variants: &[],
+        run: run_lli,
+    };
+
+    /// The flags LLVM `tool` needs to read opaque pointers, or `None` if it
+    /// is not installed.
+    fn llvm_flags(tool: &str) -> Option<Vec<&'static str>> {
+        let output = Command::new(tool).arg("--version").output().ok()?;
+        let version = String::from_utf8_lossy(&output.stdout);
+        let major = version
+            .split("version ")
+            .nth(1)?
+            .split('.')
+            .next()?
+            .parse::<u32>()
+            .ok()?;
+        Some(if major < 15 {
+            vec!["-opaque-pointers"]
+        } else {
+            vec![]
+        })
+    }
+
+    /// Run the module at `path` with `lli` on `input`; `None` without `lli`.
+    fn run_lli(path: &Path, _options: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        let flags = llvm_flags("lli")?;
+        Some(output_of(Command::new("lli").args(flags).arg(path), input))
+    }
+
+    #[test]
+    fn emits_folded_ops() {
+        let ll = transpile_text(&LlvmBackend, FOLDED_OPS, &Config::default(), &[]);
+        assert!(ll.contains("@tape = internal global [30000 x i8] zeroinitializer"));
+        assert!(ll.contains(
+            "  %t2 = load i8, ptr %t1\n  %t3 = add i8 %t2, 3\n  store i8 %t3, ptr %t1\n"
+        ));
+        assert!(ll.contains("getelementptr inbounds i8, ptr %t4, i64 2\n"));
+        assert!(ll.contains("add i8 %t6, -2\n"));
+        assert!(ll.contains("icmp ne i8 %"));
+        assert!(ll.contains("mul i8 %"));
+        assert!(ll.contains("call void @llvm.memset.p0.i64(ptr %"));
+        assert!(ll.contains(", i8 0, i64 2, i1 false)"));
+        assert!(ll.contains("scan2:"));
+        // `[.>>]` is the only loop left.
+        assert!(ll.contains("\nloop3:\n"));
+        assert!(!ll.contains("loop4"));
+    }
+
+    #[test]
+    fn loops_get_blocks() {
+        let ll = transpile_text(&LlvmBackend, b"[.>]", &Config::default(), &[]);
+        assert_eq!(
+            lines_between(&ll, "entry:", "}"),
+            [
+                "entry:",
+                "  %ptr = alloca ptr",
+                "  store ptr @tape, ptr %ptr",
+                "  br label %loop1",
+                "loop1:",
+                "  %t1 = load ptr, ptr %ptr",
+                "  %t2 = load i8, ptr %t1",
+                "  %t3 = icmp ne i8 %t2, 0",
+                "  br i1 %t3, label %loop1.body, label %loop1.end",
+                "loop1.body:",
+                "  %t4 = load ptr, ptr %ptr",
+                "  %t5 = load i8, ptr %t4",
+                "  %t6 = zext i8 %t5 to i32",
+                "  %t7 = call i32 @putchar(i32 %t6)",
+                "  %t8 = load ptr, ptr %ptr",
+                "  %t9 = getelementptr inbounds i8, ptr %t8, i64 1",
+                "  store ptr %t9, ptr %ptr",
+                "  br label %loop1",
+                "loop1.end:",
+                "  ret i32 0",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn optimizes_with_opt() {
+        let Some(flags) = llvm_flags("opt") else {
+            return;
+        };
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            let config = Config {
+                cell_width,
+                ..Config::default()
+            };
+            let mut child = Command::new("opt")
+                .args(flags.iter().chain(&["-O2", "-S"]))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let ll = transpile_text(&LlvmBackend, HELLO_HELL_STR.as_bytes(), &config, &[]);
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(ll.as_bytes())
+                .unwrap();
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success());
+            // The pointer is promoted out of memory.
+            assert!(!String::from_utf8_lossy(&output.stdout).contains("alloca"));
+        }
+    }
+}
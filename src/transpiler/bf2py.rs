@@ -0,0 +1,332 @@
+use std::{error, fmt::Write as _};
+
+use super::backend::{Artifact, Backend, Request};
+use super::ir::{self, CellWidth, Config, EofMode, Group, Node, Op};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+const GET: &str = "    def get():
+        \"\"\"The next input byte, or -1 at the end of input.\"\"\"
+        out.flush()
+        c = inp.read(1)
+        return c[0] if c else -1
+
+";
+
+const ON_TAPE: &str = "    def on_tape(i):
+        \"\"\"`i`, which Python would wrap around when left of the tape.\"\"\"
+        if i < 0:
+            raise IndexError(\"tape index out of range\")
+        return i
+
+";
+
+const MAIN: &str = "
+
+if __name__ == \"__main__\":
+    run(sys.stdin.buffer, sys.stdout.buffer)
+";
+
+/// Translate the optimized IR of a bf program into a Python 3 module that
+/// runs as a script.
+///
+/// Runs of `+`, `-`, `<` and `>` become one statement each, clear, multiply
+/// and scan loops become straight-line code and the remaining loops are
+/// `while` loops. Running off either end of the tape raises `IndexError`.
+pub fn transpile(nodes: &[Node], config: &Config) -> String {
+    let mut emitter = Emitter {
+        config,
+        mask: mask(config.cell_width),
+        body: String::new(),
+        depth: 1,
+        reads: false,
+        left: false,
+    };
+    emitter.emit(nodes);
+
+    let mut py_code = String::new();
+    py_code.push_str("#!/usr/bin/env python3\n");
+    py_code.push_str("\"\"\"Generated by bf2py.\"\"\"\n\n");
+    py_code.push_str("import sys\n\n");
+    let _ = writeln!(py_code, "TAPE_SIZE = {}\n\n", config.tape_size);
+    py_code.push_str("def run(inp, out):\n");
+    py_code.push_str(
+        "    \"\"\"Run the program, reading the binary stream `inp` and writing `out`.\"\"\"\n",
+    );
+    if emitter.reads {
+        py_code.push_str(GET);
+    }
+    if emitter.left {
+        py_code.push_str(ON_TAPE);
+    }
+    match config.cell_width {
+        CellWidth::U8 => py_code.push_str("    tape = bytearray(TAPE_SIZE)\n"),
+        _ => py_code.push_str("    tape = [0] * TAPE_SIZE\n"),
+    }
+    py_code.push_str("    p = 0\n");
+    py_code.push_str(&emitter.body);
+    py_code.push_str("    out.flush()\n");
+    py_code.push_str(MAIN);
+    py_code
+}
+
+/// The `bf2py` backend.
+pub struct PythonBackend;
+
+impl Backend for PythonBackend {
+    fn name(&self) -> &'static str {
+        "py"
+    }
+
+    fn language(&self) -> &'static str {
+        "Python"
+    }
+
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+        let py_code = transpile(nodes, request.config);
+        Ok(vec![request.artifact(py_code)])
+    }
+
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        Some(tests::RUNNER)
+    }
+}
+
+/// The largest cell value, as a literal that masks values into a cell.
+fn mask(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "0xff",
+        CellWidth::U16 => "0xffff",
+        CellWidth::U32 => "0xffffffff",
+    }
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    mask: &'static str,
+    body: String,
+    depth: usize,
+    /// Whether the `get` helper is needed.
+    reads: bool,
+    /// Whether the `on_tape` helper is needed.
+    left: bool,
+}
+
+impl Emitter<'_> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.body.push_str("    ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+
+    /// Emit `header` and the block `body` emits under it, which may be
+    /// nothing when every op in it folds away.
+    fn block(&mut self, header: &str, body: impl FnOnce(&mut Self)) {
+        self.line(header);
+        self.depth += 1;
+        let len = self.body.len();
+        body(self);
+        if self.body.len() == len {
+            self.line("pass");
+        }
+        self.depth -= 1;
+    }
+
+    /// The cell at `offset`, checked against the left edge when it may be
+    /// past it.
+    fn cell(&mut self, offset: isize) -> String {
+        match offset {
+            o if o < 0 => {
+                self.left = true;
+                format!("tape[on_tape({})]", ir::index(o, " "))
+            }
+            o => ir::cell(o, " "),
+        }
+    }
+
+    fn emit(&mut self, nodes: &[Node]) {
+        for group in ir::mul_groups(nodes) {
+            match group {
+                Group::MulAdd { src, nodes } => {
+                    let header = format!("if {}:", self.cell(src));
+                    self.block(&header, |e| {
+                        nodes.iter().for_each(|node| e.emit_op(&node.op))
+                    })
+                }
+                Group::Single(node) => self.emit_op(&node.op),
+            }
+        }
+    }
+
+    fn emit_op(&mut self, op: &Op) {
+        let width = self.config.cell_width;
+        let mask = self.mask;
+        match *op {
+            Op::Move(n) if n < 0 => {
+                self.left = true;
+                self.line(&format!("p = on_tape(p - {})", -n));
+            }
+            Op::Move(n) => self.line(&format!("p += {n}")),
+            Op::Add { offset, amount } => {
+                let cell = self.cell(offset);
+                match width.signed(amount) {
+                    0 => (),
+                    n if n < 0 => self.line(&format!("{cell} = ({cell} - {}) & {mask}", -n)),
+                    n => self.line(&format!("{cell} = ({cell} + {n}) & {mask}")),
+                }
+            }
+            Op::Set { offset, value } => {
+                let line = format!("{} = {}", self.cell(offset), width.unsigned(value));
+                self.line(&line);
+            }
+            Op::Clear { offset, len } => {
+                let (start, end) = (
+                    ir::index(offset, " "),
+                    ir::index(offset + len as isize, " "),
+                );
+                // A slice past either end would wrap around or resize the tape.
+                self.block(&format!("if {start} < 0 or {end} > TAPE_SIZE:"), |e| {
+                    e.line("raise IndexError(\"tape index out of range\")")
+                });
+                self.line(&format!("tape[{start}:{end}] = [0] * {len}"));
+            }
+            Op::MulAdd { src, dst, factor } => {
+                let (dst, src) = (self.cell(dst), self.cell(src));
+                match width.signed(factor) {
+                    0 => (),
+                    1 => self.line(&format!("{dst} = ({dst} + {src}) & {mask}")),
+                    -1 => self.line(&format!("{dst} = ({dst} - {src}) & {mask}")),
+                    k if k < 0 => self.line(&format!("{dst} = ({dst} - {src} * {}) & {mask}", -k)),
+                    k => self.line(&format!("{dst} = ({dst} + {src} * {k}) & {mask}")),
+                }
+            }
+            Op::Scan(1) => self.line("p = tape.index(0, p)"),
+            Op::Scan(-1) if width == CellWidth::U8 => self.line("p = tape.rindex(0, 0, p + 1)"),
+            Op::Scan(n) => self.block("while tape[p]:", |e| e.emit_op(&Op::Move(n))),
+            Op::Output { offset } => {
+                let cell = self.cell(offset);
+                match width {
+                    CellWidth::U8 => self.line(&format!("out.write(bytes(({cell},)))")),
+                    _ => self.line(&format!("out.write(bytes(({cell} & 0xff,)))")),
+                }
+            }
+            Op::Input { offset } => {
+                self.reads = true;
+                let cell = self.cell(offset);
+                match self.config.eof {
+                    EofMode::Unchanged => {
+                        self.line("c = get()");
+                        self.block("if c >= 0:", |e| e.line(&format!("{cell} = c")));
+                    }
+                    EofMode::Zero => self.line(&format!("{cell} = max(get(), 0)")),
+                    // -1 masks to the largest cell value.
+                    EofMode::MinusOne => self.line(&format!("{cell} = get() & {mask}")),
+                }
+            }
+            Op::Loop(ref body) => self.block("while tape[p]:", |e| e.emit(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{
+        FOLDED_OPS, has_tool, lines_between, output_of, temp_dir, transpile_text,
+    };
+    use crate::transpiler::backend::Options;
+    use std::fs;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    pub(super) const RUNNER: Runner = Runner {
+        file: "prog.py",
+        variants: &[],
+        run: run_python,
+    };
+
+    /// Run the program at `path` with `python3` on `input`; `None` without
+    /// `python3`.
+    fn run_python(path: &Path, _options: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        if !has_tool("python3", "--version") {
+            return None;
+        }
+        Some(output_of(Command::new("python3").arg(path), input))
+    }
+
+    #[test]
+    fn emits_folded_ops() {
+        let py_code = transpile_text(&PythonBackend, FOLDED_OPS, &Config::default(), &[]);
+        assert_eq!(
+            lines_between(&py_code, "p = 0", "    out.flush()"),
+            [
+                "    p = 0",
+                "    tape[p] = (tape[p] + 3) & 0xff",
+                "    tape[p + 2] = (tape[p + 2] - 2) & 0xff",
+                "    tape[p + 1] = 0",
+                "    if tape[p + 2]:",
+                "        tape[p + 3] = (tape[p + 3] + tape[p + 2] * 3) & 0xff",
+                "    tape[p + 2] = 0",
+                "    p += 3",
+                "    p = tape.rindex(0, 0, p + 1)",
+                "    if p < 0 or p + 2 > TAPE_SIZE:",
+                "        raise IndexError(\"tape index out of range\")",
+                "    tape[p:p + 2] = [0] * 2",
+                "    p += 2",
+                "    while tape[p]:",
+                "        out.write(bytes((tape[p],)))",
+                "        p += 2",
+                "    c = get()",
+                "    if c >= 0:",
+                "        tape[p] = c",
+                "    out.flush()",
+            ]
+        );
+        assert!(py_code.ends_with(MAIN));
+    }
+
+    /// Whether `bf_src` fails under `python3`; `None` without `python3`.
+    fn fails(bf_src: &[u8], config: &Config) -> Option<bool> {
+        if !has_tool("python3", "--version") {
+            return None;
+        }
+        let dir = temp_dir("bf2py");
+        let py_file = dir.join("prog.py");
+        fs::write(
+            &py_file,
+            transpile_text(&PythonBackend, bf_src, config, &[]),
+        )
+        .unwrap();
+        let output = Command::new("python3")
+            .arg(&py_file)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        Some(!output.status.success())
+    }
+
+    #[test]
+    fn clear_off_the_tape_fails() {
+        let config = Config {
+            tape_size: 4,
+            ..Config::default()
+        };
+        let py_code = transpile_text(&PythonBackend, b"+>>>[-]>[-]", &config, &[]);
+        assert!(py_code.contains("tape[p + 3:p + 5] = [0] * 2"));
+        assert_ne!(fails(b"+>>>[-]>[-]", &config), Some(false));
+    }
+
+    #[test]
+    fn left_of_the_tape_fails() {
+        assert_ne!(fails(b"<+.", &Config::default()), Some(false));
+        assert_ne!(fails(b"+[<+]", &Config::default()), Some(false));
+    }
+}
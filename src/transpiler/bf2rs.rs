@@ -0,0 +1,404 @@
+use std::{error, fmt::Write as _};
+
+use clap::ValueEnum;
+
+use super::backend::{Artifact, Backend, OptionSpec, Request};
+use super::ir::{self, CellWidth, Config, EofMode, Group, Node, Op};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+/// The kind of Rust source to generate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum RustTarget {
+    /// A program whose `main` runs on stdin and stdout.
+    #[default]
+    Program,
+    /// A module exporting `run(input, output)` for embedding in a crate.
+    Module,
+}
+
+const GET: &str = "
+/// The next input byte, or `None` at the end of input. Pending output is
+/// flushed first, so prompts show up before the program waits for input.
+fn get(input: &mut impl Read, output: &mut impl Write) -> io::Result<Option<u8>> {
+    output.flush()?;
+    input.bytes().next().transpose()
+}
+";
+
+const MAIN: &str = "
+fn main() -> io::Result<()> {
+    let mut output = io::BufWriter::new(io::stdout().lock());
+    run(&mut io::stdin().lock(), &mut output)
+}
+";
+
+/// Translate the optimized IR of a bf program into safe Rust for `target`.
+///
+/// The tape is a `Vec` of cells and all cell arithmetic wraps. Running off
+/// the tape panics.
+pub fn transpile(nodes: &[Node], config: &Config, target: RustTarget) -> String {
+    let nodes = ir::trim_trailing_move(nodes);
+    let mut emitter = Emitter {
+        config,
+        ty: cell_type(config.cell_width),
+        body: String::new(),
+        depth: 1,
+        moves: false,
+        writes: false,
+        reads: false,
+    };
+    emitter.emit(nodes);
+
+    let mut rust_code = String::new();
+    rust_code.push_str("// Generated by bf2rs.\n");
+    rust_code.push_str("#![forbid(unsafe_code)]\n\n");
+    rust_code.push_str("use std::io::{self, Read, Write};\n\n");
+    let _ = writeln!(
+        rust_code,
+        "const TAPE_SIZE: usize = {};\n",
+        config.tape_size
+    );
+    rust_code.push_str("/// Run the program, reading `input` and writing `output`.\n");
+    let input = if emitter.reads { "input" } else { "_input" };
+    let _ = writeln!(
+        rust_code,
+        "pub fn run({input}: &mut impl Read, output: &mut impl Write) -> io::Result<()> {{"
+    );
+    let mutability = |used| if used { "mut " } else { "" };
+    // Every op left after the trailing move touches both.
+    let unused = if nodes.is_empty() { "_" } else { "" };
+    let _ = writeln!(
+        rust_code,
+        "    let {}{unused}tape = vec![0{}; TAPE_SIZE];",
+        mutability(emitter.writes),
+        emitter.ty
+    );
+    let _ = writeln!(
+        rust_code,
+        "    let {}{unused}p: usize = 0;",
+        mutability(emitter.moves)
+    );
+    rust_code.push_str(&emitter.body);
+    rust_code.push_str("    output.flush()\n");
+    rust_code.push_str("}\n");
+    if emitter.reads {
+        rust_code.push_str(GET);
+    }
+    if target == RustTarget::Program {
+        rust_code.push_str(MAIN);
+    }
+    rust_code
+}
+
+/// The `bf2rs` backend.
+pub struct RustBackend;
+
+impl Backend for RustBackend {
+    fn name(&self) -> &'static str {
+        "rs"
+    }
+
+    fn language(&self) -> &'static str {
+        "Rust"
+    }
+
+    fn options(&self) -> Vec<OptionSpec> {
+        vec![OptionSpec::choice::<RustTarget>(
+            "rust-target",
+            "Kind of Rust source bf2rs mode writes",
+        )]
+    }
+
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+        let target = request.options.choice("rust-target")?;
+        let rust_code = transpile(nodes, request.config, target);
+        Ok(vec![request.artifact(rust_code)])
+    }
+
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        Some(tests::RUNNER)
+    }
+}
+
+fn cell_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+    }
+}
+
+/// `p` moved by `offset`. Moving left is checked, so that it panics at run
+/// time rather than folding into a compile error when `p` is constant.
+fn index(offset: isize) -> String {
+    match offset {
+        o if o < 0 => format!("p.checked_sub({}).expect(\"ran off the tape\")", -o),
+        o => ir::index(o, " "),
+    }
+}
+
+/// The cell at `offset`.
+fn cell(offset: isize) -> String {
+    format!("tape[{}]", index(offset))
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    /// The type of a cell.
+    ty: &'static str,
+    body: String,
+    depth: usize,
+    /// Whether the program moves the pointer, changes the tape and reads
+    /// input, which decide what needs to be mutable and what is used.
+    moves: bool,
+    writes: bool,
+    reads: bool,
+}
+
+impl Emitter<'_> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.body.push_str("    ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+
+    fn emit(&mut self, nodes: &[Node]) {
+        for group in ir::mul_groups(nodes) {
+            match group {
+                Group::MulAdd { src, nodes } => {
+                    self.line(&format!("if {} != 0 {{", cell(src)));
+                    self.depth += 1;
+                    nodes.iter().for_each(|node| self.emit_op(&node.op));
+                    self.depth -= 1;
+                    self.line("}");
+                }
+                Group::Single(node) => self.emit_op(&node.op),
+            }
+        }
+    }
+
+    fn emit_op(&mut self, op: &Op) {
+        let width = self.config.cell_width;
+        match *op {
+            Op::Move(_) | Op::Scan(_) => self.moves = true,
+            Op::Add { .. }
+            | Op::Set { .. }
+            | Op::Clear { .. }
+            | Op::MulAdd { .. }
+            | Op::Input { .. } => self.writes = true,
+            Op::Output { .. } | Op::Loop(_) => (),
+        }
+        match *op {
+            Op::Move(n) if n < 0 => self.line(&format!("p = {};", index(n))),
+            Op::Move(n) => self.line(&format!("p += {n};")),
+            Op::Add { offset, amount } => {
+                let cell = cell(offset);
+                match width.signed(amount) {
+                    0 => (),
+                    n if n < 0 => self.line(&format!("{cell} = {cell}.wrapping_sub({});", -n)),
+                    n => self.line(&format!("{cell} = {cell}.wrapping_add({n});")),
+                }
+            }
+            Op::Set { offset, value } => {
+                let line = format!("{} = {};", cell(offset), width.unsigned(value));
+                self.line(&line);
+            }
+            Op::Clear { offset, len } => {
+                let (start, end) = (index(offset), index(offset + len as isize));
+                self.line(&format!("tape[{start}..{end}].fill(0);"));
+            }
+            Op::MulAdd { src, dst, factor } => {
+                let (dst, src) = (cell(dst), cell(src));
+                match width.signed(factor) {
+                    0 => (),
+                    1 => self.line(&format!("{dst} = {dst}.wrapping_add({src});")),
+                    -1 => self.line(&format!("{dst} = {dst}.wrapping_sub({src});")),
+                    k if k < 0 => self.line(&format!(
+                        "{dst} = {dst}.wrapping_sub({src}.wrapping_mul({}));",
+                        -k
+                    )),
+                    k => self.line(&format!(
+                        "{dst} = {dst}.wrapping_add({src}.wrapping_mul({k}));"
+                    )),
+                }
+            }
+            Op::Scan(1) => self.line(
+                "p += tape[p..].iter().position(|&c| c == 0).expect(\"scan ran off the tape\");",
+            ),
+            Op::Scan(-1) => self.line(
+                "p = tape[..=p].iter().rposition(|&c| c == 0).expect(\"scan ran off the tape\");",
+            ),
+            Op::Scan(n) if n < 0 => {
+                self.line(&format!("while tape[p] != 0 {{ p = {}; }}", index(n)))
+            }
+            Op::Scan(n) => self.line(&format!("while tape[p] != 0 {{ p += {n}; }}")),
+            Op::Output { offset } => {
+                let cell = cell(offset);
+                match width {
+                    CellWidth::U8 => self.line(&format!("output.write_all(&[{cell}])?;")),
+                    _ => self.line(&format!("output.write_all(&[{cell} as u8])?;")),
+                }
+            }
+            Op::Input { offset } => {
+                self.reads = true;
+                let cell = cell(offset);
+                let c = match width {
+                    CellWidth::U8 => "c",
+                    _ => "c.into()",
+                };
+                let or_eof = |value: &str| match width {
+                    CellWidth::U8 => format!("unwrap_or({value})"),
+                    _ => format!("map_or({value}, Into::into)"),
+                };
+                match self.config.eof {
+                    EofMode::Unchanged => self.line(&format!(
+                        "if let Some(c) = get(input, output)? {{ {cell} = {c}; }}"
+                    )),
+                    EofMode::Zero => {
+                        self.line(&format!("{cell} = get(input, output)?.{};", or_eof("0")))
+                    }
+                    EofMode::MinusOne => {
+                        let max = format!("{}::MAX", self.ty);
+                        self.line(&format!("{cell} = get(input, output)?.{};", or_eof(&max)))
+                    }
+                }
+            }
+            Op::Loop(ref body) => {
+                self.line("while tape[p] != 0 {");
+                self.depth += 1;
+                self.emit(body);
+                self.depth -= 1;
+                self.line("}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{
+        FOLDED_OPS, HELLO, has_tool, lines_between, output_of, temp_dir, transpile_text,
+    };
+    use crate::transpiler::backend::Options;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    pub(super) const RUNNER: Runner = Runner {
+        file: "prog.rs",
+        variants: &[],
+        run: compile_and_run,
+    };
+
+    /// Compile the program at `path` with `rustc`, rejecting any warning,
+    /// into an executable next to it; `None` without `rustc`.
+    fn compile(path: &Path) -> Option<PathBuf> {
+        if !has_tool("rustc", "--version") {
+            return None;
+        }
+        let exe = path.with_extension("");
+        let status = Command::new("rustc")
+            .args(["--edition", "2021", "-D", "warnings", "-O", "-o"])
+            .arg(&exe)
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        Some(exe)
+    }
+
+    /// Compile the program at `path` and run it on `input`; `None` without
+    /// `rustc`.
+    fn compile_and_run(path: &Path, _options: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        Some(output_of(&mut Command::new(compile(path)?), input))
+    }
+
+    #[test]
+    fn emits_folded_ops() {
+        let rust_code = transpile_text(
+            &RustBackend,
+            FOLDED_OPS,
+            &Config::default(),
+            &[("rust-target", "module")],
+        );
+        let run = "pub fn run(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {";
+        assert_eq!(
+            lines_between(&rust_code, run, "}"),
+            [
+                run,
+                "    let mut tape = vec![0u8; TAPE_SIZE];",
+                "    let mut p: usize = 0;",
+                "    tape[p] = tape[p].wrapping_add(3);",
+                "    tape[p + 2] = tape[p + 2].wrapping_sub(2);",
+                "    tape[p + 1] = 0;",
+                "    if tape[p + 2] != 0 {",
+                "        tape[p + 3] = tape[p + 3].wrapping_add(tape[p + 2].wrapping_mul(3));",
+                "    }",
+                "    tape[p + 2] = 0;",
+                "    p += 3;",
+                "    p = tape[..=p].iter().rposition(|&c| c == 0).expect(\"scan ran off the tape\");",
+                "    tape[p..p + 2].fill(0);",
+                "    p += 2;",
+                "    while tape[p] != 0 {",
+                "        output.write_all(&[tape[p]])?;",
+                "        p += 2;",
+                "    }",
+                "    if let Some(c) = get(input, output)? { tape[p] = c; }",
+                "    output.flush()",
+                "}",
+            ]
+        );
+        assert!(rust_code.contains("#![forbid(unsafe_code)]"));
+        assert!(!rust_code.contains("fn main"));
+    }
+
+    #[test]
+    fn module_compiles_as_library() {
+        if !has_tool("rustc", "--version") {
+            return;
+        }
+        let dir = temp_dir("bf2rs");
+        let rs_file = dir.join("hello.rs");
+        let rust_code = transpile_text(
+            &RustBackend,
+            HELLO,
+            &Config::default(),
+            &[("rust-target", "module")],
+        );
+        fs::write(&rs_file, rust_code).unwrap();
+        let status = Command::new("rustc")
+            .args(["--edition", "2021", "-D", "warnings", "--crate-type", "lib"])
+            .arg("--out-dir")
+            .arg(&dir)
+            .arg(&rs_file)
+            .status()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn left_of_the_tape_panics() {
+        let dir = temp_dir("bf2rs");
+        for (name, bf_src) in [("never_moves", "<+"), ("moves", "<[+]")] {
+            let rs_file = dir.join(format!("{name}.rs"));
+            let rust_code =
+                transpile_text(&RustBackend, bf_src.as_bytes(), &Config::default(), &[]);
+            fs::write(&rs_file, rust_code).unwrap();
+            if let Some(exe) = compile(&rs_file) {
+                let output = Command::new(exe).stdin(Stdio::null()).output().unwrap();
+                assert!(!output.status.success(), "{bf_src}");
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
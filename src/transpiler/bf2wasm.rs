@@ -0,0 +1,991 @@
+//! WebAssembly output, as a binary module or in the text format.
+//!
+//! The tape is linear memory and the local `$p` holds the byte address of the
+//! current cell. A loop becomes a `block` that is left right away when the
+//! cell is zero around a `loop` that repeats while it is not:
+//!
+//! ```wat
+//! block
+//!   ;; cell
+//!   i32.eqz
+//!   br_if 0
+//!   loop
+//!     ;; body, cell
+//!     br_if 0
+//!   end
+//! end
+//! ```
+
+use std::{error, fmt::Write as _};
+
+use clap::ValueEnum;
+
+use super::backend::{Artifact, Backend, OptionSpec, Request};
+use super::ir::{self, CellWidth, Config, EofMode, Group, Node, Op};
+#[cfg(test)]
+use crate::tests::test_helper::Runner;
+
+/// How the module does I/O.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum WasmIo {
+    /// Import `env.read_byte` (returning -1 at the end of input) and
+    /// `env.write_byte`, and export `run`.
+    #[default]
+    Host,
+    /// Import WASI's `fd_read` and `fd_write` on stdin and stdout, and export
+    /// `_start`.
+    Wasi,
+}
+
+/// Where the tape starts in WASI modules; below it are the iovec at 0 and
+/// the byte count at 8 that `fd_read` and `fd_write` take, and the byte
+/// being read or written at 12.
+const WASI_TAPE_BASE: u32 = 16;
+const WASI_IOVEC: i32 = 0;
+const WASI_COUNT: i32 = 8;
+const WASI_BYTE: i32 = 12;
+
+/// Locals of the main function.
+const P: u32 = 0;
+const C: u32 = 1;
+
+const PAGE_SIZE: usize = 65536;
+
+/// The instructions the backend uses. All values are i32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instr {
+    Block,
+    Loop,
+    If,
+    End,
+    BrIf(u32),
+    Return,
+    Call(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    I32Const(i32),
+    I32Eqz,
+    I32GeS,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32And,
+    /// Load `width` unsigned bits from the address on the stack plus
+    /// `offset`.
+    Load {
+        width: CellWidth,
+        offset: u32,
+    },
+    Store {
+        width: CellWidth,
+        offset: u32,
+    },
+    MemoryFill,
+}
+
+/// A function type; all parameters and results are i32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FuncType {
+    params: usize,
+    result: bool,
+}
+
+struct Import {
+    module: &'static str,
+    name: &'static str,
+    /// Name of the function in the text format.
+    func_name: &'static str,
+    ty: FuncType,
+}
+
+struct Func {
+    name: &'static str,
+    export: Option<&'static str>,
+    ty: FuncType,
+    /// Names of the parameters followed by those of the other locals.
+    locals: &'static [&'static str],
+    body: Vec<Instr>,
+}
+
+struct Module {
+    imports: Vec<Import>,
+    funcs: Vec<Func>,
+    /// Size of the memory in 64 KiB pages.
+    pages: u32,
+    /// Bytes placed at address 0 when the module is instantiated.
+    data: Vec<u8>,
+}
+
+/// Translate the optimized IR of a bf program into a binary WebAssembly
+/// module.
+pub fn transpile(nodes: &[Node], config: &Config, io: WasmIo) -> Vec<u8> {
+    build(nodes, config, io).to_binary()
+}
+
+/// Translate the optimized IR of a bf program into a WebAssembly module in
+/// the text format.
+pub fn transpile_to_wat(nodes: &[Node], config: &Config, io: WasmIo) -> String {
+    build(nodes, config, io).to_wat()
+}
+
+/// The `bf2wasm` backend. The module is in the text format if the output
+/// file ends in `.wat`.
+pub struct WasmBackend;
+
+impl Backend for WasmBackend {
+    fn name(&self) -> &'static str {
+        "wasm"
+    }
+
+    fn language(&self) -> &'static str {
+        "WebAssembly"
+    }
+
+    fn options(&self) -> Vec<OptionSpec> {
+        vec![OptionSpec::choice::<WasmIo>(
+            "wasm-io",
+            "How the module bf2wasm mode writes does I/O",
+        )]
+    }
+
+    fn transpile(
+        &self,
+        nodes: &[Node],
+        request: &Request,
+    ) -> Result<Vec<Artifact>, Box<dyn error::Error>> {
+        let io = request.options.choice("wasm-io")?;
+        let module = if request.output.extension().is_some_and(|ext| ext == "wat") {
+            transpile_to_wat(nodes, request.config, io).into_bytes()
+        } else {
+            transpile(nodes, request.config, io)
+        };
+        Ok(vec![request.artifact(module)])
+    }
+
+    #[cfg(test)]
+    fn runner(&self) -> Option<Runner> {
+        Some(tests::RUNNER)
+    }
+}
+
+fn build(nodes: &[Node], config: &Config, io: WasmIo) -> Module {
+    let read = FuncType {
+        params: 0,
+        result: true,
+    };
+    let write = FuncType {
+        params: 1,
+        result: false,
+    };
+    let main = FuncType {
+        params: 0,
+        result: false,
+    };
+
+    let (imports, mut funcs, tape_base, data) = match io {
+        WasmIo::Host => {
+            let imports = vec![
+                Import {
+                    module: "env",
+                    name: "read_byte",
+                    func_name: "read_byte",
+                    ty: read,
+                },
+                Import {
+                    module: "env",
+                    name: "write_byte",
+                    func_name: "write_byte",
+                    ty: write,
+                },
+            ];
+            (imports, vec![], 0, vec![])
+        }
+        WasmIo::Wasi => {
+            let fd_io = FuncType {
+                params: 4,
+                result: true,
+            };
+            let imports = ["fd_read", "fd_write"].map(|name| Import {
+                module: "wasi_snapshot_preview1",
+                name,
+                func_name: name,
+                ty: fd_io,
+            });
+            let funcs = vec![
+                Func {
+                    name: "get",
+                    export: None,
+                    ty: read,
+                    locals: &[],
+                    body: wasi_get(0),
+                },
+                Func {
+                    name: "put",
+                    export: None,
+                    ty: write,
+                    locals: &["c"],
+                    body: wasi_put(1),
+                },
+            ];
+            // The iovec points at the byte and is one byte long.
+            let mut data = WASI_BYTE.to_le_bytes().to_vec();
+            data.extend(1i32.to_le_bytes());
+            (imports.into(), funcs, WASI_TAPE_BASE, data)
+        }
+    };
+    // Host imports are used as they are, the WASI ones through the
+    // functions above, which come right after them.
+    let (get, put) = match io {
+        WasmIo::Host => (0, 1),
+        WasmIo::Wasi => (2, 3),
+    };
+
+    let mut emitter = Emitter {
+        config,
+        body: vec![],
+        get,
+        put,
+    };
+    if tape_base != 0 {
+        emitter
+            .body
+            .extend([Instr::I32Const(tape_base as i32), Instr::LocalSet(P)]);
+    }
+    emitter.emit(nodes);
+    funcs.push(Func {
+        name: "main",
+        export: Some(match io {
+            WasmIo::Host => "run",
+            WasmIo::Wasi => "_start",
+        }),
+        ty: main,
+        locals: &["p", "c"],
+        body: emitter.body,
+    });
+
+    let memory_size = tape_base as usize + config.tape_size * config.cell_width.bytes();
+    Module {
+        imports,
+        funcs,
+        pages: memory_size.div_ceil(PAGE_SIZE).max(1) as u32,
+        data,
+    }
+}
+
+/// Body of `get`, returning the next byte of stdin or -1 at its end.
+fn wasi_get(fd_read: u32) -> Vec<Instr> {
+    use Instr::*;
+    vec![
+        I32Const(0),
+        I32Const(WASI_IOVEC),
+        I32Const(1),
+        I32Const(WASI_COUNT),
+        Call(fd_read),
+        // Errors end the input too.
+        If,
+        I32Const(-1),
+        Return,
+        End,
+        I32Const(WASI_COUNT),
+        Load {
+            width: CellWidth::U32,
+            offset: 0,
+        },
+        I32Eqz,
+        If,
+        I32Const(-1),
+        Return,
+        End,
+        I32Const(WASI_BYTE),
+        Load {
+            width: CellWidth::U8,
+            offset: 0,
+        },
+    ]
+}
+
+/// Body of `put`, writing its parameter to stdout.
+fn wasi_put(fd_write: u32) -> Vec<Instr> {
+    use Instr::*;
+    vec![
+        I32Const(WASI_BYTE),
+        LocalGet(0),
+        Store {
+            width: CellWidth::U8,
+            offset: 0,
+        },
+        I32Const(1),
+        I32Const(WASI_IOVEC),
+        I32Const(1),
+        I32Const(WASI_COUNT),
+        Call(fd_write),
+        Drop,
+    ]
+}
+
+struct Emitter<'a> {
+    config: &'a Config,
+    body: Vec<Instr>,
+    /// Indices of the functions reading and writing a byte.
+    get: u32,
+    put: u32,
+}
+
+impl Emitter<'_> {
+    fn push(&mut self, instr: Instr) {
+        self.body.push(instr);
+    }
+
+    /// Push what a load or store of the cell at `offset` needs on the stack
+    /// and return the offset it takes, which can't be negative.
+    fn address(&mut self, offset: isize) -> u32 {
+        let bytes = offset * self.config.cell_width.bytes() as isize;
+        self.push(Instr::LocalGet(P));
+        if bytes < 0 {
+            self.push(Instr::I32Const(bytes as i32));
+            self.push(Instr::I32Add);
+            0
+        } else {
+            bytes as u32
+        }
+    }
+
+    fn load(&mut self, offset: isize) {
+        let offset = self.address(offset);
+        let width = self.config.cell_width;
+        self.push(Instr::Load { width, offset });
+    }
+
+    /// Store the value `value` pushes in the cell at `offset`.
+    fn store(&mut self, offset: isize, value: impl FnOnce(&mut Self)) {
+        let offset = self.address(offset);
+        value(self);
+        let width = self.config.cell_width;
+        self.push(Instr::Store { width, offset });
+    }
+
+    /// Run `body` while the current cell is not zero.
+    fn while_nonzero(&mut self, body: impl FnOnce(&mut Self)) {
+        self.push(Instr::Block);
+        self.load(0);
+        self.push(Instr::I32Eqz);
+        self.push(Instr::BrIf(0));
+        self.push(Instr::Loop);
+        body(self);
+        self.load(0);
+        self.push(Instr::BrIf(0));
+        self.push(Instr::End);
+        self.push(Instr::End);
+    }
+
+    fn move_by(&mut self, n: isize) {
+        let bytes = n * self.config.cell_width.bytes() as isize;
+        self.body.extend([
+            Instr::LocalGet(P),
+            Instr::I32Const(bytes as i32),
+            Instr::I32Add,
+            Instr::LocalSet(P),
+        ]);
+    }
+
+    fn emit(&mut self, nodes: &[Node]) {
+        for group in ir::mul_groups(nodes) {
+            match group {
+                Group::MulAdd { src, nodes } => {
+                    self.load(src);
+                    self.push(Instr::If);
+                    nodes.iter().for_each(|node| self.emit_op(&node.op));
+                    self.push(Instr::End);
+                }
+                Group::Single(node) => self.emit_op(&node.op),
+            }
+        }
+    }
+
+    fn emit_op(&mut self, op: &Op) {
+        let width = self.config.cell_width;
+        match *op {
+            Op::Move(n) => self.move_by(n),
+            Op::Add { offset, amount } => self.store(offset, |e| {
+                e.load(offset);
+                e.push(Instr::I32Const(width.signed(amount) as i32));
+                e.push(Instr::I32Add);
+            }),
+            Op::Set { offset, value } => self.store(offset, |e| {
+                e.push(Instr::I32Const(width.unsigned(value) as u32 as i32));
+            }),
+            Op::Clear { offset, len } => {
+                let bytes = width.bytes() as isize;
+                self.push(Instr::LocalGet(P));
+                if offset != 0 {
+                    self.push(Instr::I32Const((offset * bytes) as i32));
+                    self.push(Instr::I32Add);
+                }
+                self.push(Instr::I32Const(0));
+                self.push(Instr::I32Const((len as isize * bytes) as i32));
+                self.push(Instr::MemoryFill);
+            }
+            Op::MulAdd { src, dst, factor } => {
+                let factor = width.signed(factor);
+                if factor == 0 {
+                    return;
+                }
+                self.store(dst, |e| {
+                    e.load(dst);
+                    e.load(src);
+                    match factor {
+                        1 => e.push(Instr::I32Add),
+                        -1 => e.push(Instr::I32Sub),
+                        k => {
+                            e.push(Instr::I32Const(k as i32));
+                            e.push(Instr::I32Mul);
+                            e.push(Instr::I32Add);
+                        }
+                    }
+                });
+            }
+            Op::Scan(n) => self.while_nonzero(|e| e.move_by(n)),
+            Op::Output { offset } => {
+                self.load(offset);
+                if width != CellWidth::U8 {
+                    self.push(Instr::I32Const(0xff));
+                    self.push(Instr::I32And);
+                }
+                self.push(Instr::Call(self.put));
+            }
+            Op::Input { offset } => match self.config.eof {
+                EofMode::Unchanged => {
+                    self.push(Instr::Call(self.get));
+                    self.push(Instr::LocalSet(C));
+                    self.body.extend([
+                        Instr::LocalGet(C),
+                        Instr::I32Const(0),
+                        Instr::I32GeS,
+                        Instr::If,
+                    ]);
+                    self.store(offset, |e| e.push(Instr::LocalGet(C)));
+                    self.push(Instr::End);
+                }
+                EofMode::Zero => {
+                    self.push(Instr::Call(self.get));
+                    self.push(Instr::LocalSet(C));
+                    self.store(offset, |e| {
+                        e.body.extend([
+                            Instr::LocalGet(C),
+                            Instr::I32Const(0),
+                            Instr::LocalGet(C),
+                            Instr::I32Const(0),
+                            Instr::I32GeS,
+                            Instr::Select,
+                        ])
+                    });
+                }
+                // -1 stored is the largest cell value.
+                EofMode::MinusOne => self.store(offset, |e| e.push(Instr::Call(e.get))),
+            },
+            Op::Loop(ref body) => self.while_nonzero(|e| e.emit(body)),
+        }
+    }
+}
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb128(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb128(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+const I32: u8 = 0x7f;
+const EMPTY_BLOCK: u8 = 0x40;
+
+/// Alignment exponent and opcodes of i32 loads and stores of `width` bits.
+fn access(width: CellWidth) -> (u8, u8, u8) {
+    match width {
+        CellWidth::U8 => (0, 0x2d, 0x3a),
+        CellWidth::U16 => (1, 0x2f, 0x3b),
+        CellWidth::U32 => (2, 0x28, 0x36),
+    }
+}
+
+fn encode(out: &mut Vec<u8>, instr: Instr) {
+    use Instr::*;
+    match instr {
+        Block => out.extend([0x02, EMPTY_BLOCK]),
+        Loop => out.extend([0x03, EMPTY_BLOCK]),
+        If => out.extend([0x04, EMPTY_BLOCK]),
+        End => out.push(0x0b),
+        BrIf(depth) => {
+            out.push(0x0d);
+            uleb128(out, depth.into());
+        }
+        Return => out.push(0x0f),
+        Call(func) => {
+            out.push(0x10);
+            uleb128(out, func.into());
+        }
+        Drop => out.push(0x1a),
+        Select => out.push(0x1b),
+        LocalGet(local) => {
+            out.push(0x20);
+            uleb128(out, local.into());
+        }
+        LocalSet(local) => {
+            out.push(0x21);
+            uleb128(out, local.into());
+        }
+        I32Const(value) => {
+            out.push(0x41);
+            sleb128(out, value.into());
+        }
+        I32Eqz => out.push(0x45),
+        I32GeS => out.push(0x4e),
+        I32Add => out.push(0x6a),
+        I32Sub => out.push(0x6b),
+        I32Mul => out.push(0x6c),
+        I32And => out.push(0x71),
+        Load { width, offset } | Store { width, offset } => {
+            let (align, load, store) = access(width);
+            out.push(if matches!(instr, Load { .. }) {
+                load
+            } else {
+                store
+            });
+            uleb128(out, align.into());
+            uleb128(out, offset.into());
+        }
+        MemoryFill => out.extend([0xfc, 0x0b, 0x00]),
+    }
+}
+
+fn text(instr: Instr, locals: &[&str], funcs: &[&str]) -> String {
+    use Instr::*;
+    let memarg = |offset: u32| match offset {
+        0 => String::new(),
+        offset => format!(" offset={offset}"),
+    };
+    match instr {
+        Block => "block".to_string(),
+        Loop => "loop".to_string(),
+        If => "if".to_string(),
+        End => "end".to_string(),
+        BrIf(depth) => format!("br_if {depth}"),
+        Return => "return".to_string(),
+        Call(func) => format!("call ${}", funcs[func as usize]),
+        Drop => "drop".to_string(),
+        Select => "select".to_string(),
+        LocalGet(local) => format!("local.get ${}", locals[local as usize]),
+        LocalSet(local) => format!("local.set ${}", locals[local as usize]),
+        I32Const(value) => format!("i32.const {value}"),
+        I32Eqz => "i32.eqz".to_string(),
+        I32GeS => "i32.ge_s".to_string(),
+        I32Add => "i32.add".to_string(),
+        I32Sub => "i32.sub".to_string(),
+        I32Mul => "i32.mul".to_string(),
+        I32And => "i32.and".to_string(),
+        Load { width, offset } => {
+            let op = match width {
+                CellWidth::U8 => "i32.load8_u",
+                CellWidth::U16 => "i32.load16_u",
+                CellWidth::U32 => "i32.load",
+            };
+            format!("{op}{}", memarg(offset))
+        }
+        Store { width, offset } => {
+            let op = match width {
+                CellWidth::U8 => "i32.store8",
+                CellWidth::U16 => "i32.store16",
+                CellWidth::U32 => "i32.store",
+            };
+            format!("{op}{}", memarg(offset))
+        }
+        MemoryFill => "memory.fill".to_string(),
+    }
+}
+
+/// `ty` in the text format, with a leading space unless it is empty. The
+/// parameters are named after `params` unless that is empty.
+fn text_type(ty: FuncType, params: &[&str]) -> String {
+    let mut out = String::new();
+    if params.is_empty() && ty.params > 0 {
+        let _ = write!(out, " (param{})", " i32".repeat(ty.params));
+    }
+    for param in params.iter().take(ty.params) {
+        let _ = write!(out, " (param ${param} i32)");
+    }
+    if ty.result {
+        out.push_str(" (result i32)");
+    }
+    out
+}
+
+impl Module {
+    fn func_types(&self) -> impl Iterator<Item = FuncType> + '_ {
+        let imported = self.imports.iter().map(|import| import.ty);
+        imported.chain(self.funcs.iter().map(|func| func.ty))
+    }
+
+    /// The distinct function types, in the order they are first used.
+    fn types(&self) -> Vec<FuncType> {
+        let mut types = vec![];
+        for ty in self.func_types() {
+            if !types.contains(&ty) {
+                types.push(ty);
+            }
+        }
+        types
+    }
+
+    fn to_binary(&self) -> Vec<u8> {
+        let types = self.types();
+        let type_index = |ty| types.iter().position(|&t| t == ty).unwrap() as u64;
+        let mut out = b"\0asm\x01\0\0\0".to_vec();
+
+        let mut contents = vec![];
+        uleb128(&mut contents, types.len() as u64);
+        for ty in &types {
+            contents.push(0x60);
+            uleb128(&mut contents, ty.params as u64);
+            contents.extend(std::iter::repeat_n(I32, ty.params));
+            uleb128(&mut contents, ty.result.into());
+            if ty.result {
+                contents.push(I32);
+            }
+        }
+        section(&mut out, 1, &contents);
+
+        contents.clear();
+        uleb128(&mut contents, self.imports.len() as u64);
+        for import in &self.imports {
+            name(&mut contents, import.module);
+            name(&mut contents, import.name);
+            contents.push(0x00);
+            uleb128(&mut contents, type_index(import.ty));
+        }
+        section(&mut out, 2, &contents);
+
+        contents.clear();
+        uleb128(&mut contents, self.funcs.len() as u64);
+        for func in &self.funcs {
+            uleb128(&mut contents, type_index(func.ty));
+        }
+        section(&mut out, 3, &contents);
+
+        contents.clear();
+        contents.extend([1, 0x00]);
+        uleb128(&mut contents, self.pages.into());
+        section(&mut out, 5, &contents);
+
+        contents.clear();
+        let exports: Vec<_> = self
+            .funcs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, func)| Some((func.export?, self.imports.len() + i)))
+            .collect();
+        uleb128(&mut contents, exports.len() as u64 + 1);
+        name(&mut contents, "memory");
+        contents.extend([0x02, 0]);
+        for (export, index) in exports {
+            name(&mut contents, export);
+            contents.push(0x00);
+            uleb128(&mut contents, index as u64);
+        }
+        section(&mut out, 7, &contents);
+
+        contents.clear();
+        uleb128(&mut contents, self.funcs.len() as u64);
+        for func in &self.funcs {
+            let mut code = vec![];
+            match func.locals.len() - func.ty.params {
+                0 => code.push(0),
+                n => {
+                    code.push(1);
+                    uleb128(&mut code, n as u64);
+                    code.push(I32);
+                }
+            }
+            for &instr in &func.body {
+                encode(&mut code, instr);
+            }
+            encode(&mut code, Instr::End);
+            uleb128(&mut contents, code.len() as u64);
+            contents.extend(code);
+        }
+        section(&mut out, 10, &contents);
+
+        if !self.data.is_empty() {
+            contents.clear();
+            contents.extend([1, 0x00]);
+            encode(&mut contents, Instr::I32Const(0));
+            encode(&mut contents, Instr::End);
+            uleb128(&mut contents, self.data.len() as u64);
+            contents.extend(&self.data);
+            section(&mut out, 11, &contents);
+        }
+        out
+    }
+
+    fn to_wat(&self) -> String {
+        let import_names = self.imports.iter().map(|import| import.func_name);
+        let func_names: Vec<&str> = import_names
+            .chain(self.funcs.iter().map(|func| func.name))
+            .collect();
+
+        let mut out = String::from("(module\n");
+        for import in &self.imports {
+            let _ = writeln!(
+                out,
+                "  (import \"{}\" \"{}\" (func ${}{}))",
+                import.module,
+                import.name,
+                import.func_name,
+                text_type(import.ty, &[]),
+            );
+        }
+        let _ = writeln!(out, "  (memory (export \"memory\") {})", self.pages);
+        for func in &self.funcs {
+            let _ = write!(out, "  (func ${}", func.name);
+            if let Some(export) = func.export {
+                let _ = write!(out, " (export \"{export}\")");
+            }
+            let _ = writeln!(out, "{}", text_type(func.ty, func.locals));
+            let locals = &func.locals[func.ty.params..];
+            if !locals.is_empty() {
+                let locals: Vec<_> = locals.iter().map(|l| format!("(local ${l} i32)")).collect();
+                let _ = writeln!(out, "    {}", locals.join(" "));
+            }
+            let mut depth = 2;
+            for &instr in &func.body {
+                if instr == Instr::End {
+                    depth -= 1;
+                }
+                let line = text(instr, func.locals, &func_names);
+                let _ = writeln!(out, "{}{line}", "  ".repeat(depth));
+                if matches!(instr, Instr::Block | Instr::Loop | Instr::If) {
+                    depth += 1;
+                }
+            }
+            out.push_str("  )\n");
+        }
+        if !self.data.is_empty() {
+            let bytes: String = self.data.iter().map(|b| format!("\\{b:02x}")).collect();
+            let _ = writeln!(out, "  (data (i32.const 0) \"{bytes}\")");
+        }
+        out.push_str(")\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_helper::{self, HELLO_HELL_STR, has_tool, output_of};
+    use crate::transpiler::backend::Options;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    pub(super) const RUNNER: Runner = Runner {
+        file: "prog.wasm",
+        variants: &[&[("wasm-io", "wasi")]],
+        run: run_node,
+    };
+
+    /// The module `WasmBackend` writes for `bf_src` to `output`, which is in
+    /// the text format if it ends in `.wat`.
+    fn transpile_module(bf_src: &[u8], config: &Config, io: WasmIo, output: &str) -> Vec<u8> {
+        let io = value_name(io);
+        let options = [("wasm-io", io.as_str())];
+        let artifacts =
+            test_helper::transpile(&WasmBackend, bf_src, config, &options, Path::new(output));
+        artifacts[0].contents.clone()
+    }
+
+    /// The module for `bf_src` in the text format, with the defaults.
+    fn wat(bf_src: &[u8]) -> String {
+        let module = transpile_module(bf_src, &Config::default(), WasmIo::Host, "prog.wat");
+        String::from_utf8(module).unwrap()
+    }
+
+    fn value_name(io: WasmIo) -> String {
+        io.to_possible_value().unwrap().get_name().to_string()
+    }
+
+    const HOST_DRIVER: &str = r#"import { readFileSync } from "fs";
+const input = readFileSync(0);
+let pos = 0;
+const out = [];
+const { instance } = await WebAssembly.instantiate(readFileSync(process.argv[2]), {
+    env: {
+        read_byte: () => (pos < input.length ? input[pos++] : -1),
+        write_byte: (c) => out.push(c),
+    },
+});
+instance.exports.run();
+process.stdout.write(Uint8Array.from(out));
+"#;
+
+    const WASI_DRIVER: &str = r#"import { readFileSync } from "fs";
+import { WASI } from "wasi";
+const wasi = new WASI({ version: "preview1" });
+const module = await WebAssembly.compile(readFileSync(process.argv[2]));
+const instance = await WebAssembly.instantiate(module, wasi.getImportObject());
+wasi.start(instance);
+"#;
+
+    /// Run the module at `path` with node on `input`; `None` without node.
+    fn run_node(path: &Path, options: &Options, input: &[u8]) -> Option<Vec<u8>> {
+        if !has_tool("node", "--version") {
+            return None;
+        }
+        let driver = match options.choice("wasm-io").unwrap() {
+            WasmIo::Host => HOST_DRIVER,
+            WasmIo::Wasi => WASI_DRIVER,
+        };
+        let driver_file = path.with_file_name("driver.mjs");
+        fs::write(&driver_file, driver).unwrap();
+        Some(output_of(
+            Command::new("node")
+                .arg("--no-warnings")
+                .arg(driver_file)
+                .arg(path),
+            input,
+        ))
+    }
+
+    /// The sections of `module` other than custom ones.
+    fn sections(module: &[u8]) -> Vec<&[u8]> {
+        wasmparser::Parser::new(0)
+            .parse_all(module)
+            .filter_map(|payload| payload.unwrap().as_section())
+            .filter(|&(id, _)| id != 0)
+            .map(|(_, range)| &module[range])
+            .collect()
+    }
+
+    fn configs() -> impl Iterator<Item = (Config, WasmIo)> {
+        let widths = [CellWidth::U8, CellWidth::U16, CellWidth::U32];
+        let eofs = [EofMode::Unchanged, EofMode::Zero, EofMode::MinusOne];
+        widths.into_iter().flat_map(move |cell_width| {
+            eofs.into_iter().flat_map(move |eof| {
+                let config = Config {
+                    cell_width,
+                    eof,
+                    ..Config::default()
+                };
+                [(config, WasmIo::Host), (config, WasmIo::Wasi)]
+            })
+        })
+    }
+
+    #[test]
+    fn modules_validate() {
+        let prog = b"+[->>+++<<]<[-]>[-]>[<<]>,.[>,.[-]<<-]";
+        for (config, io) in configs() {
+            let module = transpile_module(prog, &config, io, "prog.wasm");
+            let mut validator = wasmparser::Validator::new();
+            if let Err(e) = validator.validate_all(&module) {
+                panic!("invalid module for {config:?} {io:?}: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn wat_assembles_to_the_binary() {
+        let prog = HELLO_HELL_STR.as_bytes();
+        for (config, io) in configs() {
+            let module = transpile_module(prog, &config, io, "prog.wasm");
+            let wat = String::from_utf8(transpile_module(prog, &config, io, "prog.wat")).unwrap();
+            let assembled = wat::parse_str(&wat).unwrap();
+            assert_eq!(sections(&module), sections(&assembled), "{wat}");
+        }
+    }
+
+    #[test]
+    fn emits_folded_ops() {
+        let wat = wat(b"+++<[-]>[->+++<]>,");
+        assert!(wat.contains("(memory (export \"memory\") 1)"));
+        assert!(wat.contains("(func $main (export \"run\")"));
+        let ops = [
+            "local.get $p",
+            "local.get $p",
+            "i32.load8_u",
+            "i32.const 3",
+            "i32.add",
+            "i32.store8",
+            "local.get $p",
+            "i32.const -1",
+            "i32.add",
+            "i32.const 0",
+            "i32.store8",
+            "local.get $p",
+            "i32.load8_u",
+            "if",
+        ];
+        let lines: Vec<&str> = wat.lines().map(str::trim).collect();
+        assert!(lines.windows(ops.len()).any(|w| w == ops), "{wat}");
+        assert!(!lines.contains(&"loop"));
+        assert!(lines.contains(&"call $read_byte"));
+    }
+
+    #[test]
+    fn loops_use_block_and_br_if() {
+        let wat = wat(b"[.>]");
+        let lines: Vec<&str> = wat.lines().skip_while(|l| !l.contains("$main")).collect();
+        assert_eq!(
+            lines[2..],
+            [
+                "    block",
+                "      local.get $p",
+                "      i32.load8_u",
+                "      i32.eqz",
+                "      br_if 0",
+                "      loop",
+                "        local.get $p",
+                "        i32.load8_u",
+                "        call $write_byte",
+                "        local.get $p",
+                "        i32.const 1",
+                "        i32.add",
+                "        local.set $p",
+                "        local.get $p",
+                "        i32.load8_u",
+                "        br_if 0",
+                "      end",
+                "    end",
+                "  )",
+                ")",
+            ]
+        );
+    }
+}
@@ -0,0 +1,568 @@
+//! The optimized IR the transpilers emit code from.
+//!
+//! Pointer moves inside straight-line code are folded into the offsets of the
+//! ops, so `>+>+<<` becomes two adds at offsets 1 and 2 and no move at all.
+//! Loops that only shuffle values around are replaced by what they compute:
+//!
+//! - `[-]` and `[+]` set the cell to zero, and clears of neighbouring cells
+//!   are merged into one `Clear`.
+//! - Multiply loops like `[->++>+++<<]` become a `MulAdd` per target cell
+//!   followed by a clear of the counter.
+//! - Scan loops like `[>]` become a `Scan`.
+
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+
+/// Width of a tape cell; arithmetic wraps around at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CellWidth {
+    #[value(name = "8")]
+    U8,
+    #[value(name = "16")]
+    U16,
+    #[value(name = "32")]
+    U32,
+}
+
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// `value` reduced to the range of a cell, `0..2^bits`.
+    pub fn unsigned(self, value: i64) -> u64 {
+        (value as u64) & (u64::MAX >> (64 - self.bits()))
+    }
+
+    /// `value` reduced to `-2^(bits-1)..2^(bits-1)`, for emitting adds of
+    /// negative amounts as subtractions.
+    pub fn signed(self, value: i64) -> i64 {
+        let shift = 64 - self.bits();
+        (value << shift) >> shift
+    }
+}
+
+/// What `,` stores once the input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EofMode {
+    /// Leave the cell as it is.
+    Unchanged,
+    /// Store 0.
+    Zero,
+    /// Store -1, i.e. the largest cell value.
+    MinusOne,
+}
+
+/// Tape and I/O semantics of transpiled programs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Number of cells on the tape.
+    pub tape_size: usize,
+    pub cell_width: CellWidth,
+    pub eof: EofMode,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            tape_size: crate::bf_types::BF_MEMORY_SIZE,
+            cell_width: CellWidth::U8,
+            eof: EofMode::Unchanged,
+        }
+    }
+}
+
+/// An op; offsets are relative to the pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Move(isize),
+    /// Add `amount` to the cell at `offset`, wrapping.
+    Add {
+        offset: isize,
+        amount: i64,
+    },
+    Set {
+        offset: isize,
+        value: i64,
+    },
+    /// Zero the `len` cells starting at `offset`.
+    Clear {
+        offset: isize,
+        len: usize,
+    },
+    /// Add `factor` times the cell at `src` to the cell at `dst`.
+    MulAdd {
+        src: isize,
+        dst: isize,
+        factor: i64,
+    },
+    /// Move the pointer by `stride` until it is at a zero cell.
+    Scan(isize),
+    Output {
+        offset: isize,
+    },
+    Input {
+        offset: isize,
+    },
+    /// Run the body while the current cell is not zero.
+    Loop(Vec<Node>),
+}
+
+/// An op and where it came from: the source offset of the first command
+/// folded into it, or of the `[` of the loop it replaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub op: Op,
+    pub at: usize,
+}
+
+/// Parse `prog_src` into ops without optimizing anything but runs.
+pub fn parse(prog_src: &[u8]) -> Result<Vec<Node>, &'static str> {
+    // The enclosing blocks, each with the offset of the `[` opening it.
+    let mut stack: Vec<(Vec<Node>, usize)> = vec![(vec![], 0)];
+    for (at, &c) in prog_src.iter().enumerate() {
+        let nodes = &mut stack.last_mut().unwrap().0;
+        let last = nodes.last_mut().map(|node| &mut node.op);
+        let op = match c {
+            b'<' | b'>' => {
+                let delta = if c == b'>' { 1 } else { -1 };
+                if let Some(Op::Move(n)) = last {
+                    *n += delta;
+                    continue;
+                }
+                Op::Move(delta)
+            }
+            b'+' | b'-' => {
+                let delta = if c == b'+' { 1 } else { -1 };
+                if let Some(Op::Add { offset: 0, amount }) = last {
+                    *amount += delta;
+                    continue;
+                }
+                Op::Add {
+                    offset: 0,
+                    amount: delta,
+                }
+            }
+            b'.' => Op::Output { offset: 0 },
+            b',' => Op::Input { offset: 0 },
+            b'[' => {
+                stack.push((vec![], at));
+                continue;
+            }
+            b']' => {
+                if stack.len() == 1 {
+                    return Err("] without matching [");
+                }
+                let (body, at) = stack.pop().unwrap();
+                stack.last_mut().unwrap().0.push(Node {
+                    op: Op::Loop(body),
+                    at,
+                });
+                continue;
+            }
+            _ => continue,
+        };
+        nodes.push(Node { op, at });
+    }
+    if stack.len() > 1 {
+        return Err("[ without matching ]");
+    }
+    Ok(stack.pop().unwrap().0)
+}
+
+/// Parse and optimize `prog_src`.
+pub fn build(prog_src: &[u8]) -> Result<Vec<Node>, &'static str> {
+    Ok(optimize(parse(prog_src)?))
+}
+
+/// Straight-line code being built, with the pointer moves not emitted yet.
+#[derive(Default)]
+struct Block {
+    nodes: Vec<Node>,
+    /// Where the pointer would be if the moves so far had been emitted.
+    offset: isize,
+    /// Source offset of the first of those moves.
+    offset_at: usize,
+}
+
+impl Block {
+    fn last_op(&mut self) -> Option<&mut Op> {
+        self.nodes.last_mut().map(|node| &mut node.op)
+    }
+
+    fn move_by(&mut self, n: isize, at: usize) {
+        if self.offset == 0 {
+            self.offset_at = at;
+        }
+        self.offset += n;
+    }
+
+    /// Emit the pending pointer move.
+    fn settle(&mut self) {
+        if self.offset != 0 {
+            self.nodes.push(Node {
+                op: Op::Move(self.offset),
+                at: self.offset_at,
+            });
+            self.offset = 0;
+        }
+    }
+
+    /// Append `op` from source offset `at`, given relative to the pending
+    /// pointer position, merging it into the previous op where possible.
+    fn push(&mut self, op: Op, at: usize) {
+        let shift = self.offset;
+        let op = match op {
+            Op::Add { offset, amount } => {
+                let offset = offset + shift;
+                match self.last_op() {
+                    Some(Op::Add {
+                        offset: last,
+                        amount: total,
+                    }) if *last == offset => {
+                        *total = total.wrapping_add(amount);
+                        if *total == 0 {
+                            self.nodes.pop();
+                        }
+                        return;
+                    }
+                    Some(Op::Set {
+                        offset: last,
+                        value,
+                    }) if *last == offset => {
+                        *value = value.wrapping_add(amount);
+                        return;
+                    }
+                    _ => Op::Add { offset, amount },
+                }
+            }
+            Op::Set { offset, value } => {
+                let offset = offset + shift;
+                // Whatever was added right before is overwritten.
+                while let Some(Op::Add { offset: last, .. } | Op::Set { offset: last, .. }) =
+                    self.last_op()
+                {
+                    if *last != offset {
+                        break;
+                    }
+                    self.nodes.pop();
+                }
+                let cleared_before = Op::Set {
+                    offset: offset - 1,
+                    value: 0,
+                };
+                match self.last_op() {
+                    Some(Op::Clear { offset: start, len })
+                        if value == 0 && *start + *len as isize == offset =>
+                    {
+                        *len += 1;
+                        return;
+                    }
+                    Some(last_op) if value == 0 && *last_op == cleared_before => {
+                        *last_op = Op::Clear {
+                            offset: offset - 1,
+                            len: 2,
+                        };
+                        return;
+                    }
+                    _ => Op::Set { offset, value },
+                }
+            }
+            Op::MulAdd { src, dst, factor } => Op::MulAdd {
+                src: src + shift,
+                dst: dst + shift,
+                factor,
+            },
+            Op::Output { offset } => Op::Output {
+                offset: offset + shift,
+            },
+            Op::Input { offset } => Op::Input {
+                offset: offset + shift,
+            },
+            Op::Move(_) | Op::Clear { .. } | Op::Scan(_) | Op::Loop(_) => {
+                unreachable!("not straight-line code: {op:?}")
+            }
+        };
+        self.nodes.push(Node { op, at });
+    }
+}
+
+/// Fold pointer moves into offsets and replace simple loops.
+pub fn optimize(nodes: Vec<Node>) -> Vec<Node> {
+    let mut block = Block::default();
+    for Node { op, at } in nodes {
+        match op {
+            Op::Move(n) => block.move_by(n, at),
+            Op::Loop(body) => {
+                let body = optimize(body);
+                if let Some(ops) = closed_form(&body) {
+                    for op in ops {
+                        block.push(op, at);
+                    }
+                    continue;
+                }
+                block.settle();
+                let op = match body[..] {
+                    [
+                        Node {
+                            op: Op::Move(stride),
+                            ..
+                        },
+                    ] => Op::Scan(stride),
+                    _ => Op::Loop(body),
+                };
+                block.nodes.push(Node { op, at });
+            }
+            op => block.push(op, at),
+        }
+    }
+    block.settle();
+    block.nodes
+}
+
+/// Straight-line ops doing what a loop with `body` does, if it only adds to
+/// cells and counts the current cell down by one per iteration.
+fn closed_form(body: &[Node]) -> Option<Vec<Op>> {
+    let mut sums = BTreeMap::new();
+    for node in body {
+        match node.op {
+            Op::Add { offset, amount } => {
+                let sum: &mut i64 = sums.entry(offset).or_default();
+                *sum = sum.wrapping_add(amount);
+            }
+            _ => return None,
+        }
+    }
+    let clear = Op::Set {
+        offset: 0,
+        value: 0,
+    };
+    match sums.remove(&0) {
+        // `[-]` and `[+]` reach zero whatever the cell width.
+        Some(1) if sums.is_empty() => Some(vec![clear]),
+        Some(-1) => {
+            let mut ops: Vec<Op> = sums
+                .into_iter()
+                .filter(|&(_, factor)| factor != 0)
+                .map(|(dst, factor)| Op::MulAdd {
+                    src: 0,
+                    dst,
+                    factor,
+                })
+                .collect();
+            ops.push(clear);
+            Some(ops)
+        }
+        _ => None,
+    }
+}
+
+/// A node, or a run of `MulAdd`s reading the same cell, as `mul_groups`
+/// yields them.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Group<'a> {
+    Single(&'a Node),
+    /// The `MulAdd`s of one multiply loop, which read the cell at `src`.
+    MulAdd {
+        src: isize,
+        nodes: &'a [Node],
+    },
+}
+
+/// `nodes` with the `MulAdd`s of each multiply loop grouped together.
+///
+/// A multiply loop that is never entered may have targets off the tape, so
+/// backends only touch them when the cell at `src` is not zero, that is,
+/// when the loop would have run.
+pub fn mul_groups(nodes: &[Node]) -> impl Iterator<Item = Group<'_>> {
+    let src = |node: &Node| match node.op {
+        Op::MulAdd { src, .. } => Some(src),
+        _ => None,
+    };
+    nodes
+        .chunk_by(move |a, b| src(a).is_some() && src(a) == src(b))
+        .map(move |nodes| match src(&nodes[0]) {
+            Some(src) => Group::MulAdd { src, nodes },
+            None => Group::Single(&nodes[0]),
+        })
+}
+
+/// The pointer `p` moved by `offset`, with `space` on either side of the
+/// operator.
+pub fn index(offset: isize, space: &str) -> String {
+    match offset {
+        0 => "p".to_string(),
+        o if o < 0 => format!("p{space}-{space}{}", -o),
+        o => format!("p{space}+{space}{o}"),
+    }
+}
+
+/// The cell of `tape` at `offset`, indexed as [`index`] writes it.
+pub fn cell(offset: isize, space: &str) -> String {
+    format!("tape[{}]", index(offset, space))
+}
+
+/// `nodes` without a final pointer move, which nothing reads.
+pub fn trim_trailing_move(nodes: &[Node]) -> &[Node] {
+    match nodes {
+        [
+            rest @ ..,
+            Node {
+                op: Op::Move(_), ..
+            },
+        ] => rest,
+        nodes => nodes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ops(prog_src: &[u8]) -> Vec<Op> {
+        build(prog_src)
+            .unwrap()
+            .into_iter()
+            .map(|node| node.op)
+            .collect()
+    }
+
+    #[test]
+    fn moves_fold_into_offsets() {
+        assert_eq!(
+            ops(b">+>++<<."),
+            [
+                Op::Add {
+                    offset: 1,
+                    amount: 1
+                },
+                Op::Add {
+                    offset: 2,
+                    amount: 2
+                },
+                Op::Output { offset: 0 },
+            ]
+        );
+        assert_eq!(
+            ops(b">>+[-<]")[..2],
+            [
+                Op::Add {
+                    offset: 2,
+                    amount: 1
+                },
+                Op::Move(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn clears_and_sets() {
+        assert_eq!(
+            ops(b"[-]+++"),
+            [Op::Set {
+                offset: 0,
+                value: 3
+            }]
+        );
+        assert_eq!(
+            ops(b"+++[+]"),
+            [Op::Set {
+                offset: 0,
+                value: 0
+            }]
+        );
+        assert_eq!(
+            ops(b"[-]>[-]>[-]"),
+            [Op::Clear { offset: 0, len: 3 }, Op::Move(2)]
+        );
+    }
+
+    #[test]
+    fn multiply_loops() {
+        assert_eq!(
+            ops(b">[->++>---<<]"),
+            [
+                Op::MulAdd {
+                    src: 1,
+                    dst: 2,
+                    factor: 2
+                },
+                Op::MulAdd {
+                    src: 1,
+                    dst: 3,
+                    factor: -3
+                },
+                Op::Set {
+                    offset: 1,
+                    value: 0
+                },
+                Op::Move(1),
+            ]
+        );
+        // Counting up is only a clear when nothing else happens.
+        assert!(matches!(ops(b"[+>+<]")[..], [Op::Loop(_)]));
+    }
+
+    #[test]
+    fn scan_loops() {
+        assert_eq!(ops(b"[>>]"), [Op::Scan(2)]);
+        assert_eq!(ops(b"[<]"), [Op::Scan(-1)]);
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        assert!(build(b"[").is_err());
+        assert!(build(b"]").is_err());
+        assert!(build(b"[[]").is_err());
+    }
+
+    #[test]
+    fn mul_groups_split_at_the_source() {
+        let nodes = build(b"[->+>+<<]>[->+<]>.").unwrap();
+        let groups: Vec<Group> = mul_groups(&nodes).collect();
+        assert!(
+            matches!(
+                groups[..],
+                [
+                    Group::MulAdd {
+                        src: 0,
+                        nodes: [_, _]
+                    },
+                    Group::Single(Node {
+                        op: Op::Set { offset: 0, .. },
+                        ..
+                    }),
+                    Group::MulAdd { src: 1, nodes: [_] },
+                    Group::Single(_),
+                    Group::Single(_),
+                    Group::Single(_),
+                ]
+            ),
+            "{groups:?}"
+        );
+    }
+
+    #[test]
+    fn trailing_move_is_trimmed() {
+        let nodes = build(b"+>>").unwrap();
+        assert_eq!(trim_trailing_move(&nodes).len(), 1);
+        let nodes = build(b"+.").unwrap();
+        assert_eq!(trim_trailing_move(&nodes).len(), nodes.len());
+    }
+
+    #[test]
+    fn cell_width_arithmetic() {
+        assert_eq!(CellWidth::U8.unsigned(-1), 255);
+        assert_eq!(CellWidth::U16.signed(65535), -1);
+        assert_eq!(CellWidth::U32.unsigned(1 << 32), 0);
+    }
+}
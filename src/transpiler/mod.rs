@@ -1,2 +1,11 @@
+pub mod aot_c;
+pub mod backend;
 pub mod bf2c;
+pub mod bf2go;
 pub mod bf2js;
+pub mod bf2llvm;
+pub mod bf2py;
+pub mod bf2rs;
+pub mod bf2wasm;
+pub mod ir;
+pub mod source_map;
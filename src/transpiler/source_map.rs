@@ -0,0 +1,164 @@
+//! Source Map v3 output, mapping generated code back to the bf source.
+//!
+//! Only what the transpilers need is supported: a single source, embedded in
+//! the map so tools find it wherever the files end up, and no names.
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Append `value` as a base64 VLQ.
+fn push_vlq(out: &mut String, value: i64) {
+    // The sign goes into the lowest bit, then 5 bits per digit, least
+    // significant first, with bit 6 set on all digits but the last.
+    let mut rest = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    } as u64;
+    loop {
+        let mut digit = (rest & 0x1f) as usize;
+        rest >>= 5;
+        if rest > 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit] as char);
+        if rest == 0 {
+            break;
+        }
+    }
+}
+
+/// `s` as a JSON string literal.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Finds the line and column of source offsets.
+pub struct LineIndex {
+    /// Offset of the first byte of every line.
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(src: &[u8]) -> LineIndex {
+        let newlines = src.iter().enumerate().filter(|&(_, &c)| c == b'\n');
+        let starts = std::iter::once(0).chain(newlines.map(|(i, _)| i + 1));
+        LineIndex {
+            starts: starts.collect(),
+        }
+    }
+
+    /// 0-based line and column of byte `offset`.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        (line, offset - self.starts[line])
+    }
+}
+
+/// Mappings of generated code, built one generated line at a time.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    mappings: String,
+    /// Generated column of the previous segment on the current line.
+    prev_column: i64,
+    /// Source line and column of the previous segment anywhere.
+    prev_source: (i64, i64),
+    line_started: bool,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Start the next generated line.
+    pub fn next_line(&mut self) {
+        self.mappings.push(';');
+        self.prev_column = 0;
+        self.line_started = false;
+    }
+
+    /// Map `column` of the current generated line to the 0-based `line` and
+    /// `col` in the source.
+    pub fn add(&mut self, column: usize, line: usize, col: usize) {
+        if self.line_started {
+            self.mappings.push(',');
+        }
+        self.line_started = true;
+        let (line, col) = (line as i64, col as i64);
+        push_vlq(&mut self.mappings, column as i64 - self.prev_column);
+        push_vlq(&mut self.mappings, 0);
+        push_vlq(&mut self.mappings, line - self.prev_source.0);
+        push_vlq(&mut self.mappings, col - self.prev_source.1);
+        self.prev_column = column as i64;
+        self.prev_source = (line, col);
+    }
+
+    /// The map as JSON, for generated file `file` made from `source`, whose
+    /// text is `content`.
+    pub fn to_json(&self, file: &str, source: &str, content: &[u8]) -> String {
+        format!(
+            "{{\"version\":3,\"file\":{},\"sources\":[{}],\"sourcesContent\":[{}],\"names\":[],\"mappings\":\"{}\"}}\n",
+            json_string(file),
+            json_string(source),
+            json_string(&String::from_utf8_lossy(content)),
+            self.mappings
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vlq() {
+        let encode = |value| {
+            let mut out = String::new();
+            push_vlq(&mut out, value);
+            out
+        };
+        assert_eq!(encode(0), "A");
+        assert_eq!(encode(1), "C");
+        assert_eq!(encode(-1), "D");
+        assert_eq!(encode(15), "e");
+        assert_eq!(encode(16), "gB");
+        assert_eq!(encode(-1000), "x+B");
+    }
+
+    #[test]
+    fn line_index() {
+        let index = LineIndex::new(b"ab\n\ncd");
+        assert_eq!(index.position(0), (0, 0));
+        assert_eq!(index.position(2), (0, 2));
+        assert_eq!(index.position(3), (1, 0));
+        assert_eq!(index.position(5), (2, 1));
+    }
+
+    #[test]
+    fn segments_are_relative() {
+        let mut map = SourceMap::new();
+        map.next_line();
+        map.add(4, 0, 3);
+        map.add(8, 1, 0);
+        map.next_line();
+        map.add(4, 1, 2);
+        assert_eq!(
+            map.to_json("a.js", "a.bf", b"+\"\n"),
+            "{\"version\":3,\"file\":\"a.js\",\"sources\":[\"a.bf\"],\
+             \"sourcesContent\":[\"+\\\"\\n\"],\"names\":[],\"mappings\":\";IAAG,IACH;IAAE\"}\n"
+        );
+    }
+}